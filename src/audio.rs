@@ -0,0 +1,309 @@
+use std::f32::consts::TAU;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::cpu::FRAMERATE;
+
+const WAV_SAMPLE_RATE: u32 = 44100;
+
+// Sound output, driven once per 60 Hz frame by `CPU::run_until`
+pub trait Audio: fmt::Debug {
+    // The buzzer sounds for every frame `playing` is true
+    fn update(&mut self, playing: bool);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "square" => Ok(Waveform::Square),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" => Ok(Waveform::Sawtooth),
+            "sine" => Ok(Waveform::Sine),
+            _ => Err(format!(
+                "Unknown waveform '{}', expected square, triangle, sawtooth or sine",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneConfig {
+    pub frequency: f32, // Hz
+    pub volume: f32,    // 0.0 - 1.0
+    pub waveform: Waveform,
+}
+
+impl Default for ToneConfig {
+    fn default() -> Self {
+        ToneConfig {
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+// Generates the buzzer tone one sample at a time
+#[derive(Debug, Clone)]
+pub struct Tone {
+    config: ToneConfig,
+    sample_rate: u32,
+    phase: f32, // position in the current period, 0.0 - 1.0
+}
+
+impl Tone {
+    pub fn new(config: ToneConfig, sample_rate: u32) -> Tone {
+        Tone {
+            config,
+            sample_rate,
+            phase: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let p = self.phase;
+        let sample = match self.config.waveform {
+            Waveform::Square => {
+                if p < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (p - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * p - 1.0,
+            Waveform::Sine => (p * TAU).sin(),
+        };
+        self.phase = (p + self.config.frequency / self.sample_rate as f32).fract();
+        sample * self.config.volume.clamp(0.0, 1.0)
+    }
+
+    // Start the next tone at the beginning of a period to avoid clicks
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NullAudio;
+
+impl NullAudio {
+    pub fn new() -> NullAudio {
+        NullAudio
+    }
+}
+
+impl Audio for NullAudio {
+    fn update(&mut self, _playing: bool) {}
+}
+
+// Writes the sound as a mono 16 bit WAV file, one frame of samples per update,
+// so sound can be checked on a machine without a sound card
+#[derive(Debug)]
+pub struct WavAudio {
+    writer: BufWriter<File>,
+    tone: Tone,
+    samples: u32,
+    was_playing: bool,
+}
+
+impl WavAudio {
+    pub fn create(path: &Path, config: ToneConfig) -> io::Result<WavAudio> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // Sizes are patched in by `finish`
+        write_wav_header(&mut writer, 0)?;
+        Ok(WavAudio {
+            writer,
+            tone: Tone::new(config, WAV_SAMPLE_RATE),
+            samples: 0,
+            was_playing: false,
+        })
+    }
+
+    fn write_frame(&mut self, playing: bool) -> io::Result<()> {
+        if playing && !self.was_playing {
+            self.tone.reset();
+        }
+        self.was_playing = playing;
+        for _ in 0..WAV_SAMPLE_RATE / FRAMERATE {
+            let sample = if playing {
+                self.tone.next_sample()
+            } else {
+                0.0
+            };
+            let sample = (sample * i16::MAX as f32) as i16;
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += WAV_SAMPLE_RATE / FRAMERATE;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.samples * 2)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+impl Audio for WavAudio {
+    fn update(&mut self, playing: bool) {
+        if let Err(e) = self.write_frame(playing) {
+            eprintln!("Failed to write WAV audio: {}", e);
+        }
+    }
+}
+
+impl Drop for WavAudio {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Failed to finish WAV audio: {}", e);
+        }
+    }
+}
+
+fn write_wav_header<W: Write>(w: &mut W, data_size: u32) -> io::Result<()> {
+    let channels: u16 = 1;
+    let bits_per_sample: u16 = 16;
+    let block_align = channels * bits_per_sample / 8;
+    w.write_all(b"RIFF")?;
+    w.write_all(&(36 + data_size).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&channels.to_le_bytes())?;
+    w.write_all(&WAV_SAMPLE_RATE.to_le_bytes())?;
+    w.write_all(&(WAV_SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&bits_per_sample.to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&data_size.to_le_bytes())?;
+    Ok(())
+}
+
+// Plays the tone on the default output device
+#[cfg(feature = "beeper")]
+pub struct BeeperAudio {
+    _stream: cpal::Stream,
+    playing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[cfg(feature = "beeper")]
+impl BeeperAudio {
+    pub fn new(config: ToneConfig) -> Result<BeeperAudio, Box<dyn std::error::Error>> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("No audio output device available")?;
+        let supported = device.default_output_config()?;
+        if supported.sample_format() != cpal::SampleFormat::F32 {
+            return Err(format!(
+                "Unsupported audio sample format {}",
+                supported.sample_format()
+            )
+            .into());
+        }
+        let stream_config: cpal::StreamConfig = supported.into();
+        let channels = stream_config.channels as usize;
+        let mut tone = Tone::new(config, stream_config.sample_rate.0);
+
+        let playing = Arc::new(AtomicBool::new(false));
+        let stream_playing = Arc::clone(&playing);
+        let mut was_playing = false;
+        let stream = device.build_output_stream(
+            &stream_config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                let playing = stream_playing.load(Ordering::Relaxed);
+                if playing && !was_playing {
+                    tone.reset();
+                }
+                was_playing = playing;
+                for frame in data.chunks_mut(channels) {
+                    let sample = if playing { tone.next_sample() } else { 0.0 };
+                    frame.fill(sample);
+                }
+            },
+            |e| eprintln!("Audio stream error: {}", e),
+            None,
+        )?;
+        stream.play()?;
+
+        Ok(BeeperAudio {
+            _stream: stream,
+            playing,
+        })
+    }
+}
+
+#[cfg(feature = "beeper")]
+impl fmt::Debug for BeeperAudio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BeeperAudio")
+            .field("playing", &self.playing)
+            .finish()
+    }
+}
+
+#[cfg(feature = "beeper")]
+impl Audio for BeeperAudio {
+    fn update(&mut self, playing: bool) {
+        self.playing
+            .store(playing, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_square_wave() {
+        let config = ToneConfig {
+            frequency: 1000.0,
+            volume: 0.5,
+            waveform: Waveform::Square,
+        };
+        // 4 samples per period
+        let mut tone = Tone::new(config, 4000);
+        let samples: Vec<f32> = (0..8).map(|_| tone.next_sample()).collect();
+        assert_eq!(samples, vec![0.5, 0.5, -0.5, -0.5, 0.5, 0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn test_wav_output() {
+        let path = std::env::temp_dir().join("chip8_emu_test_wav_output.wav");
+        let mut audio = WavAudio::create(&path, ToneConfig::default()).unwrap();
+        audio.update(true);
+        audio.update(false);
+        drop(audio);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let frame_samples = (WAV_SAMPLE_RATE / FRAMERATE) as usize;
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(bytes.len(), 44 + frame_samples * 2 * 2);
+        let data_size = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        assert_eq!(data_size as usize, frame_samples * 2 * 2);
+        // First frame is the tone, second is silence
+        assert!(bytes[44..44 + frame_samples * 2].iter().any(|&b| b != 0));
+        assert!(bytes[44 + frame_samples * 2..].iter().all(|&b| b == 0));
+    }
+}
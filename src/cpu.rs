@@ -1,24 +1,31 @@
-use core::panic;
-use rand;
-
-use crate::fontset::FONTSET;
-use emu_abstractions::display::Display;
-
-const PROGRAM_START: u16 = 0x200;
-const MEMORY_SIZE: usize = 4096;
-
-const FONTSET_START: usize = 0x50;
-
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-
-const FRAMERATE: u32 = 60;
-const CYCLES_PER_FRAME: u32 = 11;
-
-const SHIFT_GLITCH: bool = true;
-// TODO: Figure out how to match framerate/cylces_per_frame with
-//       choosing when to draw the sprite
-const DRAW_GLITCH: bool = true;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::{Audio, NullAudio};
+use crate::display::Display;
+use crate::error::{Chip8Error, MovieError, RomError};
+use crate::fontset::{BIG_FONTSET, FONTSET};
+use crate::movie::{self, Movie, MovieState};
+use crate::quirks::Quirks;
+use crate::random::{Generator, RandomSource, SeededRandom};
+use crate::rewind::Rewind;
+use crate::trace::Tracer;
+
+pub const PROGRAM_START: u16 = 0x200;
+pub const MEMORY_SIZE: usize = 4096;
+pub const XO_MEMORY_SIZE: usize = 0x10000;
+
+pub const FONTSET_START: usize = 0x50;
+pub const BIG_FONTSET_START: usize = FONTSET_START + FONTSET.len();
+
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGHT: usize = 64;
+
+pub const FRAMERATE: u32 = 60;
+// Default speed, 660 instructions per second
+pub const CYCLES_PER_FRAME: u32 = 11;
 
 /*
    Notes on Sprites:
@@ -27,11 +34,41 @@ const DRAW_GLITCH: bool = true;
    * All sprites are drawn using XOR mode
      * During drawing, the sprite data is XOR'd with the current screen
      * To erase a sprite, draw it again
+   * XO-CHIP has two bitplanes, giving each pixel a color from 0-3
+     * Bit 0 of a pixel is plane 1, bit 1 is plane 2
+     * Only the planes selected with FN01 are drawn, cleared and scrolled
+     * A sprite holds the data for plane 1 followed by the data for plane 2
 */
 
+// What happened in a single call to `CPU::cycle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    // An instruction was executed
+    Continue,
+    // The instruction is blocked (FX0A, display wait) and will be retried
+    Waiting,
+    // The program asked to exit with 00FD
+    Exit,
+}
+
+// Why `CPU::run_until` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The pause callback asked to stop
+    Paused,
+    // The program exited with 00FD
+    Exit,
+    // The display was closed
+    Closed,
+    // The movie being played back has run out of frames
+    MovieEnd,
+}
+
 #[derive(Debug)]
 pub struct CPU<D: Display> {
     display: D,
+    audio: Box<dyn Audio>,
+    pub quirks: Quirks,
     pub opcode: u16,
 
     // Registers
@@ -43,14 +80,17 @@ pub struct CPU<D: Display> {
     pub sound_timer: u8, // write only
 
     // Memory
-    pub memory: [u8; MEMORY_SIZE],
+    // 4K, or 64K with XO-CHIP
+    pub memory: Vec<u8>,
 
-    pub i: u16,  // index register. Max = 0xfff
-    pub pc: u16, // program counter. Max = 0xfff
+    pub i: u16,  // index register. Max = 0xfff, or 0xffff with XO-CHIP
+    pub pc: u16, // program counter. Max = 0xfff, or 0xffff with XO-CHIP
 
     // Display
-    // 2048 pixels total, binary state black or white
-    pub gfx: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // 64x32 or 128x64 in hires mode, each pixel is a color index 0-3
+    pub gfx: Vec<u8>,
+    pub hires: bool,
+    pub planes: u8, // bitmask of the planes selected by FN01
 
     // Stack
     pub stack: [u16; 16],
@@ -58,65 +98,250 @@ pub struct CPU<D: Display> {
 
     // Keybuoard
     pub keys: [u8; 16],
+
+    // SUPER-CHIP RPL user flags, saved and restored by FX75/FX85
+    pub rpl: [u8; 16],
+
+    // XO-CHIP audio
+    pub audio_pattern: [u8; 16], // 128 1-bit samples, loaded by F002
+    pub pitch: u8,               // playback rate set by FX3A
+
+    // Source of CXNN's random numbers
+    pub random: Box<dyn RandomSource>,
+
+    // SHA-1 of the loaded program, so save states can check they match
+    pub rom_hash: [u8; 20],
+    // and its length, so the debugger can show where it is in memory
+    pub rom_size: usize,
+
+    // Recent frames for stepping backwards, captured by `run_until`
+    rewind: Option<Rewind>,
+
+    // Speed of `run_until`: instructions per 60Hz frame, and whether to
+    // run as fast as possible instead of in real time
+    pub cycles_per_frame: u32,
+    pub turbo: bool,
+    // Instructions run so far in the current frame, so pausing part way
+    // through a frame doesn't restart it
+    frame_cycle: u32,
+
+    // Keys recorded to, or played back from, a movie
+    movie: Option<MovieState>,
+
+    // Where executed instructions are logged, if anywhere
+    tracer: Option<Tracer>,
 }
 
 impl<D: Display> CPU<D> {
-    pub fn new(display: D) -> CPU<D> {
+    pub fn new(display: D, quirks: Quirks) -> CPU<D> {
         CPU {
             display,
+            audio: Box::new(NullAudio::new()),
+            quirks,
             opcode: 0,
             v: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
-            memory: [0; MEMORY_SIZE],
+            memory: vec![0; MEMORY_SIZE],
             i: 0,
             pc: 0,
-            gfx: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            gfx: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            hires: false,
+            planes: 1,
             stack: [0; 16],
             sp: 0,
             keys: [0; 16],
+            rpl: [0; 16],
+            audio_pattern: [0; 16],
+            pitch: 64,
+            random: Box::new(SeededRandom::from_entropy()),
+            rom_hash: [0; 20],
+            rom_size: 0,
+            rewind: None,
+            cycles_per_frame: CYCLES_PER_FRAME,
+            turbo: false,
+            frame_cycle: 0,
+            movie: None,
+            tracer: None,
         }
     }
 
-    pub fn run(&mut self) {
-        let frame_time = std::time::Duration::from_micros((1_000_000 / FRAMERATE).into());
-        let cycle_time =
-            std::time::Duration::from_micros((1_000_000 / (FRAMERATE * CYCLES_PER_FRAME)).into());
-        let mut last_timer_update = std::time::Instant::now();
+    pub fn set_audio(&mut self, audio: Box<dyn Audio>) {
+        self.audio = audio;
+    }
 
-        while self.display.is_open() {
-            let cycle_start = std::time::Instant::now();
-            let time_since_frame = last_timer_update.elapsed();
+    pub fn display(&self) -> &D {
+        &self.display
+    }
 
-            // Update keys
-            for i in 0..16 {
-                self.keys[i] = if self.display.is_key_down(i) { 1 } else { 0 };
-            }
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
 
-            if !self.cycle(time_since_frame.as_micros(), frame_time.as_micros()) {
-                break;
-            }
+    // Keep the last `seconds` of frames for `rewind`, 0 turns it off
+    pub fn set_rewind(&mut self, seconds: f32) {
+        self.rewind = (seconds > 0.0).then(|| Rewind::new(seconds));
+    }
+
+    // Step back one frame. Returns false when there are no earlier frames
+    pub fn rewind(&mut self) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+        let stepped = rewind.pop(self);
+        self.rewind = Some(rewind);
+        self.audio.update(false);
+        stepped
+    }
 
-            // Update timers at 60Hz
-            if time_since_frame >= frame_time {
-                // Update display
-                self.display.update(&self.gfx, SCREEN_WIDTH, SCREEN_HEIGHT);
-                if self.delay_timer > 0 {
-                    self.delay_timer -= 1;
+    // Run in real time, checking `pause` before every instruction. Each
+    // 60Hz frame runs a batch of `cycles_per_frame` instructions and then
+    // sleeps until the next frame is due, or not at all in turbo mode
+    pub fn run_until<F: FnMut(&Self) -> bool>(
+        &mut self,
+        mut pause: F,
+    ) -> Result<StopReason, Chip8Error> {
+        let frame_time = Duration::from_micros((1_000_000 / FRAMERATE).into());
+        let mut next_frame = Instant::now() + frame_time;
+
+        while self.display.is_open() {
+            if self.frame_cycle == 0 {
+                if self.movie_ended() {
+                    return Ok(StopReason::MovieEnd);
                 }
-                if self.sound_timer > 0 {
-                    self.sound_timer -= 1;
+                self.poll_keys();
+            }
+            while self.frame_cycle < self.cycles_per_frame {
+                if pause(self) {
+                    self.refresh_display();
+                    return Ok(StopReason::Paused);
                 }
-                last_timer_update = std::time::Instant::now();
+                // Instructions are spread evenly over the frame as far as
+                // the display wait quirk is concerned
+                let time_since_frame = frame_time.as_micros() * self.frame_cycle as u128
+                    / self.cycles_per_frame as u128;
+                self.frame_cycle += 1;
+                if self.cycle(time_since_frame, frame_time.as_micros())? == StepOutcome::Exit {
+                    return Ok(StopReason::Exit);
+                }
+            }
+            self.frame_cycle = 0;
+            self.end_frame();
+
+            let now = Instant::now();
+            if self.turbo || now > next_frame + frame_time {
+                // Unthrottled, or too far behind to catch up
+                next_frame = now;
+            } else if let Some(sleep_time) = next_frame.checked_duration_since(now) {
+                thread::sleep(sleep_time);
             }
+            next_frame += frame_time;
+        }
+        Ok(StopReason::Closed)
+    }
+
+    // Work done at every 60Hz frame boundary: show the screen, count down
+    // the timers, play sound and record the frame for rewinding
+    pub fn end_frame(&mut self) {
+        self.refresh_display();
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        // The buzzer sounds for as many frames as the timer was set to
+        let beeping = self.sound_timer > 0;
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+        self.random.end_frame();
+        self.audio.update(beeping);
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.push(self);
+            self.rewind = Some(rewind);
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.end_frame();
+        }
+    }
+
+    // Read the keypad for the next frame, from the display or the movie
+    // being played back
+    pub fn poll_keys(&mut self) {
+        if let Some(MovieState::Playing(movie, frame)) = &mut self.movie {
+            let keys = movie.frames.get(*frame).copied().unwrap_or(0);
+            self.keys = movie::keys_from_bits(keys);
+            *frame += 1;
+            return;
+        }
+        for i in 0..16 {
+            self.keys[i] = if self.display.is_key_down(i) { 1 } else { 0 };
+        }
+        if let Some(MovieState::Recording(movie)) = &mut self.movie {
+            movie.frames.push(movie::keys_to_bits(&self.keys));
+        }
+    }
 
-            // Sleep for remaining cycle time
-            if let Some(sleep_time) = cycle_time.checked_sub(cycle_start.elapsed()) {
-                std::thread::sleep(sleep_time);
+    // Start recording a movie. Call on a machine that has just loaded its
+    // ROM, so playing it back can start from the same place
+    pub fn record_movie(&mut self, generator: Generator, seed: u64) {
+        self.random = generator.with_seed(seed);
+        self.movie = Some(MovieState::Recording(Movie {
+            rom_hash: self.rom_hash,
+            quirks: self.quirks,
+            cycles_per_frame: self.cycles_per_frame,
+            generator,
+            seed,
+            final_hash: [0; 20],
+            frames: Vec::new(),
+        }));
+    }
+
+    // Start playing a movie back on a machine that has just loaded its ROM.
+    // The keypad is read from the movie until it runs out
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if movie.rom_hash != self.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+        self.quirks = movie.quirks;
+        self.memory.resize(self.memory_size(), 0);
+        self.cycles_per_frame = movie.cycles_per_frame;
+        self.random = movie.generator.with_seed(movie.seed);
+        self.movie = Some(MovieState::Playing(movie, 0));
+        Ok(())
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn has_movie(&self) -> bool {
+        self.movie.is_some()
+    }
+
+    pub fn movie_ended(&self) -> bool {
+        matches!(&self.movie, Some(MovieState::Playing(movie, frame)) if *frame >= movie.frames.len())
+    }
+
+    // Stop recording or playing back. A recording is finished off with the
+    // hash of the state it ends in
+    pub fn finish_movie(&mut self) -> Option<Movie> {
+        match self.movie.take()? {
+            MovieState::Recording(mut movie) => {
+                movie.final_hash = movie::state_hash(self);
+                Some(movie)
             }
+            MovieState::Playing(movie, _) => Some(movie),
         }
     }
 
+    pub fn refresh_display(&mut self) {
+        self.display
+            .update(&self.gfx, self.screen_width(), self.screen_height());
+    }
+
     pub fn initialize(&mut self) {
         self.pc = PROGRAM_START;
         self.opcode = 0;
@@ -125,148 +350,268 @@ impl<D: Display> CPU<D> {
         self.v = [0; 16];
 
         // Clear memory and stack
-        self.memory = [0; MEMORY_SIZE];
+        self.memory = vec![0; self.memory_size()];
         self.stack = [0; 16];
 
         // Clear timers
         self.delay_timer = 0;
         self.sound_timer = 0;
 
-        // Clear display
-        self.gfx = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        // Clear display and return to low resolution
+        self.planes = 1;
+        self.set_hires(false);
+
+        // Reset audio
+        self.audio_pattern = [0; 16];
+        self.pitch = 64;
 
         // Clear keys
         self.keys = [0; 16];
 
-        // Load fontset
+        // Load fontsets
         for (i, &byte) in FONTSET.iter().enumerate() {
             self.memory[i + FONTSET_START] = byte;
         }
+        for (i, &byte) in BIG_FONTSET.iter().enumerate() {
+            self.memory[i + BIG_FONTSET_START] = byte;
+        }
+
+        // RPL flags are left alone, they survived a reset on the HP48
+    }
+
+    pub fn memory_size(&self) -> usize {
+        if self.quirks.xo_chip {
+            XO_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        }
     }
 
-    pub fn load(&mut self, input: Vec<u8>) {
-        let size = input.len();
-        if (PROGRAM_START + size as u16) as usize > MEMORY_SIZE {
-            panic!("Program is too large to load.");
+    pub fn screen_width(&self) -> usize {
+        if self.hires {
+            HIRES_SCREEN_WIDTH
+        } else {
+            SCREEN_WIDTH
         }
-        for (i, &byte) in input.iter().enumerate() {
-            let idx: usize = (PROGRAM_START + i as u16) as usize;
-            self.memory[idx] = byte;
+    }
+
+    pub fn screen_height(&self) -> usize {
+        if self.hires {
+            HIRES_SCREEN_HEIGHT
+        } else {
+            SCREEN_HEIGHT
         }
     }
 
-    pub fn cycle(&mut self, time_since_frame: u128, frame_time: u128) -> bool {
-        self.opcode = (self.memory[self.pc as usize] as u16) << 8
-            | (self.memory[self.pc as usize + 1] as u16);
+    // Switch resolution, which also clears the screen
+    fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.gfx = vec![0; self.screen_width() * self.screen_height()];
+    }
+
+    // Copy a program to 0x200, failing if it doesn't fit in the memory
+    // the quirks give the machine
+    pub fn load(&mut self, input: Vec<u8>) -> Result<(), RomError> {
+        let start = PROGRAM_START as usize;
+        let max = self.memory.len() - start;
+        if input.len() > max {
+            return Err(RomError::TooLarge {
+                size: input.len(),
+                max,
+            });
+        }
+        self.rom_hash = sha1_smol::Sha1::from(&input).digest().bytes();
+        self.rom_size = input.len();
+        self.memory[start..start + input.len()].copy_from_slice(&input);
+        Ok(())
+    }
+
+    // Execute one instruction. On a fault pc is left pointing at the
+    // offending instruction
+    pub fn cycle(
+        &mut self,
+        time_since_frame: u128,
+        frame_time: u128,
+    ) -> Result<StepOutcome, Chip8Error> {
+        let pc = self.pc;
+        let (v, i) = (self.v, self.i);
+        let result = self.execute(time_since_frame, frame_time);
+        if result.is_err() {
+            self.pc = pc;
+        }
+        if let Some(mut tracer) = self.tracer.take() {
+            match &result {
+                Ok(StepOutcome::Waiting) => {}
+                Ok(_) => {
+                    let next = self.fetch(pc.wrapping_add(2)).unwrap_or(0);
+                    tracer.record(pc, self.opcode, next, (&v, i), (&self.v, self.i));
+                }
+                Err(e) => tracer.fault(pc, e),
+            }
+            self.tracer = Some(tracer);
+        }
+        result
+    }
+
+    fn execute(
+        &mut self,
+        time_since_frame: u128,
+        frame_time: u128,
+    ) -> Result<StepOutcome, Chip8Error> {
+        self.opcode = self.fetch(self.pc)?;
         let opcode = self.opcode;
-        self.pc += 2;
+        let unknown = Chip8Error::UnknownOpcode {
+            pc: self.pc,
+            opcode,
+        };
+        self.pc = self.pc.wrapping_add(2);
 
         let op_x = (opcode & 0x0F00) as usize >> 8;
         let op_y = (opcode & 0x00F0) as usize >> 4;
         match opcode & 0xF000 {
             0x0 => match opcode & 0x0FFF {
+                0x0C0..=0x0CF if self.quirks.super_chip => {
+                    // Scroll the screen down N pixels
+                    self.scroll(0, (opcode & 0x000F) as isize);
+                }
+                0x0D0..=0x0DF if self.quirks.xo_chip => {
+                    // Scroll the screen up N pixels (XO-CHIP)
+                    self.scroll(0, -((opcode & 0x000F) as isize));
+                }
                 0x0E0 => {
-                    // Clear the screen
-                    // println!("00E0");
-                    self.gfx = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+                    // Clear the selected planes of the screen
+                    let planes = self.planes;
+                    self.gfx.iter_mut().for_each(|pixel| *pixel &= !planes);
                 }
                 // TODO: Stack pushing popping tests
                 0x0EE => {
-                    // println!("return");
+                    if self.sp == 0 {
+                        return Err(Chip8Error::StackUnderflow);
+                    }
                     self.sp -= 1;
                     self.pc = self.stack[self.sp as usize];
                 }
+                0x0FB if self.quirks.super_chip => {
+                    // Scroll the screen right 4 pixels
+                    self.scroll(4, 0);
+                }
+                0x0FC if self.quirks.super_chip => {
+                    // Scroll the screen left 4 pixels
+                    self.scroll(-4, 0);
+                }
+                0x0FD if self.quirks.super_chip => {
+                    // Exit the interpreter
+                    return Ok(StepOutcome::Exit);
+                }
+                0x0FE if self.quirks.super_chip => {
+                    // Switch to 64x32 low resolution
+                    self.set_hires(false);
+                }
+                0x0FF if self.quirks.super_chip => {
+                    // Switch to 128x64 high resolution
+                    self.set_hires(true);
+                }
                 _ => {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+                    return Err(unknown);
                 }
             },
             0x1000 => {
                 // Jump to address NNN
-                // println!("1NNN");
                 self.pc = opcode & 0x0FFF;
             }
             0x2000 => {
                 // Call subroutine at NNN
-                // println!("2NNN");
+                if self.sp as usize >= self.stack.len() {
+                    return Err(Chip8Error::StackOverflow);
+                }
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
                 self.pc = opcode & 0x0FFF;
             }
             0x3000 => {
                 // Skip next instruction if vX == NN
-                // println!("3XNN");
                 if self.v[op_x] == (opcode & 0x00FF) as u8 {
-                    self.pc += 2;
+                    self.skip_next();
                 }
             }
             0x4000 => {
                 // Skip next instruction if vX != NN
-                // println!("4XNN");
                 let nn = (opcode & 0x00FF) as u8;
-                // println!("vx: {}, nn: {}", self.v[x], nn);
                 if self.v[op_x] != nn {
-                    self.pc += 2;
+                    self.skip_next();
                 }
             }
-            0x5000 => {
-                if opcode & 0x000F != 0 {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+            0x5000 => match opcode & 0x000F {
+                0x0 => {
+                    // Skip next instruction if VX == VY
+                    if self.v[op_x] == self.v[op_y] {
+                        self.skip_next();
+                    }
                 }
-                // Skip next instruction if VX == VY
-                // println!("5XY0");
-                if self.v[op_x] == self.v[op_y] {
-                    self.pc += 2;
+                0x2 if self.quirks.xo_chip => {
+                    // Store vX to vY in memory starting at I, in either order.
+                    // I is left unchanged (XO-CHIP)
+                    self.check_range(self.i as usize, op_x.abs_diff(op_y) + 1)?;
+                    for (offset, reg) in Self::register_range(op_x, op_y).enumerate() {
+                        self.write(self.i as usize + offset, self.v[reg])?;
+                    }
                 }
-            }
+                0x3 if self.quirks.xo_chip => {
+                    // Load vX to vY from memory starting at I, in either order.
+                    // I is left unchanged (XO-CHIP)
+                    self.check_range(self.i as usize, op_x.abs_diff(op_y) + 1)?;
+                    for (offset, reg) in Self::register_range(op_x, op_y).enumerate() {
+                        self.v[reg] = self.read(self.i as usize + offset)?;
+                    }
+                }
+                _ => {
+                    return Err(unknown);
+                }
+            },
             0x6000 => {
                 // Store NN in vX
-                // println!("6XNN");
                 self.v[op_x] = (opcode & 0x00FF) as u8;
             }
             0x7000 => {
                 // Add NN to vX
-                // println!("7XNN");
                 let add = (opcode & 0x00FF) as u8;
-                // println!("x: {}, add: {}", x, add);
                 // Wrapping add to handle overflow correctly
                 self.v[op_x] = self.v[op_x].wrapping_add(add);
             }
             0x8000 => match opcode & 0xF {
                 0x0 => {
                     // Store VY in VX
-                    // println!("8XY0");
                     self.v[op_x] = self.v[op_y];
                 }
                 0x1 => {
                     // store VY | VX in VX
-                    // println!("8XY1");
                     self.v[op_x] |= self.v[op_y];
-                    self.v[0xF] = 0;
+                    if self.quirks.vf_reset {
+                        self.v[0xF] = 0;
+                    }
                 }
                 0x2 => {
                     // store VY & VX in VX
-                    // println!("8XY2");
                     self.v[op_x] &= self.v[op_y];
-                    self.v[0xF] = 0;
+                    if self.quirks.vf_reset {
+                        self.v[0xF] = 0;
+                    }
                 }
                 0x3 => {
                     // store VY xor VX in VX
-                    // println!("8XY3");
                     self.v[op_x] ^= self.v[op_y];
-                    self.v[0xF] = 0;
+                    if self.quirks.vf_reset {
+                        self.v[0xF] = 0;
+                    }
                 }
                 0x4 => {
                     // Add VY to VX
                     // if carry occurred, set vf to 01 else vf to 00
-                    // println!("8XY4");
                     let (result, overflow) = self.v[op_x].overflowing_add(self.v[op_y]);
                     self.v[op_x] = result;
                     self.v[0xF] = if overflow { 0x01 } else { 0x00 };
                 }
                 0x5 => {
-                    // println!("8XY5");
                     // Sub VY from VX
                     // if borrow occurred, set vf to 00 else vf to 01
                     let (result, borrow) = self.v[op_x].overflowing_sub(self.v[op_y]);
@@ -275,177 +620,140 @@ impl<D: Display> CPU<D> {
                 }
                 0x6 => {
                     // Store vy >> 1 in vx. Set vf to LSB of vy before shift
-                    // println!("8XY6");
-                    if SHIFT_GLITCH {
-                        let bit = self.v[op_y] & 0x1;
-                        self.v[op_x] = self.v[op_y] >> 1;
-                        self.v[0xF] = bit;
-                    } else {
+                    if self.quirks.shifting {
                         let bit = self.v[op_x] & 0x1;
                         self.v[op_x] >>= 1;
                         self.v[0xF] = bit;
+                    } else {
+                        let bit = self.v[op_y] & 0x1;
+                        self.v[op_x] = self.v[op_y] >> 1;
+                        self.v[0xF] = bit;
                     }
                 }
                 0x7 => {
                     // Set vX = Vy - Vx, set VF to !borrowed
-                    // println!("8XY7");
                     let (result, borrow) = self.v[op_y].overflowing_sub(self.v[op_x]);
                     self.v[op_x] = result;
                     self.v[0xF] = if borrow { 0x00 } else { 0x01 };
                 }
                 0xE => {
                     // Store vy << 1 in vx. Set vf to most significant bit of vy before shift.
-                    // println!("8XYE");
-                    if SHIFT_GLITCH {
-                        let bit = (self.v[op_y] & 0b10000000) >> 7;
-                        self.v[op_x] = self.v[op_y] << 1;
-                        self.v[0xF] = bit;
-                    } else {
+                    if self.quirks.shifting {
                         let bit = (self.v[op_x] & 0b10000000) >> 7;
                         self.v[op_x] <<= 1;
                         self.v[0xF] = bit;
+                    } else {
+                        let bit = (self.v[op_y] & 0b10000000) >> 7;
+                        self.v[op_x] = self.v[op_y] << 1;
+                        self.v[0xF] = bit;
                     }
                 }
                 _ => {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+                    return Err(unknown);
                 }
             },
             0x9000 => {
                 // Skip next instruction if vX != vY
-                // println!("9XY0");
                 if opcode & 0x000F != 0x0000 {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+                    return Err(unknown);
                 }
                 if self.v[op_x] != self.v[op_y] {
-                    self.pc += 2;
+                    self.skip_next();
                 }
             }
             0xA000 => {
                 // ANNN: Sets I to address NNN
-                // println!("ANNN");
                 self.i = opcode & 0x0FFF;
             }
             0xB000 => {
-                // Jump to NNN + v0
-                // println!("BNNN");
-                self.pc = (opcode & 0x0FFF) + self.v[0] as u16;
+                // Jump to NNN + v0, or XNN + vX with the jumping quirk
+                let offset = if self.quirks.jumping {
+                    self.v[op_x]
+                } else {
+                    self.v[0]
+                };
+                self.pc = (opcode & 0x0FFF) + offset as u16;
             }
             0xC000 => {
                 // Set vX to random number & NN
-                // println!("CXNN");
                 let nn = (opcode & 0x00FF) as u8;
-                self.v[op_x] = rand::random::<u8>() & nn;
+                self.v[op_x] = self.random.next_byte() & nn;
             }
             0xD000 => {
-                // println!("DXYN");
                 // Draw sprite DXYN
                 // Notes:
                 // * Draw at coordinates vX, vY
                 // * Use N bytes starting at address I
                 // * Set VF to 1 if any set pixels are changed to unset, else 0
-                // * To be visible on the screen, the vX register must be
-                //      between 00 and 3F. vY must be between 00 and 1F
-                if DRAW_GLITCH {
-                    if time_since_frame > (frame_time / 20) {
-                        self.pc -= 2;
-                        return true;
-                    }
+                // * DXY0 draws a 16x16 sprite from 32 bytes (SUPER-CHIP), and
+                //      nothing without it
+                if self.quirks.display_wait && time_since_frame > (frame_time / 20) {
+                    self.pc = self.pc.wrapping_sub(2);
+                    return Ok(StepOutcome::Waiting);
                 }
-                let mut x = self.v[op_x] as usize;
-                let mut y = self.v[op_y] as usize;
                 let n = (opcode & 0x000F) as usize;
-                let addr = self.i as usize;
-                if y >= SCREEN_HEIGHT {
-                    y = y % SCREEN_HEIGHT;
-                }
-                if x >= SCREEN_WIDTH {
-                    x = x % SCREEN_WIDTH;
-                }
-                // if y >= SCREEN_HEIGHT || x >= SCREEN_WIDTH {
-                //     return true;
-                // }
-                let mut height = n;
-                if y + height > SCREEN_HEIGHT {
-                    height = SCREEN_HEIGHT - y;
-                }
-                let mut width = 8;
-                if x + 8 > SCREEN_WIDTH {
-                    width = SCREEN_WIDTH - x;
-                }
-                self.v[0xF] = 0;
-
-                for row in 0..height {
-                    let sprite = self.memory[addr + row];
-                    for col in 0..width {
-                        let pixel = sprite & (0x80 >> col);
-                        let idx: usize = (x + col + ((y + row) * SCREEN_WIDTH)) as usize;
-                        if pixel != 0 {
-                            if self.gfx[idx] == 1 {
-                                self.v[0xF] = 1;
-                            }
-                            self.gfx[idx] ^= 1;
-                        }
-                    }
-                }
+                self.draw_sprite(self.v[op_x], self.v[op_y], n)?;
             }
             0xE000 => match opcode & 0x00FF {
                 // TODO: Combine code for these two
                 0x9E => {
                     // Skip instruction if key with value vX is pressed
-                    // println!("EX9E");
-                    let val = self.v[op_x];
-                    if self.keys[val as usize] == 1 {
-                        self.pc += 2;
+                    if self.key(self.v[op_x])? == 1 {
+                        self.skip_next();
                     }
                 }
                 0xA1 => {
-                    // println!("EXA1");
-                    let val = self.v[op_x];
-                    if self.keys[val as usize] == 0 {
-                        self.pc += 2;
+                    if self.key(self.v[op_x])? == 0 {
+                        self.skip_next();
                     }
                 }
                 _ => {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+                    return Err(unknown);
                 }
             },
             0xF000 => match opcode & 0x00FF {
+                0x00 if opcode == 0xF000 && self.quirks.xo_chip => {
+                    // Set I to the 16 bit address NNNN in the next word (XO-CHIP)
+                    self.i = self.fetch(self.pc)?;
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                0x01 if self.quirks.xo_chip => {
+                    // Select the bitplanes N for drawing, clearing and scrolling (XO-CHIP)
+                    self.planes = op_x as u8 & 0x3;
+                }
+                0x02 if opcode == 0xF002 && self.quirks.xo_chip => {
+                    // Load the 16 byte audio pattern from memory at I (XO-CHIP)
+                    self.check_range(self.i as usize, self.audio_pattern.len())?;
+                    for offset in 0..self.audio_pattern.len() {
+                        self.audio_pattern[offset] = self.read(self.i as usize + offset)?;
+                    }
+                }
                 0x07 => {
                     // store delay timer in vX
-                    // println!("FX07");
                     self.v[op_x] = self.delay_timer;
                 }
                 0x0A => {
                     // Wait for keypress and store in vX
-                    // println!("FX0A");
                     let first_pressed_key = self.keys.iter().position(|&x| x == 1);
                     match first_pressed_key {
-                        Some(key) => {
-                            self.v[op_x] = key as u8;
-                            self.pc += 2;
-                        }
+                        Some(key) => self.v[op_x] = key as u8,
                         None => {
-                            self.pc -= 2;
+                            self.pc = self.pc.wrapping_sub(2);
+                            return Ok(StepOutcome::Waiting);
                         }
                     }
                 }
                 0x15 => {
                     // Set delay timer to vX
-                    // println!("FX15");
                     self.delay_timer = self.v[op_x];
                 }
                 0x18 => {
                     // Set sound timer to vX
-                    // println!("FX18");
                     self.sound_timer = self.v[op_x];
                 }
                 0x1E => {
                     // Add vX to I
-                    // println!("FX1E");
-                    self.i += self.v[op_x] as u16;
+                    self.i = self.i.wrapping_add(self.v[op_x] as u16);
                 }
                 0x29 => {
                     // Set I to location of sprite for digit vX
@@ -453,65 +761,236 @@ impl<D: Display> CPU<D> {
                     // * vX should be between 0 and F
                     // * Fontset is between 0x050-0x0A0
                     // * Each character is 5 bytes long
-                    // println!("FX29");
                     self.i = self.v[op_x] as u16 * 5 + FONTSET_START as u16;
                 }
+                0x30 if self.quirks.super_chip => {
+                    // Set I to location of the large sprite for digit vX
+                    self.i = (self.v[op_x] & 0xF) as u16 * 10 + BIG_FONTSET_START as u16;
+                }
+                0x3A if self.quirks.xo_chip => {
+                    // Set the audio pattern playback rate to vX (XO-CHIP)
+                    self.pitch = self.v[op_x];
+                }
                 0x33 => {
                     // Store binary-coded decimal representation of vX at I, I+1, I+2
                     // Notes:
                     // * Since each register is 8 bits, will have at most 3 decimal digits (0-255)
                     // * Store most significant digit at I, next at I+1, least significant at I+2
-                    // println!("FX33");
                     let x = op_x;
                     let val = self.v[x];
-                    self.memory[self.i as usize] = val / 100;
-                    self.memory[(self.i + 1) as usize] = (val / 10) % 10;
-                    self.memory[(self.i + 2) as usize] = val % 10;
+                    let addr = self.i as usize;
+                    self.check_range(addr, 3)?;
+                    self.write(addr, val / 100)?;
+                    self.write(addr + 1, (val / 10) % 10)?;
+                    self.write(addr + 2, val % 10)?;
                 }
                 0x55 => {
                     // Store v0 to vX in memory starting at I
-                    // println!("FX55");
+                    self.check_range(self.i as usize, op_x + 1)?;
                     for i in 0..=op_x {
-                        self.memory[(self.i + i as u16) as usize] = self.v[i];
+                        self.write(self.i as usize + i, self.v[i])?;
                     }
-                    self.i += (op_x + 1) as u16;
+                    self.increment_i(op_x);
                 }
                 0x65 => {
                     // Load v0 to vX from memory starting at I
-                    // println!("FX65");
+                    self.check_range(self.i as usize, op_x + 1)?;
                     for i in 0..=op_x {
-                        self.v[i] = self.memory[(self.i + i as u16) as usize];
+                        self.v[i] = self.read(self.i as usize + i)?;
                     }
-                    self.i += (op_x + 1) as u16;
+                    self.increment_i(op_x);
+                }
+                0x75 if self.quirks.super_chip => {
+                    // Save v0 to vX in the RPL flags
+                    self.rpl[..=op_x].copy_from_slice(&self.v[..=op_x]);
+                }
+                0x85 if self.quirks.super_chip => {
+                    // Load v0 to vX from the RPL flags
+                    self.v[..=op_x].copy_from_slice(&self.rpl[..=op_x]);
                 }
                 _ => {
-                    println!("Unknown opcode: 0x{:x}", opcode);
-                    return false;
+                    return Err(unknown);
                 }
             },
 
             _ => {
-                println!("Unknown opcode: 0x{:x}", opcode);
-                return false;
+                return Err(unknown);
             }
         }
-        true
+        Ok(StepOutcome::Continue)
+    }
+
+    fn draw_sprite(&mut self, x: u8, y: u8, n: usize) -> Result<(), Chip8Error> {
+        // Notes:
+        // * The starting position always wraps
+        // * With the clipping quirk, pixels past the edges are dropped,
+        //      otherwise they wrap around to the other side
+        let width = self.screen_width();
+        let height = self.screen_height();
+        let (sprite_width, sprite_height) = if n == 0 && self.quirks.super_chip {
+            (16, 16)
+        } else {
+            (8, n)
+        };
+        let row_bytes = sprite_width / 8;
+        let x = x as usize % width;
+        let y = y as usize % height;
+        let mut addr = self.i as usize;
+
+        // Check all the rows that will be read before drawing any. Each
+        // selected plane's data follows the last, and clipped rows aren't read
+        let planes = (self.planes & 0x3).count_ones() as usize;
+        if planes > 0 {
+            let rows = if self.quirks.clipping {
+                sprite_height.min(height - y)
+            } else {
+                sprite_height
+            };
+            self.check_range(addr, ((planes - 1) * sprite_height + rows) * row_bytes)?;
+        }
+        self.v[0xF] = 0;
+
+        for plane in [0x1, 0x2] {
+            if self.planes & plane == 0 {
+                continue;
+            }
+            for row in 0..sprite_height {
+                let py = y + row;
+                if py >= height && self.quirks.clipping {
+                    break;
+                }
+                let row_addr = addr + row * row_bytes;
+                let mut sprite = (self.read(row_addr)? as u16) << 8;
+                if row_bytes == 2 {
+                    sprite |= self.read(row_addr + 1)? as u16;
+                }
+                for col in 0..sprite_width {
+                    let px = x + col;
+                    if px >= width && self.quirks.clipping {
+                        break;
+                    }
+                    let pixel = sprite & (0x8000 >> col);
+                    let idx = px % width + (py % height) * width;
+                    if pixel != 0 {
+                        if self.gfx[idx] & plane != 0 {
+                            self.v[0xF] = 1;
+                        }
+                        self.gfx[idx] ^= plane;
+                    }
+                }
+            }
+            // The next plane's data follows this one
+            addr += sprite_height * row_bytes;
+        }
+        Ok(())
+    }
+
+    // Scroll the selected planes by dx, dy pixels. Pixels scrolled off the
+    // screen are lost and the uncovered area is cleared
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.screen_width() as isize;
+        let height = self.screen_height() as isize;
+        let planes = self.planes;
+        let old = self.gfx.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = (x - dx, y - dy);
+                let src = if (0..width).contains(&sx) && (0..height).contains(&sy) {
+                    old[(sx + sy * width) as usize]
+                } else {
+                    0
+                };
+                let idx = (x + y * width) as usize;
+                self.gfx[idx] = (old[idx] & !planes) | (src & planes);
+            }
+        }
+    }
+
+    fn read(&self, addr: usize) -> Result<u8, Chip8Error> {
+        self.memory
+            .get(addr)
+            .copied()
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr })
+    }
+
+    fn write(&mut self, addr: usize, value: u8) -> Result<(), Chip8Error> {
+        let byte = self
+            .memory
+            .get_mut(addr)
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr })?;
+        *byte = value;
+        Ok(())
+    }
+
+    // Fail if any of the `len` bytes from `addr` is past the end of memory,
+    // so instructions can check before changing anything
+    fn check_range(&self, addr: usize, len: usize) -> Result<(), Chip8Error> {
+        if len > 0 && addr + len > self.memory.len() {
+            return Err(Chip8Error::MemoryOutOfBounds {
+                addr: addr.max(self.memory.len()),
+            });
+        }
+        Ok(())
+    }
+
+    // Move I on after FX55 or FX65 has touched v0 to vX, if the quirks say so
+    fn increment_i(&mut self, x: usize) {
+        if self.quirks.memory_increment {
+            let by_x = self.quirks.memory_increment_by_x;
+            self.i = self.i.wrapping_add(x as u16 + !by_x as u16);
+        }
+    }
+
+    fn fetch(&self, addr: u16) -> Result<u16, Chip8Error> {
+        let addr = addr as usize;
+        Ok((self.read(addr)? as u16) << 8 | self.read(addr + 1)? as u16)
+    }
+
+    fn key(&self, key: u8) -> Result<u8, Chip8Error> {
+        self.keys
+            .get(key as usize)
+            .copied()
+            .ok_or(Chip8Error::InvalidKey { key })
+    }
+
+    // Skip the next instruction, which is 4 bytes long if it's XO-CHIP's F000 NNNN
+    fn skip_next(&mut self) {
+        if self.quirks.xo_chip && self.fetch(self.pc) == Ok(0xF000) {
+            self.pc = self.pc.wrapping_add(4);
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    // Registers vX to vY inclusive, counting down if X > Y
+    fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+        if x <= y {
+            Box::new(x..=y)
+        } else {
+            Box::new((y..=x).rev())
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use emu_abstractions::display::NullDisplay;
+    use crate::display::NullDisplay;
 
     use super::*;
     use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     fn setup(prog: Vec<u8>) -> CPU<NullDisplay> {
+        setup_with_quirks(prog, Quirks::COSMAC_VIP)
+    }
+
+    fn setup_with_quirks(prog: Vec<u8>, quirks: Quirks) -> CPU<NullDisplay> {
         let display = NullDisplay::new();
-        let mut cpu = CPU::new(display);
+        let mut cpu = CPU::new(display, quirks);
         cpu.initialize();
-        cpu.load(prog);
+        cpu.load(prog).unwrap();
         cpu
     }
 
@@ -534,12 +1013,28 @@ mod tests {
         assert_eq!(cpu.memory[PROGRAM_START as usize + 1], program[1]);
     }
 
+    #[test]
+    fn test_load_too_large() {
+        let mut cpu = setup(vec![]);
+        let max = MEMORY_SIZE - PROGRAM_START as usize;
+        assert!(cpu.load(vec![0xFF; max]).is_ok());
+        assert!(matches!(
+            cpu.load(vec![0; max + 1]),
+            Err(RomError::TooLarge { size, max: 3584 }) if size == max + 1
+        ));
+
+        // XO-CHIP has room for a lot more
+        let mut cpu = setup_with_quirks(vec![], Quirks::XO_CHIP);
+        assert!(cpu.load(vec![0; 0x10000 - 0x200]).is_ok());
+        assert!(cpu.load(vec![0; 0x10000]).is_err());
+    }
+
     #[test]
     fn test_clear_screen() {
         let mut cpu = setup(vec![0x00, 0xE0]);
         cpu.gfx[0] = 1;
         cpu.gfx[64] = 1;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.gfx.iter().all(|&x| x == 0));
         assert!(cpu.pc == PROGRAM_START + 2);
     }
@@ -547,14 +1042,14 @@ mod tests {
     #[test]
     fn test_jump() {
         let mut cpu = setup(vec![0x12, 0x34]);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x1234 & 0x0FFF);
     }
 
     #[test]
     fn test_call_subroutine() {
         let mut cpu = setup(vec![0x22, 0x34]);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.sp == 1);
         assert!(cpu.stack[0] == 0x202);
         assert!(cpu.pc == 0x1234 & 0x0FFF);
@@ -566,12 +1061,12 @@ mod tests {
         let mut cpu = setup(program.clone());
         cpu.v[0] = 0x11;
         assert!(cpu.pc == 0x200, "got 0x{:X}", cpu.pc);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x204, "got 0x{:X}", cpu.pc);
 
         cpu.initialize();
-        cpu.load(program.clone());
-        cpu.cycle(0, 0);
+        cpu.load(program.clone()).unwrap();
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x202);
     }
 
@@ -581,12 +1076,12 @@ mod tests {
         let mut cpu = setup(program.clone());
 
         cpu.v[0] = 0x11;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x202);
 
         cpu.initialize();
-        cpu.load(program.clone());
-        cpu.cycle(0, 0);
+        cpu.load(program.clone()).unwrap();
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x204);
     }
 
@@ -597,30 +1092,30 @@ mod tests {
         cpu.v[1] = 0x01;
         cpu.v[2] = 0x00;
 
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x202);
 
         cpu.initialize();
-        cpu.load(program.clone());
+        cpu.load(program.clone()).unwrap();
         cpu.v[1] = 0x01;
         cpu.v[2] = 0x01;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == 0x204);
     }
 
     #[test]
     fn test_set_vx() {
         let mut cpu = setup(vec![0x60, 0x12]);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0x12);
     }
 
     #[test]
     fn test_add_vx() {
         let mut cpu = setup(vec![0x70, 0x12, 0x70, 0x12]);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0x12);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0x12 + 0x12);
     }
 
@@ -629,14 +1124,14 @@ mod tests {
         let mut cpu = setup(vec![0x80, 0x10]);
         cpu.v[0] = 0x10;
         cpu.v[1] = 0x20;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0x20);
     }
 
     #[test]
     fn test_store_vx() {
         let mut cpu = setup(vec![0x61, 0x23]);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[1] == 0x23);
     }
 
@@ -645,15 +1140,15 @@ mod tests {
         let program = vec![0x80, 0x1E];
         let mut cpu = setup(program.clone());
         cpu.v[1] = 0b00000100;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0b00001000);
         assert!(cpu.v[0xF] == 0);
         assert!(cpu.v[1] == 0b00000100);
 
         cpu.initialize();
-        cpu.load(program.clone());
+        cpu.load(program.clone()).unwrap();
         cpu.v[1] = 0b10000100;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0b00001000);
         assert!(cpu.v[0xF] == 1);
         assert!(cpu.v[1] == 0b10000100);
@@ -663,10 +1158,10 @@ mod tests {
     fn test_keypress() {
         let mut cpu = setup(vec![0xE0, 0x9E, 0x00, 0x00, 0xE0, 0xA1]);
         cpu.keys[0] = 1;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == PROGRAM_START + 4, "got 0x{:X}", cpu.pc);
         cpu.keys[0] = 0;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.pc == PROGRAM_START + 8, "got 0x{:X}", cpu.pc);
     }
 
@@ -674,7 +1169,7 @@ mod tests {
     fn test_get_font_address() {
         let mut cpu = setup(vec![0xF0, 0x29]);
         cpu.v[0] = 0x3;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.i == (0x3 * 5) + FONTSET_START as u16);
     }
 
@@ -705,9 +1200,9 @@ mod tests {
         assert!(cpu.pc == PROGRAM_START);
 
         // Check various fontset bytes
-        assert!(cpu.memory[FONTSET_START as usize] == 0xF0);
-        assert!(cpu.memory[FONTSET_START as usize + 5] == 0x20);
-        assert!(cpu.memory[FONTSET_START as usize + 79] == 0x80);
+        assert!(cpu.memory[FONTSET_START] == 0xF0);
+        assert!(cpu.memory[FONTSET_START + 5] == 0x20);
+        assert!(cpu.memory[FONTSET_START + 79] == 0x80);
     }
 
     #[test]
@@ -716,16 +1211,16 @@ mod tests {
         let mut cpu = setup(program.clone());
         cpu.v[1] = 0b00000100;
         cpu.v[0] = 0b00000000;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0b00001000);
         assert!(cpu.v[1] == 0b00000100);
         assert!(cpu.v[0xF] == 0);
 
         cpu.initialize();
-        cpu.load(program.clone());
+        cpu.load(program.clone()).unwrap();
         cpu.v[1] = 0b10000100;
         cpu.v[0] = 0b00000000;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.v[0] == 0b00001000);
         assert!(cpu.v[1] == 0b10000100);
         assert!(cpu.v[0xF] == 1);
@@ -737,34 +1232,34 @@ mod tests {
         // Test drawing sprite
         cpu.v[0] = 0x00;
         cpu.i = 0x50; // Sprite for 0
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.gfx[0] == 1);
         assert!(cpu.gfx[1] == 1);
         assert!(cpu.gfx[2] == 1);
         assert!(cpu.gfx[3] == 1);
-        assert!(cpu.gfx[0 + SCREEN_WIDTH] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH] == 1);
         assert!(cpu.gfx[3 + SCREEN_WIDTH] == 1);
-        assert!(cpu.gfx[0 + SCREEN_WIDTH * 2] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH * 2] == 1);
         assert!(cpu.gfx[3 + SCREEN_WIDTH * 2] == 1);
-        assert!(cpu.gfx[0 + SCREEN_WIDTH * 3] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH * 3] == 1);
         assert!(cpu.gfx[3 + SCREEN_WIDTH * 3] == 1);
-        assert!(cpu.gfx[0 + SCREEN_WIDTH * 4] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH * 4] == 1);
         assert!(cpu.gfx[1 + SCREEN_WIDTH * 4] == 1);
         assert!(cpu.gfx[2 + SCREEN_WIDTH * 4] == 1);
         assert!(cpu.gfx[3 + SCREEN_WIDTH * 4] == 1);
         assert!(cpu.v[0xF] == 0);
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.gfx.iter().all(|&x| x == 0));
         assert!(cpu.v[0xF] == 1);
 
         // Test drawing sprite near right edge
         cpu.initialize();
         let program = vec![0xD0, 0x15];
-        cpu.load(program.clone());
+        cpu.load(program.clone()).unwrap();
         cpu.v[0] = SCREEN_WIDTH as u8 - 4; // Just enough to fit sprite
         cpu.v[1] = 0x01;
         cpu.i = 0x50;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
         assert!(cpu.gfx.iter().take(SCREEN_WIDTH).all(|&x| x == 0));
         assert!(cpu.gfx[SCREEN_WIDTH - 4 + SCREEN_WIDTH] == 1);
         assert!(cpu.gfx[SCREEN_WIDTH - 3 + SCREEN_WIDTH] == 1);
@@ -775,22 +1270,689 @@ mod tests {
         // Test drawing sprite near top
         cpu.initialize();
         let program = vec![0xD0, 0x15];
-        cpu.load(program.clone());
+        cpu.load(program.clone()).unwrap();
         cpu.v[0] = 0x00;
         cpu.v[1] = SCREEN_HEIGHT as u8 - 1;
         cpu.i = 0x50;
-        cpu.cycle(0, 0);
+        cpu.cycle(0, 0).unwrap();
 
         assert!(cpu
             .gfx
             .iter()
             .take(SCREEN_WIDTH * (SCREEN_HEIGHT - 1))
             .all(|&x| x == 0));
-        assert!(cpu.gfx[0 + SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 1);
         assert!(cpu.gfx[1 + SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 1);
         assert!(cpu.gfx[2 + SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 1);
         assert!(cpu.gfx[3 + SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 1);
         assert!(cpu.gfx[4 + SCREEN_WIDTH * (SCREEN_HEIGHT - 1)] == 0);
         assert!(cpu.v[0xF] == 0);
     }
+
+    #[test]
+    fn test_quirk_vf_reset() {
+        let program = vec![0x80, 0x11, 0x80, 0x12, 0x80, 0x13];
+        let quirks = Quirks {
+            vf_reset: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        for _ in 0..3 {
+            cpu.v[0xF] = 0x5;
+            cpu.cycle(0, 0).unwrap();
+            assert!(cpu.v[0xF] == 0);
+        }
+
+        let quirks = Quirks {
+            vf_reset: false,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        for _ in 0..3 {
+            cpu.v[0xF] = 0x5;
+            cpu.cycle(0, 0).unwrap();
+            assert!(cpu.v[0xF] == 0x5);
+        }
+    }
+
+    #[test]
+    fn test_quirk_memory_increment() {
+        let program = vec![0xF2, 0x55, 0xF2, 0x65];
+        let quirks = Quirks {
+            memory_increment: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.i = 0x300;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x303, "got 0x{:X}", cpu.i);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x306, "got 0x{:X}", cpu.i);
+
+        let quirks = Quirks {
+            memory_increment: true,
+            memory_increment_by_x: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.i = 0x300;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x302, "got 0x{:X}", cpu.i);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x304, "got 0x{:X}", cpu.i);
+
+        let mut cpu = setup_with_quirks(program.clone(), Quirks::CHIP_48);
+        cpu.i = 0x300;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x302, "got 0x{:X}", cpu.i);
+
+        // Only applies when I is moved on at all
+        let quirks = Quirks {
+            memory_increment: false,
+            memory_increment_by_x: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        cpu.i = 0x300;
+        cpu.v[0] = 0x1;
+        cpu.v[1] = 0x2;
+        cpu.v[2] = 0x3;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x300, "got 0x{:X}", cpu.i);
+        assert!(cpu.memory[0x300..0x303] == [0x1, 0x2, 0x3]);
+        cpu.v = [0; 16];
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0x300, "got 0x{:X}", cpu.i);
+        assert!(cpu.v[0..3] == [0x1, 0x2, 0x3]);
+    }
+
+    #[test]
+    fn test_quirk_display_wait() {
+        let program = vec![0xD0, 0x05];
+        let quirks = Quirks {
+            display_wait: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.i = 0x50;
+        // Too late in the frame, so the draw is retried
+        cpu.cycle(1000, 1000).unwrap();
+        assert!(cpu.pc == PROGRAM_START, "got 0x{:X}", cpu.pc);
+        assert!(cpu.gfx.iter().all(|&x| x == 0));
+        cpu.cycle(0, 1000).unwrap();
+        assert!(cpu.pc == PROGRAM_START + 2, "got 0x{:X}", cpu.pc);
+        assert!(cpu.gfx[0] == 1);
+
+        let quirks = Quirks {
+            display_wait: false,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        cpu.i = 0x50;
+        cpu.cycle(1000, 1000).unwrap();
+        assert!(cpu.pc == PROGRAM_START + 2, "got 0x{:X}", cpu.pc);
+        assert!(cpu.gfx[0] == 1);
+    }
+
+    #[test]
+    fn test_quirk_clipping() {
+        let program = vec![0xD0, 0x15];
+        let quirks = Quirks {
+            clipping: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.v[0] = SCREEN_WIDTH as u8 - 2;
+        cpu.v[1] = SCREEN_HEIGHT as u8 - 2;
+        cpu.i = 0x50; // Sprite for 0
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx.iter().filter(|&&x| x == 1).count() == 3);
+        assert!(cpu.gfx[0] == 0);
+
+        let quirks = Quirks {
+            clipping: false,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        cpu.v[0] = SCREEN_WIDTH as u8 - 2;
+        cpu.v[1] = SCREEN_HEIGHT as u8 - 2;
+        cpu.i = 0x50;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx.iter().filter(|&&x| x == 1).count() == 14);
+        // Third row of the sprite wraps to the top left
+        assert!(cpu.gfx[0] == 0);
+        assert!(cpu.gfx[1] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH - 2] == 1);
+    }
+
+    #[test]
+    fn test_quirk_shifting() {
+        let program = vec![0x80, 0x16, 0x80, 0x1E];
+        let quirks = Quirks {
+            shifting: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.v[0] = 0b00000011;
+        cpu.v[1] = 0b11110000;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[0] == 0b00000001);
+        assert!(cpu.v[0xF] == 1);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[0] == 0b00000010);
+        assert!(cpu.v[0xF] == 0);
+
+        let quirks = Quirks {
+            shifting: false,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        cpu.v[0] = 0b00000011;
+        cpu.v[1] = 0b11110000;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[0] == 0b01111000);
+        assert!(cpu.v[0xF] == 0);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[0] == 0b11100000);
+        assert!(cpu.v[0xF] == 1);
+    }
+
+    #[test]
+    fn test_quirk_jumping() {
+        let program = vec![0xB3, 0x00];
+        let quirks = Quirks {
+            jumping: true,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program.clone(), quirks);
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.pc == 0x320, "got 0x{:X}", cpu.pc);
+
+        let quirks = Quirks {
+            jumping: false,
+            ..Quirks::MODERN
+        };
+        let mut cpu = setup_with_quirks(program, quirks);
+        cpu.v[0] = 0x10;
+        cpu.v[3] = 0x20;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.pc == 0x310, "got 0x{:X}", cpu.pc);
+    }
+
+    #[test]
+    fn test_resolution_switch() {
+        let mut cpu = setup_with_quirks(vec![0x00, 0xFF, 0x00, 0xFE], Quirks::SUPER_CHIP);
+        cpu.gfx[0] = 1;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.hires);
+        assert!(cpu.screen_width() == HIRES_SCREEN_WIDTH);
+        assert!(cpu.screen_height() == HIRES_SCREEN_HEIGHT);
+        assert!(cpu.gfx.len() == HIRES_SCREEN_WIDTH * HIRES_SCREEN_HEIGHT);
+        assert!(cpu.gfx.iter().all(|&x| x == 0));
+        cpu.cycle(0, 0).unwrap();
+        assert!(!cpu.hires);
+        assert!(cpu.gfx.len() == SCREEN_WIDTH * SCREEN_HEIGHT);
+    }
+
+    #[test]
+    fn test_draw_large_sprite() {
+        let mut cpu = setup_with_quirks(vec![0x00, 0xFF, 0xD0, 0x10], Quirks::SUPER_CHIP);
+        cpu.v[0] = HIRES_SCREEN_WIDTH as u8 - 8;
+        cpu.v[1] = 0x01;
+        cpu.i = 0x300;
+        for row in 0..16 {
+            cpu.memory[0x300 + row * 2] = 0xFF;
+            cpu.memory[0x300 + row * 2 + 1] = 0x01;
+        }
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        // Right half is clipped, leaving an 8x16 block
+        assert!(cpu.gfx.iter().filter(|&&x| x == 1).count() == 8 * 16);
+        assert!(cpu.gfx[HIRES_SCREEN_WIDTH - 8 + HIRES_SCREEN_WIDTH] == 1);
+        assert!(cpu.gfx[HIRES_SCREEN_WIDTH - 1 + HIRES_SCREEN_WIDTH * 16] == 1);
+        assert!(cpu.gfx[HIRES_SCREEN_WIDTH - 8 + HIRES_SCREEN_WIDTH * 17] == 0);
+        assert!(cpu.v[0xF] == 0);
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut cpu = setup_with_quirks(
+            vec![0x00, 0xC2, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC],
+            Quirks::SUPER_CHIP,
+        );
+        cpu.gfx[0] = 1;
+        cpu.gfx[SCREEN_WIDTH - 1] = 1;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[SCREEN_WIDTH * 2] == 1);
+        assert!(cpu.gfx[SCREEN_WIDTH * 3 - 1] == 1);
+        assert!(cpu.gfx.iter().filter(|&&x| x == 1).count() == 2);
+        cpu.cycle(0, 0).unwrap();
+        // Pixels scrolled off the right edge are lost
+        assert!(cpu.gfx[SCREEN_WIDTH * 2 + 4] == 1);
+        assert!(cpu.gfx.iter().filter(|&&x| x == 1).count() == 1);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[SCREEN_WIDTH * 2] == 1);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx.iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut cpu = setup_with_quirks(vec![0x00, 0xFD], Quirks::SUPER_CHIP);
+        assert_eq!(cpu.cycle(0, 0), Ok(StepOutcome::Exit));
+    }
+
+    #[test]
+    fn test_get_big_font_address() {
+        let mut cpu = setup_with_quirks(vec![0xF0, 0x30], Quirks::SUPER_CHIP);
+        cpu.v[0] = 0xA;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == (0xA * 10) + BIG_FONTSET_START as u16);
+        assert!(cpu.memory[cpu.i as usize] == 0x7E);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        let mut cpu = setup_with_quirks(vec![0xF2, 0x75, 0xF2, 0x85], Quirks::SUPER_CHIP);
+        cpu.v[0] = 0x1;
+        cpu.v[1] = 0x2;
+        cpu.v[2] = 0x3;
+        cpu.v[3] = 0x4;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.rpl[0..4] == [0x1, 0x2, 0x3, 0x0]);
+        cpu.v = [0; 16];
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[0..4] == [0x1, 0x2, 0x3, 0x0]);
+    }
+
+    #[test]
+    fn test_xo_memory_size() {
+        let cpu = setup(vec![]);
+        assert!(cpu.memory.len() == MEMORY_SIZE);
+        let cpu = setup_with_quirks(vec![], Quirks::XO_CHIP);
+        assert!(cpu.memory.len() == XO_MEMORY_SIZE);
+    }
+
+    #[test]
+    fn test_long_load_i() {
+        let program = vec![0xF0, 0x00, 0xAB, 0xCD, 0x60, 0x01];
+        let mut cpu = setup_with_quirks(program, Quirks::XO_CHIP);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.i == 0xABCD, "got 0x{:X}", cpu.i);
+        assert!(cpu.pc == PROGRAM_START + 4, "got 0x{:X}", cpu.pc);
+    }
+
+    #[test]
+    fn test_skip_long_load_i() {
+        let program = vec![0x30, 0x00, 0xF0, 0x00, 0xAB, 0xCD];
+        let mut cpu = setup_with_quirks(program.clone(), Quirks::XO_CHIP);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.pc == PROGRAM_START + 6, "got 0x{:X}", cpu.pc);
+
+        // Without XO-CHIP F000 is an unknown 2 byte opcode
+        let mut cpu = setup_with_quirks(program, Quirks::MODERN);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.pc == PROGRAM_START + 4, "got 0x{:X}", cpu.pc);
+    }
+
+    #[test]
+    fn test_xo_opcodes_need_xo_chip() {
+        // 00D1, 5012, 5013, F000 NNNN, F101, F002 and F03A
+        let opcodes = [
+            [0x00, 0xD1],
+            [0x50, 0x12],
+            [0x50, 0x13],
+            [0xF0, 0x00],
+            [0xF1, 0x01],
+            [0xF0, 0x02],
+            [0xF0, 0x3A],
+        ];
+        for opcode in opcodes {
+            let mut program = opcode.to_vec();
+            program.extend([0x03, 0x00]);
+            for (name, quirks) in Quirks::PRESETS {
+                let mut cpu = setup_with_quirks(program.clone(), quirks);
+                cpu.i = 0x300;
+                let result = cpu.cycle(0, 0);
+                if quirks.xo_chip {
+                    assert!(result.is_ok(), "{:02X?} on {}: {:?}", opcode, name, result);
+                } else {
+                    assert_eq!(
+                        result,
+                        Err(Chip8Error::UnknownOpcode {
+                            pc: PROGRAM_START,
+                            opcode: u16::from_be_bytes(opcode),
+                        }),
+                        "{:02X?} on {}",
+                        opcode,
+                        name
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_schip_opcodes_need_super_chip() {
+        // 00C1, 00FB, 00FC, 00FD, 00FE, 00FF, F030, F075 and F085
+        let opcodes = [
+            [0x00, 0xC1],
+            [0x00, 0xFB],
+            [0x00, 0xFC],
+            [0x00, 0xFD],
+            [0x00, 0xFE],
+            [0x00, 0xFF],
+            [0xF0, 0x30],
+            [0xF0, 0x75],
+            [0xF0, 0x85],
+        ];
+        for opcode in opcodes {
+            for (name, quirks) in Quirks::PRESETS {
+                let mut cpu = setup_with_quirks(opcode.to_vec(), quirks);
+                let result = cpu.cycle(0, 0);
+                if quirks.super_chip {
+                    assert!(result.is_ok(), "{:02X?} on {}: {:?}", opcode, name, result);
+                } else {
+                    assert_eq!(
+                        result,
+                        Err(Chip8Error::UnknownOpcode {
+                            pc: PROGRAM_START,
+                            opcode: u16::from_be_bytes(opcode),
+                        }),
+                        "{:02X?} on {}",
+                        opcode,
+                        name
+                    );
+                    assert!(!cpu.hires);
+                }
+            }
+        }
+
+        // Without SUPER-CHIP DXY0 draws nothing rather than a 16x16 sprite
+        let mut cpu = setup(vec![0xD0, 0x10]);
+        cpu.i = 0x300;
+        cpu.memory[0x300..0x320].fill(0xFF);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx.iter().all(|&x| x == 0));
+        assert!(cpu.v[0xF] == 0);
+    }
+
+    #[test]
+    fn test_save_load_register_range() {
+        let program = vec![0x51, 0x32, 0x53, 0x12, 0x51, 0x33, 0x53, 0x13];
+        let mut cpu = setup_with_quirks(program, Quirks::XO_CHIP);
+        cpu.i = 0x300;
+        cpu.v[1] = 0x1;
+        cpu.v[2] = 0x2;
+        cpu.v[3] = 0x3;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.memory[0x300..0x303] == [0x1, 0x2, 0x3]);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.memory[0x300..0x303] == [0x3, 0x2, 0x1]);
+        assert!(cpu.i == 0x300);
+
+        cpu.v = [0; 16];
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[1..4] == [0x3, 0x2, 0x1]);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.v[1..4] == [0x1, 0x2, 0x3]);
+        assert!(cpu.i == 0x300);
+    }
+
+    #[test]
+    fn test_bitplanes() {
+        // Select both planes, draw a 1 row sprite, then clear plane 1 only
+        let program = vec![0xF3, 0x01, 0xD0, 0x01, 0xF1, 0x01, 0x00, 0xE0];
+        let mut cpu = setup_with_quirks(program, Quirks::XO_CHIP);
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0b11000000; // plane 1
+        cpu.memory[0x301] = 0b10100000; // plane 2
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.planes == 0x3);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[0..4] == [3, 1, 2, 0]);
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[0..4] == [2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_bitplane_collision() {
+        let program = vec![0xF2, 0x01, 0xD0, 0x01, 0xF1, 0x01, 0xD0, 0x01];
+        let mut cpu = setup_with_quirks(program, Quirks::XO_CHIP);
+        cpu.i = 0x300;
+        cpu.memory[0x300] = 0b10000000;
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[0] == 2);
+        assert!(cpu.v[0xF] == 0);
+        // Drawing on plane 1 doesn't collide with plane 2
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.gfx[0] == 3);
+        assert!(cpu.v[0xF] == 0);
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        let mut cpu = setup_with_quirks(vec![0xF2, 0x01, 0x00, 0xD1], Quirks::XO_CHIP);
+        cpu.gfx[SCREEN_WIDTH] = 3;
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        // Only plane 2 moves
+        assert!(cpu.gfx[0] == 2);
+        assert!(cpu.gfx[SCREEN_WIDTH] == 1);
+    }
+
+    #[test]
+    fn test_audio_pattern_and_pitch() {
+        let mut cpu = setup_with_quirks(vec![0xF0, 0x02, 0xF1, 0x3A], Quirks::XO_CHIP);
+        cpu.i = 0x300;
+        for i in 0..16 {
+            cpu.memory[0x300 + i] = i as u8;
+        }
+        cpu.v[1] = 0x70;
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.audio_pattern[15] == 15);
+        cpu.cycle(0, 0).unwrap();
+        assert!(cpu.pitch == 0x70);
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let mut cpu = setup(vec![0x00, 0x00]);
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::UnknownOpcode {
+                pc: PROGRAM_START,
+                opcode: 0x0000
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // Call itself forever
+        let mut cpu = setup(vec![0x22, 0x00]);
+        for _ in 0..16 {
+            cpu.cycle(0, 0).unwrap();
+        }
+        assert_eq!(cpu.cycle(0, 0), Err(Chip8Error::StackOverflow));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut cpu = setup(vec![0x00, 0xEE]);
+        assert_eq!(cpu.cycle(0, 0), Err(Chip8Error::StackUnderflow));
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut cpu = setup(vec![0xF0, 0x33, 0xF2, 0x55, 0xF2, 0x65, 0xD0, 0x05]);
+        cpu.i = MEMORY_SIZE as u16 - 2;
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::MemoryOutOfBounds { addr: MEMORY_SIZE })
+        );
+        cpu.pc += 2;
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::MemoryOutOfBounds { addr: MEMORY_SIZE })
+        );
+        cpu.pc += 2;
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::MemoryOutOfBounds { addr: MEMORY_SIZE })
+        );
+        cpu.pc += 2;
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::MemoryOutOfBounds { addr: MEMORY_SIZE })
+        );
+    }
+
+    #[test]
+    fn test_out_of_bounds_has_no_effects() {
+        // Store, BCD, load and draw, each running off the end of memory
+        let program = vec![0xF2, 0x55, 0xF0, 0x33, 0xF2, 0x65, 0xD0, 0x15];
+        let mut cpu = setup(program);
+        cpu.i = MEMORY_SIZE as u16 - 2;
+        cpu.v[..3].copy_from_slice(&[0xAA, 0xBB, 0xCC]);
+        cpu.v[0xF] = 1;
+        cpu.memory[MEMORY_SIZE - 2..].copy_from_slice(&[0x80, 0x80]);
+        for _ in 0..4 {
+            let pc = cpu.pc;
+            assert!(cpu.cycle(0, 0).is_err());
+            assert_eq!(cpu.memory[MEMORY_SIZE - 2..], [0x80, 0x80]);
+            assert_eq!(cpu.v[..3], [0xAA, 0xBB, 0xCC]);
+            assert_eq!(cpu.v[0xF], 1);
+            assert!(cpu.gfx.iter().all(|&p| p == 0));
+            assert_eq!(cpu.pc, pc);
+            cpu.pc += 2;
+        }
+
+        // Register ranges, either way round (XO-CHIP)
+        let mut cpu = setup_with_quirks(vec![0x50, 0x32, 0x53, 0x03], Quirks::XO_CHIP);
+        cpu.i = (XO_MEMORY_SIZE - 2) as u16;
+        cpu.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(cpu.cycle(0, 0).is_err());
+        assert_eq!(cpu.memory[XO_MEMORY_SIZE - 2..], [0, 0]);
+        cpu.pc += 2;
+        assert!(cpu.cycle(0, 0).is_err());
+        assert_eq!(cpu.v[..4], [1, 2, 3, 4]);
+    }
+
+    // Keeps whether the buzzer sounded in each frame
+    #[derive(Debug)]
+    struct RecordedAudio(Rc<RefCell<Vec<bool>>>);
+
+    impl Audio for RecordedAudio {
+        fn update(&mut self, playing: bool) {
+            self.0.borrow_mut().push(playing);
+        }
+    }
+
+    #[test]
+    fn test_sound_timer_frames() {
+        // The buzzer sounds for as many frames as the timer was set to
+        for frames in [1, 3] {
+            let mut cpu = setup(vec![]);
+            let played = Rc::default();
+            cpu.set_audio(Box::new(RecordedAudio(Rc::clone(&played))));
+            cpu.sound_timer = frames;
+            for _ in 0..5 {
+                cpu.end_frame();
+            }
+            let beeps = played.borrow().iter().filter(|&&p| p).count();
+            assert_eq!(beeps, frames as usize);
+            assert!(played.borrow()[..beeps].iter().all(|&p| p));
+        }
+    }
+
+    #[test]
+    fn test_wait_at_end_of_memory() {
+        // FX0A and a waiting DXYN in the last word go back to it after pc
+        // has wrapped to 0
+        let quirks = Quirks {
+            display_wait: true,
+            ..Quirks::XO_CHIP
+        };
+        for opcode in [[0xF0, 0x0A], [0xD0, 0x01]] {
+            let mut cpu = setup_with_quirks(vec![], quirks);
+            cpu.memory[XO_MEMORY_SIZE - 2..].copy_from_slice(&opcode);
+            cpu.pc = (XO_MEMORY_SIZE - 2) as u16;
+            assert_eq!(cpu.cycle(1000, 1000), Ok(StepOutcome::Waiting));
+            assert_eq!(cpu.pc as usize, XO_MEMORY_SIZE - 2);
+        }
+    }
+
+    #[test]
+    fn test_fetch_out_of_bounds() {
+        let mut cpu = setup(vec![]);
+        cpu.pc = MEMORY_SIZE as u16 - 1;
+        assert_eq!(
+            cpu.cycle(0, 0),
+            Err(Chip8Error::MemoryOutOfBounds { addr: MEMORY_SIZE })
+        );
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let mut cpu = setup(vec![0xE0, 0x9E, 0xE0, 0xA1]);
+        cpu.v[0] = 0x10;
+        assert_eq!(cpu.cycle(0, 0), Err(Chip8Error::InvalidKey { key: 0x10 }));
+        cpu.pc += 2;
+        assert_eq!(cpu.cycle(0, 0), Err(Chip8Error::InvalidKey { key: 0x10 }));
+    }
+
+    #[test]
+    fn test_wait_for_key() {
+        let mut cpu = setup(vec![0xF0, 0x0A]);
+        assert_eq!(cpu.cycle(0, 0), Ok(StepOutcome::Waiting));
+        assert!(cpu.pc == PROGRAM_START);
+        cpu.keys[5] = 1;
+        assert_eq!(cpu.cycle(0, 0), Ok(StepOutcome::Continue));
+        assert_eq!(cpu.v[0], 5);
+        // Carries on with the next instruction rather than skipping it
+        assert!(cpu.pc == PROGRAM_START + 2, "got 0x{:X}", cpu.pc);
+    }
+
+    #[test]
+    fn test_run_until_frames() {
+        // Set the delay timer to 10, then count up in v1 forever
+        let mut cpu = setup(vec![0x60, 0x0A, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04]);
+        cpu.cycles_per_frame = 10;
+        cpu.turbo = true;
+        let pause_after = |n: usize| {
+            let mut calls = 0;
+            move |_: &CPU<NullDisplay>| {
+                calls += 1;
+                calls > n
+            }
+        };
+        assert_eq!(cpu.run_until(pause_after(5)), Ok(StopReason::Paused));
+        assert_eq!(cpu.delay_timer, 10);
+        // Resumes part way through the frame rather than starting a new one
+        assert_eq!(cpu.run_until(pause_after(5)), Ok(StopReason::Paused));
+        assert_eq!(cpu.delay_timer, 9);
+        assert_eq!(cpu.v[1], 4);
+    }
+
+    #[test]
+    fn test_seeded_random() {
+        // v0 := random 0xFF, three times
+        let prog = vec![0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF];
+        let run = |seed| {
+            let mut cpu = setup(prog.clone());
+            cpu.random = Box::new(SeededRandom::new(seed));
+            for _ in 0..3 {
+                cpu.cycle(0, 0).unwrap();
+            }
+            cpu.v
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
 }
@@ -0,0 +1,896 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::cpu::{StepOutcome, StopReason, CPU};
+use crate::disasm::{self, Syntax};
+use crate::display::Display;
+use crate::octo::SourceMap;
+use crate::quirks::Quirks;
+use crate::rom_loader::{Ch8RomLoader, HexRomLoader, OctoRomLoader, RomLoader};
+
+/*
+   Debug Adapter Protocol server (https://microsoft.github.io/debug-adapter-protocol)
+   * Messages are JSON with a Content-Length header, read from stdin and
+     written to stdout
+   * There's one thread, id 1, and each frame of the call stack is a 2NNN
+     call site
+   * Launching an Octo (.8o) source file assembles it with a source map, so
+     breakpoints can be set on lines and stopped PCs shown in the source.
+     Other ROMs only get instruction breakpoints and the disassembly view
+   * Memory references are addresses in hex, e.g. 0x200
+*/
+
+const THREAD: i64 = 1;
+
+// Variable references for the scopes of every frame
+const REGISTERS: i64 = 1;
+const TIMERS: i64 = 2;
+const STACK: i64 = 3;
+
+// How to run after a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Continue,
+    // One instruction
+    Instruction,
+    // To the next source line, or one instruction without a source map
+    StepIn,
+    // One instruction, running over a 2NNN call
+    Over,
+    // Like StepIn, but running over calls
+    Next,
+    // Until the current subroutine returns
+    StepOut,
+}
+
+pub struct DapServer<D: Display, W: Write> {
+    display: Option<D>,
+    cpu: Option<CPU<D>>,
+    // The Octo source that was launched, and where its instructions are
+    source: Option<(PathBuf, SourceMap)>,
+    source_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+    stop_on_entry: bool,
+    // Set while the program runs, so requests that arrive meanwhile know
+    running: bool,
+    pause_requested: bool,
+    input: Receiver<Value>,
+    output: W,
+    seq: i64,
+}
+
+impl<D: Display> DapServer<D, io::Stdout> {
+    // The machine is made with `display` when the client launches a ROM
+    pub fn stdio(display: D) -> DapServer<D, io::Stdout> {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = BufReader::new(io::stdin().lock());
+            while let Some(message) = read_message(&mut stdin) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        DapServer::new(display, input, io::stdout())
+    }
+}
+
+// The next message, skipping any that aren't valid JSON. None at the end
+// of the input
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if input.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse().ok();
+                }
+            }
+        }
+        let Some(length) = length else { continue };
+        let mut body = vec![0; length];
+        input.read_exact(&mut body).ok()?;
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+impl<D: Display, W: Write> DapServer<D, W> {
+    fn new(display: D, input: Receiver<Value>, output: W) -> DapServer<D, W> {
+        DapServer {
+            display: Some(display),
+            cpu: None,
+            source: None,
+            source_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            stop_on_entry: false,
+            running: false,
+            pause_requested: false,
+            input,
+            output,
+            seq: 1,
+        }
+    }
+
+    // Handle requests until the client disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Ok(message) = self.input.recv() {
+            if !self.handle(&message)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD,
+                "allThreadsStopped": true,
+            }),
+        )
+    }
+
+    // Returns false once the client has disconnected
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        if request["type"] != "request" {
+            return Ok(true);
+        }
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        let result = match command.as_str() {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsSteppingGranularity": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "CHIP-8" }] })),
+            "stackTrace" => self.cpu().map(|cpu| self.stack_trace(cpu)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ] })),
+            "variables" => self.cpu().map(|cpu| variables(cpu, args)),
+            "readMemory" => self.cpu().and_then(|cpu| read_memory(cpu, args)),
+            "writeMemory" => self
+                .cpu
+                .as_mut()
+                .ok_or("No program has been launched".to_string())
+                .and_then(|cpu| write_memory(cpu, args)),
+            "disassemble" => self.cpu().and_then(|cpu| disassemble(cpu, args)),
+            "pause" => {
+                self.pause_requested = self.running;
+                Ok(Value::Null)
+            }
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" => {
+                if self.cpu.is_none() {
+                    Err("No program has been launched".to_string())
+                } else {
+                    Ok(json!({ "allThreadsContinued": true }))
+                }
+            }
+            "disconnect" | "terminate" => Ok(Value::Null),
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+        let response = match result {
+            Ok(body) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "success": true,
+                "command": command,
+                "body": body,
+            }),
+            Err(message) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "success": false,
+                "command": command,
+                "message": message,
+            }),
+        };
+        let success = response["success"] == true;
+        self.send(response)?;
+        if command == "initialize" {
+            self.event("initialized", json!({}))?;
+        }
+        if !success || self.running {
+            return Ok(!matches!(command.as_str(), "disconnect" | "terminate"));
+        }
+        // Only instructions are stepped without a source map
+        let instruction = args["granularity"] == "instruction" || self.source.is_none();
+        let run = match command.as_str() {
+            "configurationDone" if self.stop_on_entry => {
+                self.stopped("entry", None)?;
+                return Ok(true);
+            }
+            "configurationDone" | "continue" => Run::Continue,
+            "stepIn" if instruction => Run::Instruction,
+            "stepIn" => Run::StepIn,
+            "next" if instruction => Run::Over,
+            "next" => Run::Next,
+            "stepOut" => Run::StepOut,
+            "disconnect" | "terminate" => return Ok(false),
+            _ => return Ok(true),
+        };
+        self.execute(run)
+    }
+
+    fn cpu(&self) -> Result<&CPU<D>, String> {
+        self.cpu
+            .as_ref()
+            .ok_or("No program has been launched".to_string())
+    }
+
+    // launch { program, quirks?, stopOnEntry? }
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("launch needs the path of a program")?;
+        let quirks = match args["quirks"].as_str() {
+            Some(name) => {
+                Quirks::from_name(name).ok_or(format!("Unknown quirks preset '{}'", name))?
+            }
+            None => Quirks::default(),
+        };
+        let display = self
+            .display
+            .take()
+            .ok_or("A program has already been launched")?;
+        let path = Path::new(program);
+        let rom = if program.ends_with(".8o") {
+            OctoRomLoader::read_with_map(path).map(|(rom, map)| {
+                let source = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+                self.source = Some((source, map));
+                rom
+            })
+        } else if program.ends_with(".hex") {
+            HexRomLoader::read(path)
+        } else {
+            Ch8RomLoader::read(path)
+        }
+        .map_err(|e| format!("{}: {}", program, e))?;
+        let mut cpu = CPU::new(display, quirks);
+        cpu.initialize();
+        cpu.load(rom.data)
+            .map_err(|e| format!("{}: {}", program, e))?;
+        self.cpu = Some(cpu);
+        self.stop_on_entry = args["stopOnEntry"] == true;
+        Ok(Value::Null)
+    }
+
+    // setBreakpoints { source: { path }, breakpoints: [{ line }] }, for the
+    // launched Octo source
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let lines: Vec<usize> = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|b| b["line"].as_u64())
+            .map(|line| line as usize)
+            .collect();
+        let path = Path::new(args["source"]["path"].as_str().unwrap_or_default());
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let map = match &self.source {
+            Some((source, map)) if *source == path => map,
+            _ => {
+                let unverified: Vec<Value> = lines
+                    .iter()
+                    .map(|line| {
+                        json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "Not the source of the launched program",
+                        })
+                    })
+                    .collect();
+                return Ok(json!({ "breakpoints": unverified }));
+            }
+        };
+        self.source_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for line in lines {
+            breakpoints.push(match map.address(line) {
+                Some((addr, line)) => {
+                    self.source_breakpoints.insert(addr);
+                    json!({
+                        "verified": true,
+                        "line": line,
+                        "instructionReference": format!("0x{:03X}", addr),
+                    })
+                }
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code on or after this line",
+                }),
+            });
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // setInstructionBreakpoints { breakpoints: [{ instructionReference, offset? }] }
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = address(&breakpoint["instructionReference"])
+                .and_then(|addr| addr.checked_add(breakpoint["offset"].as_i64().unwrap_or(0)))
+                .and_then(|addr| u16::try_from(addr).ok());
+            breakpoints.push(match addr {
+                Some(addr) => {
+                    self.instruction_breakpoints.insert(addr);
+                    json!({ "verified": true, "instructionReference": format!("0x{:03X}", addr) })
+                }
+                None => json!({ "verified": false, "message": "Invalid address" }),
+            });
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // The current instruction, then the call site of each return address
+    fn stack_trace(&self, cpu: &CPU<D>) -> Value {
+        let mut pcs = vec![cpu.pc];
+        pcs.extend(
+            cpu.stack[..cpu.sp as usize]
+                .iter()
+                .rev()
+                .map(|addr| addr.wrapping_sub(2)),
+        );
+        let frames: Vec<Value> = pcs
+            .iter()
+            .enumerate()
+            .map(|(n, &pc)| {
+                let mut frame = json!({
+                    "id": n,
+                    "name": format!("0x{:03X}  {}", pc, instruction_at(cpu, pc)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:03X}", pc),
+                });
+                let line = self.source.as_ref().and_then(|(path, map)| {
+                    let line = map.line(pc)?;
+                    Some((path, line))
+                });
+                if let Some((path, line)) = line {
+                    frame["source"] = json!({
+                        "name": path.file_name().map(|n| n.to_string_lossy()),
+                        "path": path,
+                    });
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": pcs.len() })
+    }
+
+    // Run the program, answering requests as they come in, until it stops
+    fn execute(&mut self, run: Run) -> io::Result<bool> {
+        let cpu = self.cpu.as_mut().unwrap();
+        if run == Run::Instruction {
+            cpu.poll_keys();
+            let result = cpu.cycle(0, 1);
+            cpu.refresh_display();
+            return match result {
+                Ok(StepOutcome::Exit) => self.exited(),
+                Ok(_) => self.stopped("step", None).map(|_| true),
+                Err(e) => self.stopped("exception", Some(e.to_string())).map(|_| true),
+            };
+        }
+        let (start_pc, start_sp) = (cpu.pc, cpu.sp);
+        let start_line = self.source.as_ref().and_then(|(_, map)| map.line(start_pc));
+        let returned = move |cpu: &CPU<D>| cpu.sp < start_sp;
+        // At the start of a new line, or back at the start of this one
+        let stepped = move |cpu: &CPU<D>, line: Option<usize>| {
+            line.is_some() && (line != start_line || cpu.pc == start_pc)
+        };
+
+        self.running = true;
+        let mut first = true;
+        let result = loop {
+            let mut pending = None;
+            let mut reason = "step";
+            let source = &self.source;
+            let breakpoints = (&self.source_breakpoints, &self.instruction_breakpoints);
+            let input = &self.input;
+            let cpu = self.cpu.as_mut().unwrap();
+            let result = cpu.run_until(|cpu| {
+                // The first instruction is run even if it has a breakpoint
+                if std::mem::take(&mut first) {
+                    return false;
+                }
+                if breakpoints.0.contains(&cpu.pc) || breakpoints.1.contains(&cpu.pc) {
+                    reason = "breakpoint";
+                    return true;
+                }
+                let line = source.as_ref().and_then(|(_, map)| map.line(cpu.pc));
+                let stop = match run {
+                    Run::Continue => false,
+                    Run::StepIn => stepped(cpu, line),
+                    Run::Over | Run::Next if cpu.sp > start_sp => false,
+                    Run::Over => true,
+                    Run::Next => stepped(cpu, line) || returned(cpu),
+                    Run::StepOut => returned(cpu),
+                    Run::Instruction => true,
+                };
+                if stop {
+                    return true;
+                }
+                if let Ok(message) = input.try_recv() {
+                    pending = Some(message);
+                    return true;
+                }
+                false
+            });
+            match (result, pending) {
+                (Ok(StopReason::Paused), Some(message)) => {
+                    if !self.handle(&message)? {
+                        self.running = false;
+                        return Ok(false);
+                    }
+                    if std::mem::take(&mut self.pause_requested) {
+                        break Ok(("pause", None));
+                    }
+                    // Carry on from the instruction that was about to run
+                    first = true;
+                }
+                (Ok(StopReason::Paused), None) => break Ok((reason, None)),
+                (Ok(_), _) => break Err(()),
+                (Err(e), _) => break Ok(("exception", Some(e.to_string()))),
+            }
+        };
+        self.running = false;
+        match result {
+            Ok((reason, description)) => self.stopped(reason, description).map(|_| true),
+            Err(()) => self.exited(),
+        }
+    }
+
+    fn exited(&mut self) -> io::Result<bool> {
+        self.event("exited", json!({ "exitCode": 0 }))?;
+        self.event("terminated", json!({}))?;
+        Ok(true)
+    }
+}
+
+fn word<D: Display>(cpu: &CPU<D>, addr: u16) -> Option<u16> {
+    let addr = addr as usize;
+    let bytes = cpu.memory.get(addr..addr + 2)?;
+    Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+fn instruction_at<D: Display>(cpu: &CPU<D>, addr: u16) -> String {
+    let Some(opcode) = word(cpu, addr) else {
+        return "----".to_string();
+    };
+    let next = addr
+        .checked_add(2)
+        .and_then(|next| word(cpu, next))
+        .unwrap_or(0);
+    match disasm::decode(opcode, next) {
+        Some(instruction) => instruction.format(Syntax::Octo, &|_| None),
+        None => format!("0x{:04X}", opcode),
+    }
+}
+
+// A memory or instruction reference, in hex with a 0x prefix, or decimal
+fn address(reference: &Value) -> Option<i64> {
+    let reference = reference.as_str()?;
+    match reference.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+// memoryReference plus offset, and `words` more 2 byte instructions
+fn start_address(args: &Value, words: i64) -> Result<i64, String> {
+    address(&args["memoryReference"])
+        .ok_or("Invalid memory reference")?
+        .checked_add(args["offset"].as_i64().unwrap_or(0))
+        .and_then(|addr| addr.checked_add(words.checked_mul(2)?))
+        .ok_or("Memory out of range".to_string())
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn variables<D: Display>(cpu: &CPU<D>, args: &Value) -> Value {
+    let variables: Vec<Value> = match args["variablesReference"].as_i64() {
+        Some(REGISTERS) => {
+            let mut registers: Vec<Value> = cpu
+                .v
+                .iter()
+                .enumerate()
+                .map(|(x, v)| variable(&format!("V{:X}", x), format!("0x{:02X}", v)))
+                .collect();
+            let mut i = variable("I", format!("0x{:03X}", cpu.i));
+            i["memoryReference"] = json!(format!("0x{:03X}", cpu.i));
+            registers.push(i);
+            registers.push(variable("PC", format!("0x{:03X}", cpu.pc)));
+            registers.push(variable("SP", cpu.sp.to_string()));
+            registers
+        }
+        Some(TIMERS) => vec![
+            variable("delay", cpu.delay_timer.to_string()),
+            variable("sound", cpu.sound_timer.to_string()),
+        ],
+        Some(STACK) => cpu.stack[..cpu.sp as usize]
+            .iter()
+            .enumerate()
+            .map(|(n, addr)| variable(&format!("[{}]", n), format!("0x{:03X}", addr)))
+            .collect(),
+        _ => Vec::new(),
+    };
+    json!({ "variables": variables })
+}
+
+// readMemory { memoryReference, offset?, count }
+fn read_memory<D: Display>(cpu: &CPU<D>, args: &Value) -> Result<Value, String> {
+    let start = start_address(args, 0)?;
+    let count = args["count"].as_u64().unwrap_or(0);
+    let size = cpu.memory.len();
+    let first = start.clamp(0, size as i64) as usize;
+    let end = start.saturating_add(i64::try_from(count).unwrap_or(i64::MAX));
+    let last = end.clamp(0, size as i64) as usize;
+    Ok(json!({
+        "address": format!("0x{:X}", start),
+        "data": base64_encode(&cpu.memory[first..last.max(first)]),
+        "unreadableBytes": count - (last.max(first) - first) as u64,
+    }))
+}
+
+// writeMemory { memoryReference, offset?, data }
+fn write_memory<D: Display>(cpu: &mut CPU<D>, args: &Value) -> Result<Value, String> {
+    let start = start_address(args, 0)?;
+    let data = args["data"]
+        .as_str()
+        .and_then(base64_decode)
+        .ok_or("Invalid base64 data")?;
+    let memory = usize::try_from(start)
+        .ok()
+        .and_then(|start| cpu.memory.get_mut(start..start.checked_add(data.len())?))
+        .ok_or("Memory out of range")?;
+    memory.copy_from_slice(&data);
+    Ok(json!({ "bytesWritten": data.len() }))
+}
+
+// disassemble { memoryReference, offset?, instructionOffset?, instructionCount }.
+// Instructions are taken as 2 bytes each, apart from F000 NNNN. No more
+// are given than there are words in memory
+fn disassemble<D: Display>(cpu: &CPU<D>, args: &Value) -> Result<Value, String> {
+    let start = start_address(args, args["instructionOffset"].as_i64().unwrap_or(0))?;
+    let count = args["instructionCount"]
+        .as_u64()
+        .unwrap_or(0)
+        .min(cpu.memory.len() as u64 / 2);
+    let mut addr = start;
+    let mut instructions = Vec::new();
+    for _ in 0..count {
+        let valid = (0..cpu.memory.len() as i64 - 1).contains(&addr);
+        let opcode = valid.then(|| word(cpu, addr as u16)).flatten();
+        instructions.push(match opcode {
+            Some(opcode) => {
+                let next = (addr as u16).checked_add(2).and_then(|next| word(cpu, next));
+                let instruction = disasm::decode(opcode, next.unwrap_or(0));
+                let size = instruction.map_or(2, |i| i.size()) as i64;
+                // F000 NNNN in the last word runs off the end of memory
+                let end = ((addr + size) as usize).min(cpu.memory.len());
+                let bytes = &cpu.memory[addr as usize..end];
+                let entry = json!({
+                    "address": format!("0x{:03X}", addr),
+                    "instructionBytes": bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" "),
+                    "instruction": instruction_at(cpu, addr as u16),
+                });
+                addr += size;
+                entry
+            }
+            None => {
+                let entry = json!({
+                    "address": format!("0x{:03X}", addr.max(0)),
+                    "instruction": "----",
+                    "presentationHint": "invalid",
+                });
+                addr = addr.saturating_add(2);
+                entry
+            }
+        });
+    }
+    Ok(json!({ "instructions": instructions }))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (n, &b)| bits | (b as u32) << (16 - 8 * n));
+        for n in 0..4 {
+            if n <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * n) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+
+    fn server() -> DapServer<NullDisplay, Vec<u8>> {
+        let (_, input) = mpsc::channel();
+        DapServer::new(NullDisplay::new(), input, Vec::new())
+    }
+
+    // Handle a request, returning its response and any events sent
+    fn request(
+        server: &mut DapServer<NullDisplay, Vec<u8>>,
+        command: &str,
+        arguments: Value,
+    ) -> (Value, Vec<Value>) {
+        let request = json!({
+            "seq": 1,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        assert!(server.handle(&request).unwrap());
+        let output = std::mem::take(&mut server.output);
+        let mut output = &output[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output) {
+            messages.push(message);
+        }
+        let response = messages.remove(0);
+        assert_eq!(response["command"], command);
+        assert_eq!(response["success"], true, "{}", response);
+        (response["body"].clone(), messages)
+    }
+
+    fn stopped(events: &[Value]) -> &Value {
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0]["event"], "stopped");
+        &events[0]["body"]["reason"]
+    }
+
+    fn launch(server: &mut DapServer<NullDisplay, Vec<u8>>, name: &str, program: &[u8]) {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, program).unwrap();
+        let (_, events) = request(server, "initialize", json!({}));
+        assert_eq!(events[0]["event"], "initialized");
+        request(
+            server,
+            "launch",
+            json!({ "program": path, "stopOnEntry": true }),
+        );
+    }
+
+    #[test]
+    fn test_source_stepping() {
+        let mut server = server();
+        let source =
+            ": main\n  v0 := 1\n  sub\n  v1 := 2\n  loop again\n: sub\n  v0 += 1\n  return\n";
+        launch(&mut server, "chip8_emu_test_dap.8o", source.as_bytes());
+        let path = std::env::temp_dir().join("chip8_emu_test_dap.8o");
+        let (body, _) = request(
+            &mut server,
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": [{ "line": 6 }, { "line": 9 }] }),
+        );
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["line"], 7);
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+
+        let (_, events) = request(&mut server, "configurationDone", json!({}));
+        assert_eq!(stopped(&events), "entry");
+        let (_, events) = request(&mut server, "continue", json!({ "threadId": 1 }));
+        assert_eq!(stopped(&events), "breakpoint");
+        let (body, _) = request(&mut server, "stackTrace", json!({ "threadId": 1 }));
+        let frames = body["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["line"], 7);
+        assert_eq!(frames[0]["instructionPointerReference"], "0x208");
+        assert_eq!(frames[1]["line"], 3);
+
+        let (_, events) = request(&mut server, "stepOut", json!({ "threadId": 1 }));
+        assert_eq!(stopped(&events), "step");
+        assert_eq!(server.cpu.as_ref().unwrap().pc, 0x204);
+        let (_, events) = request(&mut server, "next", json!({ "threadId": 1 }));
+        assert_eq!(stopped(&events), "step");
+        assert_eq!(server.cpu.as_ref().unwrap().pc, 0x206);
+
+        let (body, _) = request(
+            &mut server,
+            "variables",
+            json!({ "variablesReference": REGISTERS }),
+        );
+        assert_eq!(
+            body["variables"][0],
+            json!({ "name": "V0", "value": "0x02", "variablesReference": 0 })
+        );
+        assert_eq!(body["variables"][1]["value"], "0x02");
+        assert_eq!(body["variables"][17]["value"], "0x206");
+    }
+
+    #[test]
+    fn test_instructions_and_memory() {
+        let mut server = server();
+        // call 0x206, v1 := 2, loop; 0x206: v0 := 1, return
+        let program = [0x22, 0x06, 0x61, 0x02, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE];
+        launch(&mut server, "chip8_emu_test_dap.ch8", &program);
+        let (_, events) = request(&mut server, "configurationDone", json!({}));
+        assert_eq!(stopped(&events), "entry");
+        // Over the call
+        let (_, events) = request(&mut server, "next", json!({ "threadId": 1 }));
+        assert_eq!(stopped(&events), "step");
+        let cpu = server.cpu.as_ref().unwrap();
+        assert_eq!((cpu.pc, cpu.v[0]), (0x202, 1));
+
+        // Turn v1 := 2 into another call
+        let (body, _) = request(
+            &mut server,
+            "writeMemory",
+            json!({ "memoryReference": "0x200", "offset": 2, "data": "IgY=" }),
+        );
+        assert_eq!(body["bytesWritten"], 2);
+        let (body, _) = request(
+            &mut server,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0x206" }] }),
+        );
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        let (_, events) = request(&mut server, "continue", json!({ "threadId": 1 }));
+        assert_eq!(stopped(&events), "breakpoint");
+        assert_eq!(server.cpu.as_ref().unwrap().pc, 0x206);
+
+        let (body, _) = request(
+            &mut server,
+            "readMemory",
+            json!({ "memoryReference": "0x200", "count": 4 }),
+        );
+        assert_eq!(body["data"], "IgYiBg==");
+        let (body, _) = request(
+            &mut server,
+            "disassemble",
+            json!({ "memoryReference": "0x206", "instructionCount": 2 }),
+        );
+        assert_eq!(body["instructions"][0]["instructionBytes"], "60 01");
+        assert_eq!(body["instructions"][1]["address"], "0x208");
+    }
+
+    #[test]
+    fn test_disassemble_end_of_memory() {
+        let path = std::env::temp_dir().join("chip8_emu_test_dap_end.ch8");
+        fs::write(&path, [0x00, 0xE0]).unwrap();
+        // 4K, and 64K where the next word's address overflows a u16
+        for quirks in ["vip", "xochip"] {
+            let mut server = server();
+            request(
+                &mut server,
+                "launch",
+                json!({ "program": path, "quirks": quirks }),
+            );
+            let cpu = server.cpu.as_mut().unwrap();
+            let last = cpu.memory.len() - 2;
+            cpu.memory[last..].copy_from_slice(&[0xF0, 0x00]);
+            let (body, _) = request(
+                &mut server,
+                "disassemble",
+                json!({ "memoryReference": format!("0x{:X}", last), "instructionCount": 2 }),
+            );
+            assert_eq!(body["instructions"][0]["instructionBytes"], "F0 00");
+            assert_eq!(body["instructions"][1]["presentationHint"], "invalid");
+        }
+    }
+
+    #[test]
+    fn test_memory_ranges() {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        let count = disassemble(
+            &cpu,
+            &json!({ "memoryReference": "0x0", "instructionCount": u64::MAX }),
+        )
+        .unwrap()["instructions"]
+            .as_array()
+            .unwrap()
+            .len();
+        assert_eq!(count, 2048);
+        let overflow =
+            json!({ "memoryReference": "0x7FFFFFFFFFFFFFFF", "offset": 1, "data": "AA==" });
+        assert_eq!(
+            write_memory(&mut cpu, &overflow),
+            Err("Memory out of range".to_string())
+        );
+        assert!(read_memory(&cpu, &overflow).is_err());
+        let overflow = json!({ "memoryReference": "0x0", "instructionOffset": i64::MAX, "instructionCount": 1 });
+        assert!(disassemble(&cpu, &overflow).is_err());
+        let near_end = json!({ "memoryReference": "0x7FFFFFFFFFFFFFFE", "instructionCount": 4 });
+        assert_eq!(
+            disassemble(&cpu, &near_end).unwrap()["instructions"][3]["instruction"],
+            "----"
+        );
+        let body = read_memory(
+            &cpu,
+            &json!({ "memoryReference": "0xFFE", "count": u64::MAX }),
+        )
+        .unwrap();
+        assert_eq!(body["unreadableBytes"], u64::MAX - 2);
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in [&b""[..], b"a", b"ab", b"abc", b"\xFF\x00\x80\x7F"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_decode("not base64!"), None);
+    }
+}
@@ -0,0 +1,607 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::cpu::{StepOutcome, StopReason, CPU};
+use crate::disasm::{self, Syntax};
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::memview;
+
+const HELP: &str = "\
+Commands:
+  c, continue        run until a breakpoint or watchpoint, press Enter to pause
+  s, step [n]        execute n instructions (default 1)
+  n, next            step, running over 2NNN subroutine calls
+  f, finish          run until the current subroutine returns
+  b, break [addr]    set a breakpoint at addr, or list breakpoints
+  d, delete <addr>   remove the breakpoint at addr
+  w, watch <vX|addr> break when register vX or memory[addr] changes
+  u, unwatch <vX|addr>
+  r, regs            print registers, I, SP, stack and timers
+  x, examine <loc> [len]
+                     hex dump len bytes (default 64) from loc, marking PC >,
+                     I * and return addresses ^
+  sprite <loc> [len] draw len bytes (default 16) as 8 pixel wide sprite rows
+  poke <addr> <bytes>...
+                     write hex bytes, e.g. poke 300 F0 90 or poke 300 F090
+  fill <addr> <len> <byte>
+                     set len bytes from addr to byte
+  find <bytes>...    search memory for hex bytes, ?? matching any byte
+  h, help            show this message
+  q, quit            exit the emulator
+An empty line repeats the last command. Addresses are hex, e.g. 0x2A0 or 2a0.
+A loc is an address, i or pc. Lengths are decimal, or hex with 0x.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Register(usize),
+    Memory(u16),
+}
+
+impl Watch {
+    fn value<D: Display>(&self, cpu: &CPU<D>) -> u8 {
+        match *self {
+            Watch::Register(x) => cpu.v[x],
+            Watch::Memory(addr) => cpu.memory.get(addr as usize).copied().unwrap_or(0),
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Watch::Register(x) => format!("v{:X}", x),
+            Watch::Memory(addr) => format!("memory[0x{:03X}]", addr),
+        }
+    }
+}
+
+// Where a memory view starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Address(u16),
+    I,
+    Pc,
+}
+
+impl Location {
+    fn address<D: Display>(&self, cpu: &CPU<D>) -> usize {
+        match *self {
+            Location::Address(addr) => addr as usize,
+            Location::I => cpu.i as usize,
+            Location::Pc => cpu.pc as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Continue,
+    Step(usize),
+    Next,
+    Finish,
+    Break(Option<u16>),
+    Delete(u16),
+    Watch(Watch),
+    Unwatch(Watch),
+    Registers,
+    Examine(Location, usize),
+    Sprite(Location, usize),
+    Poke(u16, Vec<u8>),
+    Fill(u16, usize, u8),
+    Find(Vec<Option<u8>>),
+    Help,
+    Quit,
+}
+
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address '{}'", s))
+}
+
+// The `len` bytes of memory from `addr`, or why they aren't all there
+fn memory_range<D: Display>(cpu: &CPU<D>, addr: u16, len: usize) -> Result<Range<usize>, String> {
+    let start = addr as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= cpu.memory.len() => Ok(start..end),
+        _ => Err(format!(
+            "0x{:03X}-0x{:03X} is past the end of memory",
+            start,
+            start.saturating_add(len).saturating_sub(1)
+        )),
+    }
+}
+
+fn parse_watch(s: &str) -> Result<Watch, String> {
+    match s.strip_prefix(['v', 'V']) {
+        Some(reg) if reg.len() == 1 => usize::from_str_radix(reg, 16)
+            .map(Watch::Register)
+            .map_err(|_| format!("Invalid register '{}'", s)),
+        _ => parse_address(s).map(Watch::Memory),
+    }
+}
+
+fn parse_location(s: &str) -> Result<Location, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "i" => Ok(Location::I),
+        "pc" => Ok(Location::Pc),
+        _ => parse_address(s).map(Location::Address),
+    }
+}
+
+fn parse_length(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid length '{}'", s))
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let arg = words.next();
+    let rest: Vec<&str> = words.collect();
+    let required = || arg.ok_or(format!("'{}' needs an argument", name));
+    let length = |default| rest.first().map_or(Ok(default), |s| parse_length(s));
+    match name {
+        "c" | "continue" => Ok(Command::Continue),
+        "s" | "step" => match arg {
+            Some(n) => n
+                .parse()
+                .map(Command::Step)
+                .map_err(|_| format!("Invalid count '{}'", n)),
+            None => Ok(Command::Step(1)),
+        },
+        "n" | "next" => Ok(Command::Next),
+        "f" | "finish" => Ok(Command::Finish),
+        "b" | "break" => arg.map(parse_address).transpose().map(Command::Break),
+        "d" | "delete" => parse_address(required()?).map(Command::Delete),
+        "w" | "watch" => parse_watch(required()?).map(Command::Watch),
+        "u" | "unwatch" => parse_watch(required()?).map(Command::Unwatch),
+        "r" | "regs" => Ok(Command::Registers),
+        "x" | "examine" => Ok(Command::Examine(parse_location(required()?)?, length(64)?)),
+        "sprite" => Ok(Command::Sprite(parse_location(required()?)?, length(16)?)),
+        "poke" => {
+            let addr = parse_address(required()?)?;
+            let bytes = memview::parse_bytes(&rest, false)?;
+            if bytes.is_empty() {
+                return Err("'poke' needs bytes to write".to_string());
+            }
+            Ok(Command::Poke(addr, bytes.into_iter().flatten().collect()))
+        }
+        "fill" => match rest[..] {
+            [len, byte] => {
+                let byte = u8::from_str_radix(byte.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Invalid byte '{}'", byte))?;
+                Ok(Command::Fill(
+                    parse_address(required()?)?,
+                    parse_length(len)?,
+                    byte,
+                ))
+            }
+            _ => Err("'fill' needs an address, a length and a byte".to_string()),
+        },
+        "find" => {
+            let words: Vec<&str> = arg.into_iter().chain(rest.iter().copied()).collect();
+            let pattern = memview::parse_bytes(&words, true)?;
+            if pattern.is_empty() {
+                return Err("'find' needs bytes to search for".to_string());
+            }
+            Ok(Command::Find(pattern))
+        }
+        "h" | "help" => Ok(Command::Help),
+        "q" | "quit" => Ok(Command::Quit),
+        _ => Err(format!(
+            "Unknown command '{}', type 'help' for a list",
+            name
+        )),
+    }
+}
+
+// Interactive debugger reading commands from stdin, so it works over SSH
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    // Watched locations with the last value seen
+    watches: Vec<(Watch, u8)>,
+    input: Receiver<String>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        // Read stdin on its own thread so a running program can be paused
+        // by pressing Enter
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            input,
+        }
+    }
+
+    pub fn run<D: Display>(&mut self, cpu: &mut CPU<D>) {
+        println!("CHIP-8 debugger, type 'help' for commands");
+        print_state(cpu);
+        let mut last = Command::Step(1);
+        loop {
+            print!("(chip8) ");
+            io::stdout().flush().ok();
+            let Ok(line) = self.input.recv() else {
+                return;
+            };
+            let command = if line.trim().is_empty() {
+                last.clone()
+            } else {
+                match parse_command(&line) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            };
+            last = command.clone();
+
+            let result = match command {
+                Command::Continue => {
+                    println!("Continuing, press Enter to pause");
+                    self.resume(cpu, |_| false)
+                }
+                Command::Step(n) => self.step(cpu, n),
+                Command::Next => self.next(cpu),
+                Command::Finish => {
+                    if cpu.sp == 0 {
+                        println!("Not in a subroutine");
+                        continue;
+                    }
+                    let sp = cpu.sp - 1;
+                    self.resume(cpu, |cpu| cpu.sp == sp)
+                }
+                Command::Break(Some(addr)) => {
+                    self.breakpoints.insert(addr);
+                    println!("Breakpoint at 0x{:03X}", addr);
+                    continue;
+                }
+                Command::Break(None) => {
+                    for addr in &self.breakpoints {
+                        println!("Breakpoint at 0x{:03X}", addr);
+                    }
+                    for (watch, value) in &self.watches {
+                        println!("Watching {} = 0x{:02X}", watch.name(), value);
+                    }
+                    continue;
+                }
+                Command::Delete(addr) => {
+                    if !self.breakpoints.remove(&addr) {
+                        println!("No breakpoint at 0x{:03X}", addr);
+                    }
+                    continue;
+                }
+                Command::Watch(watch) => {
+                    let value = watch.value(cpu);
+                    self.watches.retain(|(w, _)| *w != watch);
+                    self.watches.push((watch, value));
+                    println!("Watching {} = 0x{:02X}", watch.name(), value);
+                    continue;
+                }
+                Command::Unwatch(watch) => {
+                    self.watches.retain(|(w, _)| *w != watch);
+                    continue;
+                }
+                Command::Registers => {
+                    print_state(cpu);
+                    continue;
+                }
+                Command::Examine(location, len) => {
+                    print!("{}", memview::hex_dump(cpu, location.address(cpu), len));
+                    continue;
+                }
+                Command::Sprite(location, len) => {
+                    print!(
+                        "{}",
+                        memview::sprites(&cpu.memory, location.address(cpu), len)
+                    );
+                    continue;
+                }
+                Command::Poke(addr, bytes) => {
+                    self.poke(cpu, addr, &bytes);
+                    continue;
+                }
+                Command::Fill(addr, len, byte) => {
+                    self.fill(cpu, addr, len, byte);
+                    continue;
+                }
+                Command::Find(pattern) => {
+                    let matches = memview::search(&cpu.memory, &pattern);
+                    let shown: Vec<String> = matches
+                        .iter()
+                        .take(32)
+                        .map(|addr| format!("0x{:03X}", addr))
+                        .collect();
+                    match matches.len() {
+                        0 => println!("Not found"),
+                        n if n > shown.len() => {
+                            println!("{} matches: {}, ...", n, shown.join(", "))
+                        }
+                        n => println!("{} matches: {}", n, shown.join(", ")),
+                    }
+                    continue;
+                }
+                Command::Help => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                Command::Quit => return,
+            };
+
+            match result {
+                Ok(StopReason::Paused) => print_state(cpu),
+                Ok(StopReason::Exit) => {
+                    println!("Program exited");
+                    return;
+                }
+                Ok(StopReason::Closed | StopReason::MovieEnd) => return,
+                Err(e) => {
+                    println!("{}", e);
+                    print_state(cpu);
+                }
+            }
+        }
+    }
+
+    fn step<D: Display>(&mut self, cpu: &mut CPU<D>, n: usize) -> Result<StopReason, Chip8Error> {
+        cpu.poll_keys();
+        for _ in 0..n {
+            let outcome = cpu.cycle(0, 1)?;
+            if let Some(changes) = self.check_watches(cpu) {
+                println!("{}", changes);
+                break;
+            }
+            match outcome {
+                StepOutcome::Exit => return Ok(StopReason::Exit),
+                StepOutcome::Waiting => {
+                    println!("Waiting for a key or the next frame");
+                    break;
+                }
+                StepOutcome::Continue => {}
+            }
+        }
+        cpu.refresh_display();
+        Ok(StopReason::Paused)
+    }
+
+    // Step over a subroutine call by running until it returns
+    fn next<D: Display>(&mut self, cpu: &mut CPU<D>) -> Result<StopReason, Chip8Error> {
+        let pc = cpu.pc as usize;
+        let is_call = cpu.memory.get(pc).is_some_and(|&b| b & 0xF0 == 0x20);
+        if !is_call {
+            return self.step(cpu, 1);
+        }
+        let (return_pc, sp) = (cpu.pc.wrapping_add(2), cpu.sp);
+        self.resume(cpu, |cpu| cpu.pc == return_pc && cpu.sp == sp)
+    }
+
+    // Run in real time until `stop` returns true, a breakpoint or watchpoint
+    // is hit, or a line is entered on stdin
+    fn resume<D: Display, F: FnMut(&CPU<D>) -> bool>(
+        &mut self,
+        cpu: &mut CPU<D>,
+        mut stop: F,
+    ) -> Result<StopReason, Chip8Error> {
+        // Don't stop on a breakpoint at the starting instruction
+        let mut first = true;
+        // Discard stray input so it doesn't pause straight away
+        while self.input.try_recv().is_ok() {}
+        cpu.run_until(|cpu| {
+            if std::mem::take(&mut first) {
+                return false;
+            }
+            if self.input.try_recv().is_ok() {
+                println!("Paused");
+                return true;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                println!("Breakpoint at 0x{:03X}", cpu.pc);
+                return true;
+            }
+            if let Some(changes) = self.check_watches(cpu) {
+                println!("{}", changes);
+                return true;
+            }
+            stop(cpu)
+        })
+    }
+
+    // Write bytes from `addr`, if they fit in memory
+    fn poke<D: Display>(&mut self, cpu: &mut CPU<D>, addr: u16, bytes: &[u8]) {
+        match memory_range(cpu, addr, bytes.len()) {
+            Ok(range) => cpu.memory[range].copy_from_slice(bytes),
+            Err(e) => return println!("{}", e),
+        }
+        // Watchpoints are for changes the program makes
+        self.check_watches(cpu);
+    }
+
+    // Set `len` bytes from `addr` to `byte`, checking they fit before
+    // anything is written
+    fn fill<D: Display>(&mut self, cpu: &mut CPU<D>, addr: u16, len: usize, byte: u8) {
+        match memory_range(cpu, addr, len) {
+            Ok(range) => cpu.memory[range].fill(byte),
+            Err(e) => return println!("{}", e),
+        }
+        self.check_watches(cpu);
+    }
+
+    // Report and remember any watched values that changed
+    fn check_watches<D: Display>(&mut self, cpu: &CPU<D>) -> Option<String> {
+        let mut changes = Vec::new();
+        for (watch, last) in self.watches.iter_mut() {
+            let value = watch.value(cpu);
+            if value != *last {
+                changes.push(format!(
+                    "Watchpoint {}: 0x{:02X} -> 0x{:02X}",
+                    watch.name(),
+                    last,
+                    value
+                ));
+                *last = value;
+            }
+        }
+        if changes.is_empty() {
+            None
+        } else {
+            Some(changes.join("\n"))
+        }
+    }
+}
+
+pub fn print_state<D: Display>(cpu: &CPU<D>) {
+    let pc = cpu.pc as usize;
+    let word = |addr: usize| match (cpu.memory.get(addr), cpu.memory.get(addr + 1)) {
+        (Some(&hi), Some(&lo)) => Some((hi as u16) << 8 | lo as u16),
+        _ => None,
+    };
+    let next = match word(pc) {
+        Some(opcode) => match disasm::decode(opcode, word(pc + 2).unwrap_or(0)) {
+            Some(instruction) => format!(
+                "0x{:04X} ({})",
+                opcode,
+                instruction.format(Syntax::Octo, &|_| None)
+            ),
+            None => format!("0x{:04X}", opcode),
+        },
+        None => "----".to_string(),
+    };
+    println!(
+        "PC: 0x{:03X}  I: 0x{:03X}  SP: {}  DT: {}  ST: {}  Next: {}",
+        cpu.pc, cpu.i, cpu.sp, cpu.delay_timer, cpu.sound_timer, next
+    );
+    for (n, regs) in cpu.v.chunks(8).enumerate() {
+        let regs: Vec<String> = regs.iter().map(|v| format!("{:02X}", v)).collect();
+        println!("V{:X}-V{:X}: {}", n * 8, n * 8 + 7, regs.join(" "));
+    }
+    let stack: Vec<String> = cpu.stack[..cpu.sp as usize]
+        .iter()
+        .map(|addr| format!("0x{:03X}", addr))
+        .collect();
+    println!("Stack: [{}]", stack.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+
+    fn debugger() -> Debugger {
+        let (_, input) = mpsc::channel();
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            input,
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("c"), Ok(Command::Continue));
+        assert_eq!(parse_command("step"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("s 10"), Ok(Command::Step(10)));
+        assert_eq!(parse_command("b 0x2A0"), Ok(Command::Break(Some(0x2A0))));
+        assert_eq!(parse_command("break"), Ok(Command::Break(None)));
+        assert_eq!(parse_command("d 2a0"), Ok(Command::Delete(0x2A0)));
+        assert_eq!(
+            parse_command("w vF"),
+            Ok(Command::Watch(Watch::Register(0xF)))
+        );
+        assert_eq!(
+            parse_command("watch 0x300"),
+            Ok(Command::Watch(Watch::Memory(0x300)))
+        );
+        assert_eq!(
+            parse_command("x i 0x10"),
+            Ok(Command::Examine(Location::I, 16))
+        );
+        assert_eq!(
+            parse_command("sprite 2a0"),
+            Ok(Command::Sprite(Location::Address(0x2A0), 16))
+        );
+        assert_eq!(
+            parse_command("poke 300 F0 9090"),
+            Ok(Command::Poke(0x300, vec![0xF0, 0x90, 0x90]))
+        );
+        assert_eq!(
+            parse_command("fill 300 32 0"),
+            Ok(Command::Fill(0x300, 32, 0))
+        );
+        assert_eq!(
+            parse_command("find A2 ??"),
+            Ok(Command::Find(vec![Some(0xA2), None]))
+        );
+        assert!(parse_command("poke 300").is_err());
+        assert!(parse_command("fill 300 32").is_err());
+        assert!(parse_command("x").is_err());
+        assert!(parse_command("w").is_err());
+        assert!(parse_command("b xyz").is_err());
+        assert!(parse_command("jump").is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        cpu.load(vec![0x63, 0x05, 0xA3, 0x00, 0xF3, 0x55]).unwrap();
+        let mut debugger = debugger();
+        debugger.watches.push((Watch::Register(3), 0));
+        debugger.watches.push((Watch::Memory(0x303), 0));
+
+        cpu.cycle(0, 0).unwrap();
+        assert_eq!(
+            debugger.check_watches(&cpu),
+            Some("Watchpoint v3: 0x00 -> 0x05".to_string())
+        );
+        cpu.cycle(0, 0).unwrap();
+        assert_eq!(debugger.check_watches(&cpu), None);
+        cpu.cycle(0, 0).unwrap();
+        assert_eq!(
+            debugger.check_watches(&cpu),
+            Some("Watchpoint memory[0x303]: 0x00 -> 0x05".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        let mut debugger = debugger();
+        debugger.fill(&mut cpu, 0x300, 4, 0xAA);
+        assert_eq!(cpu.memory[0x2FF..0x305], [0, 0xAA, 0xAA, 0xAA, 0xAA, 0]);
+        // Too long, or far too long for an allocation, and nothing changes
+        debugger.fill(&mut cpu, 0xFFE, 3, 0xBB);
+        debugger.fill(&mut cpu, 0x300, usize::MAX, 0xBB);
+        assert!(!cpu.memory.contains(&0xBB));
+        assert_eq!(
+            memory_range(&cpu, 0x300, usize::MAX),
+            Err(format!(
+                "0x300-0x{:03X} is past the end of memory",
+                usize::MAX - 1
+            ))
+        );
+        assert_eq!(memory_range(&cpu, 0xFFF, 1), Ok(0xFFF..0x1000));
+    }
+}
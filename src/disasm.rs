@@ -0,0 +1,641 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+const PROGRAM_START: u16 = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    // Octo assembly, e.g. `v0 += 0x01`
+    Octo,
+    // Cowgod's technical reference, e.g. `ADD V0, #01`
+    Cowgod,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "octo" => Ok(Syntax::Octo),
+            "cowgod" => Ok(Syntax::Cowgod),
+            _ => Err(format!("Unknown syntax '{}', expected octo or cowgod", s)),
+        }
+    }
+}
+
+// A decoded instruction. x and y are register numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Clear,
+    Return,
+    Exit,
+    Lores,
+    Hires,
+    ScrollDown(u8),
+    ScrollUp(u8),
+    ScrollRight,
+    ScrollLeft,
+    Jump(u16),
+    Call(u16),
+    JumpOffset(u16),
+    SkipEq(u8, u8),
+    SkipNe(u8, u8),
+    SkipEqReg(u8, u8),
+    SkipNeReg(u8, u8),
+    SaveRange(u8, u8),
+    LoadRange(u8, u8),
+    Load(u8, u8),
+    Add(u8, u8),
+    Move(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddReg(u8, u8),
+    Sub(u8, u8),
+    ShiftRight(u8, u8),
+    SubReverse(u8, u8),
+    ShiftLeft(u8, u8),
+    LoadI(u16),
+    LongLoadI(u16),
+    Random(u8, u8),
+    Draw(u8, u8, u8),
+    SkipKey(u8),
+    SkipNotKey(u8),
+    Plane(u8),
+    Audio,
+    GetDelay(u8),
+    WaitKey(u8),
+    SetDelay(u8),
+    SetSound(u8),
+    AddI(u8),
+    Font(u8),
+    BigFont(u8),
+    Bcd(u8),
+    Pitch(u8),
+    Store(u8),
+    Restore(u8),
+    SaveFlags(u8),
+    LoadFlags(u8),
+}
+
+// Decode an opcode. `next` is the following word, only used by F000 NNNN
+pub fn decode(opcode: u16, next: u16) -> Option<Instruction> {
+    use Instruction::*;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+    let instruction = match opcode & 0xF000 {
+        0x0000 => match nnn {
+            0x0C0..=0x0CF => ScrollDown(n),
+            0x0D0..=0x0DF => ScrollUp(n),
+            0x0E0 => Clear,
+            0x0EE => Return,
+            0x0FB => ScrollRight,
+            0x0FC => ScrollLeft,
+            0x0FD => Exit,
+            0x0FE => Lores,
+            0x0FF => Hires,
+            _ => return None,
+        },
+        0x1000 => Jump(nnn),
+        0x2000 => Call(nnn),
+        0x3000 => SkipEq(x, nn),
+        0x4000 => SkipNe(x, nn),
+        0x5000 => match n {
+            0x0 => SkipEqReg(x, y),
+            0x2 => SaveRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => return None,
+        },
+        0x6000 => Load(x, nn),
+        0x7000 => Add(x, nn),
+        0x8000 => match n {
+            0x0 => Move(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => AddReg(x, y),
+            0x5 => Sub(x, y),
+            0x6 => ShiftRight(x, y),
+            0x7 => SubReverse(x, y),
+            0xE => ShiftLeft(x, y),
+            _ => return None,
+        },
+        0x9000 if n == 0 => SkipNeReg(x, y),
+        0xA000 => LoadI(nnn),
+        0xB000 => JumpOffset(nnn),
+        0xC000 => Random(x, nn),
+        0xD000 => Draw(x, y, n),
+        0xE000 => match nn {
+            0x9E => SkipKey(x),
+            0xA1 => SkipNotKey(x),
+            _ => return None,
+        },
+        0xF000 => match nn {
+            0x00 if x == 0 => LongLoadI(next),
+            0x01 => Plane(x),
+            0x02 if x == 0 => Audio,
+            0x07 => GetDelay(x),
+            0x0A => WaitKey(x),
+            0x15 => SetDelay(x),
+            0x18 => SetSound(x),
+            0x1E => AddI(x),
+            0x29 => Font(x),
+            0x30 => BigFont(x),
+            0x33 => Bcd(x),
+            0x3A => Pitch(x),
+            0x55 => Store(x),
+            0x65 => Restore(x),
+            0x75 => SaveFlags(x),
+            0x85 => LoadFlags(x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+impl Instruction {
+    // Size in bytes
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LongLoadI(_) => 4,
+            _ => 2,
+        }
+    }
+
+    pub fn is_skip(&self) -> bool {
+        use Instruction::*;
+        matches!(
+            self,
+            SkipEq(..) | SkipNe(..) | SkipEqReg(..) | SkipNeReg(..) | SkipKey(_) | SkipNotKey(_)
+        )
+    }
+
+    // Format the instruction. `label` names jump, call and I targets
+    pub fn format(&self, syntax: Syntax, label: &dyn Fn(u16) -> Option<String>) -> String {
+        match syntax {
+            Syntax::Octo => self.format_octo(label),
+            Syntax::Cowgod => self.format_cowgod(label),
+        }
+    }
+
+    fn format_octo(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        use Instruction::*;
+        let addr = |a: u16| label(a).unwrap_or(format!("0x{:03X}", a));
+        match *self {
+            Clear => "clear".to_string(),
+            Return => "return".to_string(),
+            Exit => "exit".to_string(),
+            Lores => "lores".to_string(),
+            Hires => "hires".to_string(),
+            ScrollDown(n) => format!("scroll-down {}", n),
+            ScrollUp(n) => format!("scroll-up {}", n),
+            ScrollRight => "scroll-right".to_string(),
+            ScrollLeft => "scroll-left".to_string(),
+            Jump(a) => format!("jump {}", addr(a)),
+            Call(a) => format!(":call {}", addr(a)),
+            JumpOffset(a) => format!("jump0 {}", addr(a)),
+            // Octo names skips by the condition for running the next instruction
+            SkipEq(x, nn) => format!("if v{:X} != 0x{:02X} then", x, nn),
+            SkipNe(x, nn) => format!("if v{:X} == 0x{:02X} then", x, nn),
+            SkipEqReg(x, y) => format!("if v{:X} != v{:X} then", x, y),
+            SkipNeReg(x, y) => format!("if v{:X} == v{:X} then", x, y),
+            SkipKey(x) => format!("if v{:X} -key then", x),
+            SkipNotKey(x) => format!("if v{:X} key then", x),
+            SaveRange(x, y) => format!("save v{:X} - v{:X}", x, y),
+            LoadRange(x, y) => format!("load v{:X} - v{:X}", x, y),
+            Load(x, nn) => format!("v{:X} := 0x{:02X}", x, nn),
+            Add(x, nn) => format!("v{:X} += 0x{:02X}", x, nn),
+            Move(x, y) => format!("v{:X} := v{:X}", x, y),
+            Or(x, y) => format!("v{:X} |= v{:X}", x, y),
+            And(x, y) => format!("v{:X} &= v{:X}", x, y),
+            Xor(x, y) => format!("v{:X} ^= v{:X}", x, y),
+            AddReg(x, y) => format!("v{:X} += v{:X}", x, y),
+            Sub(x, y) => format!("v{:X} -= v{:X}", x, y),
+            ShiftRight(x, y) => format!("v{:X} >>= v{:X}", x, y),
+            SubReverse(x, y) => format!("v{:X} =- v{:X}", x, y),
+            ShiftLeft(x, y) => format!("v{:X} <<= v{:X}", x, y),
+            LoadI(a) => format!("i := {}", addr(a)),
+            LongLoadI(a) => format!("i := long {}", label(a).unwrap_or(format!("0x{:04X}", a))),
+            Random(x, nn) => format!("v{:X} := random 0x{:02X}", x, nn),
+            Draw(x, y, n) => format!("sprite v{:X} v{:X} {}", x, y, n),
+            Plane(n) => format!("plane {}", n),
+            Audio => "audio".to_string(),
+            GetDelay(x) => format!("v{:X} := delay", x),
+            WaitKey(x) => format!("v{:X} := key", x),
+            SetDelay(x) => format!("delay := v{:X}", x),
+            SetSound(x) => format!("buzzer := v{:X}", x),
+            AddI(x) => format!("i += v{:X}", x),
+            Font(x) => format!("i := hex v{:X}", x),
+            BigFont(x) => format!("i := bighex v{:X}", x),
+            Bcd(x) => format!("bcd v{:X}", x),
+            Pitch(x) => format!("pitch := v{:X}", x),
+            Store(x) => format!("save v{:X}", x),
+            Restore(x) => format!("load v{:X}", x),
+            SaveFlags(x) => format!("saveflags v{:X}", x),
+            LoadFlags(x) => format!("loadflags v{:X}", x),
+        }
+    }
+
+    fn format_cowgod(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        use Instruction::*;
+        let addr = |a: u16| label(a).unwrap_or(format!("#{:03X}", a));
+        match *self {
+            Clear => "CLS".to_string(),
+            Return => "RET".to_string(),
+            Exit => "EXIT".to_string(),
+            Lores => "LOW".to_string(),
+            Hires => "HIGH".to_string(),
+            ScrollDown(n) => format!("SCD #{:X}", n),
+            ScrollUp(n) => format!("SCU #{:X}", n),
+            ScrollRight => "SCR".to_string(),
+            ScrollLeft => "SCL".to_string(),
+            Jump(a) => format!("JP {}", addr(a)),
+            Call(a) => format!("CALL {}", addr(a)),
+            JumpOffset(a) => format!("JP V0, {}", addr(a)),
+            SkipEq(x, nn) => format!("SE V{:X}, #{:02X}", x, nn),
+            SkipNe(x, nn) => format!("SNE V{:X}, #{:02X}", x, nn),
+            SkipEqReg(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            SkipNeReg(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            SkipKey(x) => format!("SKP V{:X}", x),
+            SkipNotKey(x) => format!("SKNP V{:X}", x),
+            SaveRange(x, y) => format!("SAVE V{:X}-V{:X}", x, y),
+            LoadRange(x, y) => format!("LOAD V{:X}-V{:X}", x, y),
+            Load(x, nn) => format!("LD V{:X}, #{:02X}", x, nn),
+            Add(x, nn) => format!("ADD V{:X}, #{:02X}", x, nn),
+            Move(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            SubReverse(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            LoadI(a) => format!("LD I, {}", addr(a)),
+            LongLoadI(a) => format!("LD I, {}", label(a).unwrap_or(format!("#{:04X}", a))),
+            Random(x, nn) => format!("RND V{:X}, #{:02X}", x, nn),
+            Draw(x, y, n) => format!("DRW V{:X}, V{:X}, #{:X}", x, y, n),
+            Plane(n) => format!("PLANE #{:X}", n),
+            Audio => "AUDIO".to_string(),
+            GetDelay(x) => format!("LD V{:X}, DT", x),
+            WaitKey(x) => format!("LD V{:X}, K", x),
+            SetDelay(x) => format!("LD DT, V{:X}", x),
+            SetSound(x) => format!("LD ST, V{:X}", x),
+            AddI(x) => format!("ADD I, V{:X}", x),
+            Font(x) => format!("LD F, V{:X}", x),
+            BigFont(x) => format!("LD HF, V{:X}", x),
+            Bcd(x) => format!("LD B, V{:X}", x),
+            Pitch(x) => format!("PITCH V{:X}", x),
+            Store(x) => format!("LD [I], V{:X}", x),
+            Restore(x) => format!("LD V{:X}, [I]", x),
+            SaveFlags(x) => format!("LD R, V{:X}", x),
+            LoadFlags(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+// Kinds of label, in increasing order of priority when an address has several
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Sprite,
+    Jump,
+    Subroutine,
+    Main,
+}
+
+// One line of a disassembly listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub label: Option<String>,
+    pub text: String,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Analysis {
+    code: Vec<bool>,
+    // Start address and length of sprites drawn from ANNN targets
+    sprites: BTreeMap<u16, u16>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+impl Analysis {
+    fn add_label(&mut self, addr: u16, kind: LabelKind) {
+        let entry = self.labels.entry(addr).or_insert(kind);
+        *entry = (*entry).max(kind);
+    }
+
+    fn label(&self, addr: u16) -> Option<String> {
+        let name = match self.labels.get(&addr)? {
+            LabelKind::Main => return Some("main".to_string()),
+            LabelKind::Subroutine => "sub",
+            LabelKind::Jump => "label",
+            LabelKind::Sprite => "sprite",
+            LabelKind::Data => "data",
+        };
+        Some(format!("{}_{:03X}", name, addr))
+    }
+}
+
+fn word(rom: &[u8], addr: u16) -> Option<u16> {
+    let offset = addr.checked_sub(PROGRAM_START)? as usize;
+    let hi = *rom.get(offset)?;
+    let lo = *rom.get(offset + 1)?;
+    Some((hi as u16) << 8 | lo as u16)
+}
+
+// Follow every path through the program from the entry point, marking the
+// bytes that are executed as code and recording labels and sprite data
+fn analyze(rom: &[u8]) -> Analysis {
+    let end = PROGRAM_START as usize + rom.len();
+    let in_rom = |addr: u16| (PROGRAM_START as usize..end).contains(&(addr as usize));
+    let mut analysis = Analysis {
+        code: vec![false; rom.len()],
+        ..Analysis::default()
+    };
+    analysis.add_label(PROGRAM_START, LabelKind::Main);
+
+    let mut pending = vec![PROGRAM_START];
+    while let Some(start) = pending.pop() {
+        let mut pc = start;
+        // Target of the last ANNN on this path, for spotting sprite data
+        let mut last_i = None;
+        while let Some(opcode) = word(rom, pc) {
+            let offset = (pc - PROGRAM_START) as usize;
+            if analysis.code[offset] {
+                break;
+            }
+            let next = word(rom, pc.wrapping_add(2)).unwrap_or(0);
+            let Some(instruction) = decode(opcode, next) else {
+                break;
+            };
+            let len = instruction.size() as usize;
+            let code_end = (offset + len).min(rom.len());
+            analysis.code[offset..code_end].fill(true);
+
+            match instruction {
+                Instruction::Jump(target) => {
+                    if in_rom(target) {
+                        analysis.add_label(target, LabelKind::Jump);
+                        pending.push(target);
+                    }
+                    break;
+                }
+                Instruction::Call(target) if in_rom(target) => {
+                    analysis.add_label(target, LabelKind::Subroutine);
+                    pending.push(target);
+                }
+                Instruction::Return | Instruction::Exit | Instruction::JumpOffset(_) => break,
+                Instruction::LoadI(target) | Instruction::LongLoadI(target) => {
+                    last_i = Some(target);
+                    if in_rom(target) {
+                        analysis.add_label(target, LabelKind::Data);
+                    }
+                }
+                Instruction::AddI(_) => last_i = None,
+                Instruction::Draw(_, _, n) => {
+                    if let Some(target) = last_i.filter(|&t| in_rom(t)) {
+                        let len = if n == 0 { 32 } else { n as u16 };
+                        let sprite = analysis.sprites.entry(target).or_insert(0);
+                        *sprite = (*sprite).max(len);
+                        analysis.add_label(target, LabelKind::Sprite);
+                    }
+                }
+                _ => {}
+            }
+
+            // A skip can also land after the following instruction
+            if instruction.is_skip() {
+                let following = pc.wrapping_add(2);
+                let skipped_len = word(rom, following)
+                    .and_then(|op| decode(op, 0))
+                    .map_or(2, |i| i.size());
+                pending.push(following.wrapping_add(skipped_len));
+            }
+            pc = pc.wrapping_add(len as u16);
+        }
+    }
+    analysis
+}
+
+// Disassemble a ROM loaded at 0x200
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> Vec<Line> {
+    let analysis = analyze(rom);
+    let label = |addr: u16| analysis.label(addr);
+    let sprite_end = |addr: u16| {
+        analysis
+            .sprites
+            .range(..=addr)
+            .any(|(&start, &len)| (addr as usize) < start as usize + len as usize)
+    };
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        // XO-CHIP programs can run up to 0xFFFF
+        let addr = PROGRAM_START.wrapping_add(offset as u16);
+        if analysis.code[offset] {
+            let opcode = word(rom, addr).unwrap_or(0);
+            let next = addr
+                .checked_add(2)
+                .and_then(|next| word(rom, next))
+                .unwrap_or(0);
+            let instruction = decode(opcode, next).expect("analysed code decodes");
+            let len = (instruction.size() as usize).min(rom.len() - offset);
+            lines.push(Line {
+                addr,
+                bytes: rom[offset..offset + len].to_vec(),
+                label: label(addr),
+                text: instruction.format(syntax, &label),
+                comment: None,
+            });
+            offset += len;
+        } else if sprite_end(addr) {
+            // Sprite rows one per line with a preview of the pixels
+            let byte = rom[offset];
+            let preview: String = (0..8)
+                .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+                .collect();
+            lines.push(Line {
+                addr,
+                bytes: vec![byte],
+                label: label(addr),
+                text: format_data(&[byte], syntax),
+                comment: Some(preview),
+            });
+            offset += 1;
+        } else {
+            // Other data up to 8 bytes per line, stopping at code or a label
+            let mut len = 1;
+            while len < 8
+                && offset + len < rom.len()
+                && !analysis.code[offset + len]
+                && label(addr.wrapping_add(len as u16)).is_none()
+            {
+                len += 1;
+            }
+            let bytes = &rom[offset..offset + len];
+            lines.push(Line {
+                addr,
+                bytes: bytes.to_vec(),
+                label: label(addr),
+                text: format_data(bytes, syntax),
+                comment: None,
+            });
+            offset += len;
+        }
+    }
+    lines
+}
+
+fn format_data(bytes: &[u8], syntax: Syntax) -> String {
+    let bytes: Vec<String> = match syntax {
+        Syntax::Octo => bytes.iter().map(|b| format!("0x{:02X}", b)).collect(),
+        Syntax::Cowgod => bytes.iter().map(|b| format!("#{:02X}", b)).collect(),
+    };
+    match syntax {
+        Syntax::Octo => bytes.join(" "),
+        Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+    }
+}
+
+// Render lines as a listing of address, raw bytes and instruction
+pub fn format_listing(lines: &[Line], syntax: Syntax) -> String {
+    let comment = match syntax {
+        Syntax::Octo => "#",
+        Syntax::Cowgod => ";",
+    };
+    let mut out = String::new();
+    for line in lines {
+        if let Some(label) = &line.label {
+            match syntax {
+                Syntax::Octo => writeln!(out, "{:17}: {}", "", label).unwrap(),
+                Syntax::Cowgod => writeln!(out, "{:17}{}:", "", label).unwrap(),
+            }
+        }
+        let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let mut text = format!("{:04X}  {:11}  {}", line.addr, bytes.join(" "), line.text);
+        if let Some(note) = &line.comment {
+            text = format!("{:48} {} {}", text, comment, note);
+        }
+        writeln!(out, "{}", text.trim_end()).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x00E0, 0), Some(Instruction::Clear));
+        assert_eq!(decode(0x8AB4, 0), Some(Instruction::AddReg(0xA, 0xB)));
+        assert_eq!(decode(0xF000, 0x1234), Some(Instruction::LongLoadI(0x1234)));
+        assert_eq!(decode(0xD125, 0), Some(Instruction::Draw(1, 2, 5)));
+        assert_eq!(decode(0x5121, 0), None);
+        assert_eq!(decode(0x0123, 0), None);
+    }
+
+    #[test]
+    fn test_format_syntaxes() {
+        let no_labels = |_: u16| None;
+        let add = Instruction::Add(0x3, 0x12);
+        assert_eq!(add.format(Syntax::Octo, &no_labels), "v3 += 0x12");
+        assert_eq!(add.format(Syntax::Cowgod, &no_labels), "ADD V3, #12");
+        let skip = Instruction::SkipEq(0x1, 0x0);
+        assert_eq!(skip.format(Syntax::Octo, &no_labels), "if v1 != 0x00 then");
+        assert_eq!(skip.format(Syntax::Cowgod, &no_labels), "SE V1, #00");
+        let jump = Instruction::Jump(0x208);
+        let labels = |a: u16| (a == 0x208).then(|| "loop".to_string());
+        assert_eq!(jump.format(Syntax::Octo, &labels), "jump loop");
+        assert_eq!(jump.format(Syntax::Cowgod, &no_labels), "JP #208");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let rom = vec![
+            0xA2, 0x0C, // i := sprite_20C
+            0x22, 0x0A, // :call sub_20A
+            0xD0, 0x12, // sprite v0 v1 2
+            0x12, 0x06, // jump label_206
+            0x00, 0x00, // unreachable
+            0x00, 0xEE, // return
+            0xF0, 0x90, // sprite data
+        ];
+        let lines = disassemble(&rom, Syntax::Octo);
+        let text: Vec<(u16, Option<&str>, &str)> = lines
+            .iter()
+            .map(|l| (l.addr, l.label.as_deref(), l.text.as_str()))
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                (0x200, Some("main"), "i := sprite_20C"),
+                (0x202, None, ":call sub_20A"),
+                (0x204, None, "sprite v0 v1 2"),
+                (0x206, Some("label_206"), "jump label_206"),
+                (0x208, None, "0x00 0x00"),
+                (0x20A, Some("sub_20A"), "return"),
+                (0x20C, Some("sprite_20C"), "0xF0"),
+                (0x20D, None, "0x90"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sprite_data() {
+        let rom = vec![
+            0xA2, 0x06, // i := sprite_206
+            0xD0, 0x02, // sprite v0 v0 2
+            0x00, 0xFD, // exit
+            0xF0, 0x90, // sprite data
+        ];
+        let lines = disassemble(&rom, Syntax::Cowgod);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3].label, Some("sprite_206".to_string()));
+        assert_eq!(lines[3].text, "DB #F0");
+        assert_eq!(lines[3].comment, Some("####....".to_string()));
+        assert_eq!(lines[4].comment, Some("#..#....".to_string()));
+
+        let listing = format_listing(&lines, Syntax::Cowgod);
+        assert!(listing.contains("sprite_206:"));
+        assert!(listing.contains("0206  F0           DB #F0"));
+    }
+
+    #[test]
+    fn test_skip_follows_both_paths() {
+        let rom = vec![
+            0x30, 0x00, // skip next if v0 == 0
+            0x00, 0xFD, // exit
+            0x60, 0x01, // v0 := 1, only reached by skipping
+            0x00, 0xFD, // exit
+        ];
+        let lines = disassemble(&rom, Syntax::Octo);
+        assert_eq!(lines[2].text, "v0 := 0x01");
+    }
+
+    #[test]
+    fn test_end_of_xo_memory() {
+        // Code running up to 0xFFFF
+        let rom = [0x60, 0x00].repeat(0x7F00);
+        let lines = disassemble(&rom, Syntax::Octo);
+        assert_eq!(lines.last().unwrap().addr, 0xFFFE);
+
+        // A sprite in the last two bytes
+        let mut rom = vec![0x00; 0xFE00];
+        rom[..8].copy_from_slice(&[0xF0, 0x00, 0xFF, 0xFE, 0xD0, 0x02, 0x00, 0xFD]);
+        let lines = disassemble(&rom, Syntax::Octo);
+        assert_eq!(lines.last().unwrap().addr, 0xFFFF);
+        assert_eq!(lines.last().unwrap().comment, Some("........".to_string()));
+    }
+}
@@ -0,0 +1,30 @@
+// Where the CPU shows its screen and reads the keypad from. Kept here
+// rather than in a windowing crate so the core builds without one
+pub trait Display {
+    // Show a frame of `width` x `height` pixels, each a color index 0-3
+    fn update(&mut self, buffer: &[u8], width: usize, height: usize);
+    fn is_open(&self) -> bool;
+    fn is_key_down(&self, key: usize) -> bool;
+}
+
+// Shows nothing and never has a key down
+#[derive(Debug, Default)]
+pub struct NullDisplay;
+
+impl NullDisplay {
+    pub fn new() -> NullDisplay {
+        NullDisplay
+    }
+}
+
+impl Display for NullDisplay {
+    fn update(&mut self, _buffer: &[u8], _width: usize, _height: usize) {}
+
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    fn is_key_down(&self, _key: usize) -> bool {
+        false
+    }
+}
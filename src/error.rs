@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io;
+
+use crate::octo::AssemblyError;
+
+// Faults raised while executing a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds { addr: usize },
+    InvalidKey { key: u8 },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            Chip8Error::StackOverflow => write!(f, "Stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "Stack underflow"),
+            Chip8Error::MemoryOutOfBounds { addr } => {
+                write!(f, "Memory access out of bounds at 0x{:X}", addr)
+            }
+            Chip8Error::InvalidKey { key } => write!(f, "Invalid key 0x{:X}", key),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+// Reasons a save state can't be restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    NotASaveState,
+    UnsupportedVersion(u8),
+    RomMismatch,
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::NotASaveState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            StateError::RomMismatch => write!(f, "Save state was made with a different ROM"),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Reasons a movie can't be played back, or didn't play back as recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u8),
+    RomMismatch,
+    Corrupt,
+    Diverged,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version {}", version)
+            }
+            MovieError::RomMismatch => write!(f, "Movie was recorded with a different ROM"),
+            MovieError::Corrupt => write!(f, "Movie is corrupt"),
+            MovieError::Diverged => write!(f, "Playback diverged from the recording"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// Reasons a ROM can't be read or loaded
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    // A token in a .hex file that isn't a byte, with its position
+    InvalidHex {
+        line: usize,
+        column: usize,
+        text: String,
+    },
+    Assembly(AssemblyError),
+    // More than fits in memory from 0x200 on
+    TooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "{}", e),
+            RomError::InvalidHex { line, column, text } => {
+                write!(f, "{}:{}: Invalid hex byte '{}'", line, column, text)
+            }
+            RomError::Assembly(e) => write!(f, "{}", e),
+            RomError::TooLarge { size, max } => write!(
+                f,
+                "ROM is {} bytes, more than the {} bytes of memory available",
+                size, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> Self {
+        RomError::Io(e)
+    }
+}
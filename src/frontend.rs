@@ -0,0 +1,21 @@
+use crate::display::Display;
+
+// Emulator controls, as opposed to keypad keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState(usize),
+    LoadState(usize),
+    Slower,
+    Faster,
+    Turbo,
+}
+
+// A display the emulator can be played in interactively, which also
+// reports the hotkeys
+pub trait Frontend: Display {
+    // The hotkey pressed since the last `take_hotkey`
+    fn hotkey(&self) -> Option<Hotkey>;
+    fn take_hotkey(&mut self) -> Option<Hotkey>;
+    // Whether the rewind key is held down
+    fn is_rewinding(&self) -> bool;
+}
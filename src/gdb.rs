@@ -0,0 +1,558 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvError, TryRecvError};
+use std::thread;
+
+use crate::cpu::{StepOutcome, StopReason, CPU};
+use crate::display::Display;
+use crate::error::Chip8Error;
+
+/*
+   GDB remote serial protocol stub
+   * Registers are numbered as in TARGET_XML: V0-VF, I, PC, SP, DT, ST.
+     I and PC are 16 bits, sent little endian, the rest are bytes
+   * Breakpoints (Z0/Z1) are kept in a list and checked before every
+     instruction, so memory is never patched
+   * Ctrl-C from GDB (0x03) pauses a running program
+*/
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: usize = 21;
+const I: usize = 16;
+const PC: usize = 17;
+const SP: usize = 18;
+const DT: usize = 19;
+const ST: usize = 20;
+
+// Signals given in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const INTERRUPT: u8 = 0x03;
+
+// What to do after a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    Step,
+    Continue,
+    // GDB has detached or killed the program
+    Stop,
+}
+
+// Serves one GDB connection for a CPU
+pub struct GdbStub<W: Write> {
+    breakpoints: BTreeSet<u16>,
+    input: Receiver<u8>,
+    output: W,
+}
+
+impl GdbStub<TcpStream> {
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub<TcpStream>> {
+        // Read on its own thread so a running program can be interrupted
+        let (sender, input) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            while let Ok(n @ 1..) = reader.read(&mut buffer) {
+                if buffer[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+        Ok(GdbStub {
+            breakpoints: BTreeSet::new(),
+            input,
+            output: stream,
+        })
+    }
+}
+
+impl<W: Write> GdbStub<W> {
+    // Handle packets until GDB detaches or the connection is closed
+    pub fn run<D: Display>(&mut self, cpu: &mut CPU<D>) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(Some(packet)) => packet,
+                // Nothing is running, so there's nothing to interrupt
+                Ok(None) => continue,
+                Err(RecvError) => return Ok(()),
+            };
+            let reply = match self.command(cpu, &packet) {
+                Action::Reply(reply) => reply,
+                Action::Step => self.step(cpu),
+                Action::Continue => self.resume(cpu),
+                Action::Stop => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                }
+            };
+            self.write_packet(&reply)?;
+        }
+    }
+
+    // The next packet, acknowledged, or None for an interrupt
+    fn read_packet(&mut self) -> Result<Option<String>, RecvError> {
+        loop {
+            match self.input.recv()? {
+                b'$' => {}
+                INTERRUPT => return Ok(None),
+                // Acks, and anything else between packets
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.input.recv()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.input.recv()?, self.input.recv()?];
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .is_some_and(|sum| sum == checksum_of(&data));
+            // A failed write shows up when the reply is sent
+            let _ = self.output.write_all(if valid { b"+" } else { b"-" });
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend([b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend(format!("#{:02x}", checksum_of(&escaped)).bytes());
+        self.output.write_all(&packet)?;
+        self.output.flush()
+    }
+
+    fn command<D: Display>(&mut self, cpu: &mut CPU<D>, packet: &str) -> Action {
+        let (kind, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match kind {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REGISTERS).map(|n| read_register(cpu, n)).collect(),
+            "G" => {
+                // Check every value before writing any, so a bad packet
+                // leaves the registers alone
+                let mut rest = args;
+                let mut values = Vec::new();
+                for n in 0..REGISTERS {
+                    let size = register_size(n) * 2;
+                    let Some((hex, tail)) = rest.split_at_checked(size) else {
+                        return Action::Reply("E01".to_string());
+                    };
+                    let Some(value) = parse_register(cpu, n, hex) else {
+                        return Action::Reply("E01".to_string());
+                    };
+                    values.push(value);
+                    rest = tail;
+                }
+                for (n, value) in values.into_iter().enumerate() {
+                    write_register(cpu, n, value);
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTERS => read_register(cpu, n),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let value = args.split_once('=').and_then(|(n, hex)| {
+                    let n = usize::from_str_radix(n, 16)
+                        .ok()
+                        .filter(|&n| n < REGISTERS)?;
+                    Some((n, parse_register(cpu, n, hex)?))
+                });
+                if let Some((n, value)) = value {
+                    write_register(cpu, n, value);
+                }
+                status(value.is_some())
+            }
+            "m" => parse_range(args)
+                .and_then(|(addr, len)| {
+                    let end = addr.checked_add(len)?.min(cpu.memory.len());
+                    cpu.memory.get(addr..end)
+                })
+                .filter(|bytes| !bytes.is_empty() || args.ends_with(",0"))
+                .map(|bytes| bytes.iter().map(|b| format!("{:02x}", b)).collect())
+                .unwrap_or("E01".to_string()),
+            "M" => {
+                let written = args.split_once(':').is_some_and(|(range, data)| {
+                    match (parse_range(range), decode_hex(data)) {
+                        (Some((addr, len)), Some(bytes)) if bytes.len() == len => addr
+                            .checked_add(len)
+                            .and_then(|end| cpu.memory.get_mut(addr..end))
+                            .map(|memory| memory.copy_from_slice(&bytes))
+                            .is_some(),
+                        _ => false,
+                    }
+                });
+                status(written)
+            }
+            "Z" | "z" => match breakpoint_address(args) {
+                Some(addr) => {
+                    if kind == "Z" {
+                        self.breakpoints.insert(addr);
+                    } else {
+                        self.breakpoints.remove(&addr);
+                    }
+                    "OK".to_string()
+                }
+                // Watchpoints aren't supported
+                None => String::new(),
+            },
+            // Resuming at another address isn't supported, so the address
+            // is ignored
+            "s" => return Action::Step,
+            "c" => return Action::Continue,
+            "D" | "k" => return Action::Stop,
+            "H" => "OK".to_string(),
+            "q" => query(args),
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    // Run one instruction
+    fn step<D: Display>(&mut self, cpu: &mut CPU<D>) -> String {
+        cpu.poll_keys();
+        let reply = match cpu.cycle(0, 1) {
+            Ok(StepOutcome::Exit) => "W00".to_string(),
+            Ok(_) => format!("S{:02x}", SIGTRAP),
+            Err(e) => fault_reply(&e),
+        };
+        cpu.refresh_display();
+        reply
+    }
+
+    // Run in real time until a breakpoint, an interrupt from GDB, or the
+    // program stops
+    fn resume<D: Display>(&mut self, cpu: &mut CPU<D>) -> String {
+        let mut first = true;
+        let mut signal = SIGTRAP;
+        let result = cpu.run_until(|cpu| {
+            // Don't stop on a breakpoint at the starting instruction
+            if std::mem::take(&mut first) {
+                return false;
+            }
+            match self.input.try_recv() {
+                Ok(INTERRUPT) | Err(TryRecvError::Disconnected) => {
+                    signal = SIGINT;
+                    return true;
+                }
+                _ => {}
+            }
+            self.breakpoints.contains(&cpu.pc)
+        });
+        match result {
+            Ok(StopReason::Paused) => format!("S{:02x}", signal),
+            Ok(StopReason::Exit | StopReason::Closed | StopReason::MovieEnd) => "W00".to_string(),
+            Err(e) => fault_reply(&e),
+        }
+    }
+}
+
+fn status(ok: bool) -> String {
+    if ok { "OK" } else { "E01" }.to_string()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn fault_reply(error: &Chip8Error) -> String {
+    let signal = match error {
+        Chip8Error::UnknownOpcode { .. } | Chip8Error::InvalidKey { .. } => SIGILL,
+        _ => SIGSEGV,
+    };
+    format!("S{:02x}", signal)
+}
+
+fn query(args: &str) -> String {
+    if args.starts_with("Supported") {
+        return "PacketSize=4000;qXfer:features:read+".to_string();
+    }
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let Some((offset, len)) = parse_range(range) else {
+            return "E01".to_string();
+        };
+        let xml = TARGET_XML.as_bytes();
+        let start = offset.min(xml.len());
+        let Some(end) = start.checked_add(len) else {
+            return "E01".to_string();
+        };
+        let end = end.min(xml.len());
+        let more = if end < xml.len() { 'm' } else { 'l' };
+        return format!("{}{}", more, String::from_utf8_lossy(&xml[start..end]));
+    }
+    match args {
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+// addr,length in hex
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// type,addr,kind for software (0) and hardware (1) breakpoints
+fn breakpoint_address(s: &str) -> Option<u16> {
+    let mut parts = s.split(',');
+    let kind = parts.next()?;
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    matches!(kind, "0" | "1").then_some(addr)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|n| u8::from_str_radix(&s[n..n + 2], 16).ok())
+        .collect()
+}
+
+fn register_size(n: usize) -> usize {
+    match n {
+        I | PC => 2,
+        _ => 1,
+    }
+}
+
+fn read_register<D: Display>(cpu: &CPU<D>, n: usize) -> String {
+    let value = match n {
+        0..=15 => cpu.v[n] as u16,
+        I => cpu.i,
+        PC => cpu.pc,
+        SP => cpu.sp,
+        DT => cpu.delay_timer as u16,
+        ST => cpu.sound_timer as u16,
+        _ => unreachable!(),
+    };
+    let mut hex = String::new();
+    for byte in &value.to_le_bytes()[..register_size(n)] {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+// The value GDB gives for register n, if it's one the register can hold
+fn parse_register<D: Display>(cpu: &CPU<D>, n: usize, hex: &str) -> Option<u16> {
+    let bytes = decode_hex(hex).filter(|b| b.len() == register_size(n))?;
+    let value = u16::from_le_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)]);
+    // The stack only has room for 16 addresses
+    if n == SP && value as usize > cpu.stack.len() {
+        return None;
+    }
+    Some(value)
+}
+
+fn write_register<D: Display>(cpu: &mut CPU<D>, n: usize, value: u16) {
+    match n {
+        0..=15 => cpu.v[n] = value as u8,
+        I => cpu.i = value,
+        PC => cpu.pc = value,
+        SP => cpu.sp = value,
+        DT => cpu.delay_timer = value as u8,
+        ST => cpu.sound_timer = value as u8,
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+    use std::sync::mpsc::Sender;
+
+    fn setup(prog: Vec<u8>) -> (CPU<NullDisplay>, GdbStub<Vec<u8>>, Sender<u8>) {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        cpu.load(prog).unwrap();
+        let (sender, input) = mpsc::channel();
+        let stub = GdbStub {
+            breakpoints: BTreeSet::new(),
+            input,
+            output: Vec::new(),
+        };
+        (cpu, stub, sender)
+    }
+
+    fn reply(stub: &mut GdbStub<Vec<u8>>, cpu: &mut CPU<NullDisplay>, packet: &str) -> String {
+        match stub.command(cpu, packet) {
+            Action::Reply(reply) => reply,
+            Action::Step => stub.step(cpu),
+            action => panic!("{:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        let (_cpu, mut stub, sender) = setup(vec![]);
+        for &byte in b"+$g#67$m200,2#00\x03" {
+            sender.send(byte).unwrap();
+        }
+        assert_eq!(stub.read_packet(), Ok(Some("g".to_string())));
+        // Bad checksum, nacked and skipped until the interrupt
+        assert_eq!(stub.read_packet(), Ok(None));
+        assert_eq!(stub.output, b"+-");
+
+        stub.output.clear();
+        stub.write_packet("a#b").unwrap();
+        assert_eq!(stub.output, b"$a}\x03b#43");
+        drop(sender);
+        assert_eq!(stub.read_packet(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut cpu, mut stub, _sender) = setup(vec![0x6A, 0x42, 0xA3, 0x21]);
+        cpu.cycle(0, 0).unwrap();
+        cpu.cycle(0, 0).unwrap();
+        cpu.delay_timer = 9;
+        assert_eq!(
+            reply(&mut stub, &mut cpu, "g"),
+            "00000000000000000000420000000000\
+             2103\
+             0402\
+             00\
+             09\
+             00"
+        );
+        assert_eq!(reply(&mut stub, &mut cpu, "pa"), "42");
+        assert_eq!(reply(&mut stub, &mut cpu, "p11"), "0402");
+        assert_eq!(reply(&mut stub, &mut cpu, "P11=0002"), "OK");
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(reply(&mut stub, &mut cpu, "P12=11"), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "p15"), "E01");
+
+        let all = "0102030405060708090a0b0c0d0e0f10ff0f06020103ff";
+        assert_eq!(reply(&mut stub, &mut cpu, &format!("G{}", all)), "OK");
+        assert_eq!(cpu.v[0xF], 0x10);
+        assert_eq!((cpu.i, cpu.pc, cpu.sp), (0xFFF, 0x206, 1));
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (3, 0xFF));
+        assert_eq!(reply(&mut stub, &mut cpu, "g"), all);
+
+        // A short packet, and one with an SP past the stack, change nothing
+        let before = reply(&mut stub, &mut cpu, "g");
+        assert_eq!(reply(&mut stub, &mut cpu, "G00000000"), "E01");
+        let bad_sp = "0000000000000000000000000000000000000000110000";
+        assert_eq!(reply(&mut stub, &mut cpu, &format!("G{}", bad_sp)), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "g"), before);
+    }
+
+    #[test]
+    fn test_memory() {
+        let (mut cpu, mut stub, _sender) = setup(vec![0x12, 0x34]);
+        assert_eq!(reply(&mut stub, &mut cpu, "m200,3"), "123400");
+        assert_eq!(reply(&mut stub, &mut cpu, "mfff,4"), "00");
+        assert_eq!(reply(&mut stub, &mut cpu, "m1000,1"), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "M300,2:abcd"), "OK");
+        assert_eq!(&cpu.memory[0x300..0x302], &[0xAB, 0xCD]);
+        assert_eq!(reply(&mut stub, &mut cpu, "M300,2:ab"), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "Mfff,2:abcd"), "E01");
+        // Ranges that overflow are refused rather than panicking
+        assert_eq!(reply(&mut stub, &mut cpu, "m1,ffffffffffffffff"), "E01");
+        assert_eq!(reply(&mut stub, &mut cpu, "Mffffffffffffffff,1:00"), "E01");
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        // v0 += 1, v1 += 1, jump back to the start
+        let (mut cpu, mut stub, sender) = setup(vec![0x70, 0x01, 0x71, 0x01, 0x12, 0x00]);
+        assert_eq!(reply(&mut stub, &mut cpu, "s"), "S05");
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(reply(&mut stub, &mut cpu, "Z0,202,2"), "OK");
+        assert_eq!(stub.command(&mut cpu, "c"), Action::Continue);
+        // Runs round the loop back to the breakpoint it started on
+        assert_eq!(stub.resume(&mut cpu), "S05");
+        assert_eq!((cpu.pc, cpu.v[0], cpu.v[1]), (0x202, 2, 1));
+        assert_eq!(reply(&mut stub, &mut cpu, "z0,202,2"), "OK");
+        assert_eq!(reply(&mut stub, &mut cpu, "Z2,300,1"), "");
+
+        sender.send(INTERRUPT).unwrap();
+        assert_eq!(stub.resume(&mut cpu), "S02");
+    }
+
+    #[test]
+    fn test_faults_and_exit() {
+        let (mut cpu, mut stub, _sender) = setup(vec![0x00, 0xFD, 0x00, 0x00]);
+        cpu.quirks.super_chip = true;
+        assert_eq!(reply(&mut stub, &mut cpu, "s"), "W00");
+        cpu.pc = 0x202;
+        assert_eq!(reply(&mut stub, &mut cpu, "s"), "S04");
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_target_xml() {
+        assert_eq!(
+            query("Supported:multiprocess+;xmlRegisters=i386"),
+            "PacketSize=4000;qXfer:features:read+"
+        );
+        let start = query("Xfer:features:read:target.xml:0,a");
+        assert_eq!(start, "m<?xml vers");
+        let end = query(&format!(
+            "Xfer:features:read:target.xml:{:x},1000",
+            TARGET_XML.len() - 10
+        ));
+        assert_eq!(end, "l</target>\n");
+        assert_eq!(
+            query("Xfer:features:read:target.xml:1,ffffffffffffffff"),
+            "E01"
+        );
+        assert_eq!(TARGET_XML.matches("<reg ").count(), REGISTERS);
+    }
+}
@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use crate::display::Display;
+
+use crate::cpu::{StepOutcome, CPU, FRAMERATE};
+use crate::error::Chip8Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// A key held down for `frames` frames starting at `frame`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub frame: u64,
+    pub key: u8,
+    pub frames: u64,
+}
+
+impl KeyPress {
+    fn is_down(&self, frame: u64) -> bool {
+        frame >= self.frame && frame < self.frame + self.frames
+    }
+}
+
+// frame:key[:frames], with the key in hex. Held for 5 frames by default,
+// long enough for programs that only check the keys every few frames
+impl FromStr for KeyPress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid key press '{}', expected frame:key[:frames]", s);
+        let mut parts = s.split(':');
+        let frame = parts
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(invalid)?;
+        let key = parts
+            .next()
+            .and_then(|k| u8::from_str_radix(k, 16).ok())
+            .filter(|&k| k < 16)
+            .ok_or_else(invalid)?;
+        let frames = match parts.next() {
+            Some(n) => n.parse().map_err(|_| invalid())?,
+            None => 5,
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(KeyPress { frame, key, frames })
+    }
+}
+
+// Run without a window or real time delays until the limit is reached, the
+// program exits or the movie being played back ends. Keys come from the
+// movie if there is one, otherwise only from `presses`
+pub fn run<D: Display>(
+    cpu: &mut CPU<D>,
+    limit: Limit,
+    presses: &[KeyPress],
+) -> Result<(), Chip8Error> {
+    run_with(cpu, limit, presses, |_, _, _| true)
+}
+
+// `run`, calling `step` after every cycle with the frame it was in and its
+// outcome. Stops early when `step` returns false
+pub fn run_with<D: Display, F: FnMut(&CPU<D>, u64, StepOutcome) -> bool>(
+    cpu: &mut CPU<D>,
+    limit: Limit,
+    presses: &[KeyPress],
+    mut step: F,
+) -> Result<(), Chip8Error> {
+    // Pretend each instruction takes its share of the frame, so the display
+    // wait quirk behaves as it does in real time
+    let frame_time = (1_000_000 / FRAMERATE) as u128;
+    let cycles_per_frame = cpu.cycles_per_frame as u128;
+    let mut cycles = 0;
+    for frame in 0.. {
+        if limit == Limit::Frames(frame) {
+            break;
+        }
+        if cpu.has_movie() {
+            if cpu.movie_ended() {
+                break;
+            }
+            cpu.poll_keys();
+        } else {
+            for key in 0..16 {
+                let down = presses.iter().any(|p| p.key == key && p.is_down(frame));
+                cpu.keys[key as usize] = down as u8;
+            }
+        }
+        for n in 0..cycles_per_frame {
+            if limit == Limit::Cycles(cycles) {
+                return Ok(());
+            }
+            let time_since_frame = frame_time * n / cycles_per_frame;
+            cycles += 1;
+            let outcome = cpu.cycle(time_since_frame, frame_time)?;
+            if !step(cpu, frame, outcome) || outcome == StepOutcome::Exit {
+                return Ok(());
+            }
+        }
+        cpu.end_frame();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CYCLES_PER_FRAME;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+
+    fn setup(prog: Vec<u8>) -> CPU<NullDisplay> {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::COSMAC_VIP);
+        cpu.initialize();
+        cpu.load(prog).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_parse_key_press() {
+        let press: KeyPress = "120:a".parse().unwrap();
+        assert_eq!(
+            press,
+            KeyPress {
+                frame: 120,
+                key: 0xA,
+                frames: 5
+            }
+        );
+        let press: KeyPress = "3:F:1".parse().unwrap();
+        assert_eq!(
+            press,
+            KeyPress {
+                frame: 3,
+                key: 0xF,
+                frames: 1
+            }
+        );
+        assert!("3".parse::<KeyPress>().is_err());
+        assert!("3:10".parse::<KeyPress>().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        // Count up in v0 forever
+        let prog = vec![0x70, 0x01, 0x12, 0x00];
+        let mut cpu = setup(prog.clone());
+        run(&mut cpu, Limit::Cycles(10), &[]).unwrap();
+        assert_eq!(cpu.v[0], 5);
+
+        let mut cpu = setup(prog.clone());
+        run(&mut cpu, Limit::Frames(2), &[]).unwrap();
+        assert_eq!(cpu.v[0], CYCLES_PER_FRAME as u8);
+
+        let mut cpu = setup(prog);
+        cpu.cycles_per_frame = 30;
+        run(&mut cpu, Limit::Frames(2), &[]).unwrap();
+        assert_eq!(cpu.v[0], 30);
+    }
+
+    #[test]
+    fn test_key_presses() {
+        // Loop until key 7 is down, draw its character, then exit
+        let prog = vec![
+            0x60, 0x07, 0xE0, 0x9E, 0x12, 0x02, 0xF0, 0x29, 0xD1, 0x15, 0x00, 0xFD,
+        ];
+        let mut cpu = setup(prog.clone());
+        run(&mut cpu, Limit::Frames(60), &[]).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        let mut cpu = setup(prog);
+        cpu.quirks.super_chip = true;
+        let presses = [KeyPress {
+            frame: 30,
+            key: 0x7,
+            frames: 1,
+        }];
+        run(&mut cpu, Limit::Frames(600), &presses).unwrap();
+        // Exited rather than running the remaining frames
+        assert_eq!(cpu.pc, 0x20C);
+        // Top row of the 7 glyph is 0xF0
+        assert_eq!(&cpu.gfx[..8], &[1, 1, 1, 1, 0, 0, 0, 0]);
+    }
+}
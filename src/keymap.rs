@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+// Which host keys press each of the 16 keypad keys. Host keys are named
+// as in minifb's `Key`: "A", "Key1", "NumPad7", "Semicolon", "Up"...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    pub keys: [Vec<String>; 16],
+}
+
+// Host keys for keypad keys 0-F in each layout. All but numpad sit on the
+// same physical keys, the left of the keyboard:
+//   1 2 3 C      1 2 3 4
+//   4 5 6 D      Q W E R
+//   7 8 9 E  ->  A S D F
+//   A 0 B F      Z X C V
+// numpad puts 0-9 on the matching digits, so 2/4/6/8 are the arrows
+pub const LAYOUTS: [(&str, [&str; 16]); 4] = [
+    (
+        "qwerty",
+        [
+            "X", "Key1", "Key2", "Key3", "Q", "W", "E", "A", "S", "D", "Z", "C", "Key4", "R", "F",
+            "V",
+        ],
+    ),
+    (
+        "azerty",
+        [
+            "X", "Key1", "Key2", "Key3", "A", "Z", "E", "Q", "S", "D", "W", "C", "Key4", "R", "F",
+            "V",
+        ],
+    ),
+    (
+        "dvorak",
+        [
+            "Q",
+            "Key1",
+            "Key2",
+            "Key3",
+            "Apostrophe",
+            "Comma",
+            "Period",
+            "A",
+            "O",
+            "E",
+            "Semicolon",
+            "J",
+            "Key4",
+            "P",
+            "U",
+            "K",
+        ],
+    ),
+    (
+        "numpad",
+        [
+            "NumPad0",
+            "NumPad1",
+            "NumPad2",
+            "NumPad3",
+            "NumPad4",
+            "NumPad5",
+            "NumPad6",
+            "NumPad7",
+            "NumPad8",
+            "NumPad9",
+            "NumPadSlash",
+            "NumPadAsterisk",
+            "NumPadMinus",
+            "NumPadPlus",
+            "NumPadEnter",
+            "NumPadDot",
+        ],
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeymapError {
+    Json(String),
+    UnknownLayout(String),
+    InvalidKey(String),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::Json(e) => write!(f, "{}", e),
+            KeymapError::UnknownLayout(name) => {
+                let names: Vec<&str> = LAYOUTS.iter().map(|(n, _)| *n).collect();
+                write!(
+                    f,
+                    "Unknown layout '{}', expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+            KeymapError::InvalidKey(key) => {
+                write!(f, "Invalid keypad key '{}', expected 0-F", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+// A ROM's own layout and keys, applied over the top level ones
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mapping {
+    layout: Option<String>,
+    #[serde(default)]
+    keys: HashMap<String, Vec<String>>,
+}
+
+// The keymap config file, JSON like:
+//   {
+//     "layout": "qwerty",
+//     "keys": { "5": ["W", "Up"] },
+//     "roms": {
+//       "pong.ch8": { "layout": "numpad" },
+//       "<SHA-1 of the ROM>": { "keys": { "4": ["Left"], "6": ["Right"] } }
+//     }
+//   }
+// ROMs are matched by file name or SHA-1. Their mapping applies on top of
+// the top level one
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeymapConfig {
+    layout: Option<String>,
+    #[serde(default)]
+    keys: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    roms: HashMap<String, Mapping>,
+}
+
+impl Keymap {
+    pub fn layout(name: &str) -> Option<Keymap> {
+        LAYOUTS
+            .iter()
+            .find(|(layout, _)| layout.eq_ignore_ascii_case(name))
+            .map(|(_, keys)| Keymap {
+                keys: keys.map(|key| vec![key.to_string()]),
+            })
+    }
+
+    // Also press keypad keys with other host keys, e.g. the arrow keys for
+    // a game's directions. Host keys already mapped are left alone
+    pub fn add_keys(&mut self, keys: &[(u8, String)]) {
+        for (key, host_key) in keys {
+            if !self
+                .keys
+                .iter()
+                .flatten()
+                .any(|k| k.eq_ignore_ascii_case(host_key))
+            {
+                self.keys[*key as usize & 0xF].push(host_key.clone());
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        layout: Option<&str>,
+        keys: &HashMap<String, Vec<String>>,
+    ) -> Result<(), KeymapError> {
+        if let Some(name) = layout {
+            *self =
+                Keymap::layout(name).ok_or_else(|| KeymapError::UnknownLayout(name.to_string()))?;
+        }
+        for (key, host_keys) in keys {
+            let index = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|&k| k < 16)
+                .ok_or_else(|| KeymapError::InvalidKey(key.clone()))?;
+            self.keys[index as usize] = host_keys.clone();
+        }
+        Ok(())
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::layout("qwerty").unwrap()
+    }
+}
+
+impl KeymapConfig {
+    pub fn parse(json: &str) -> Result<KeymapConfig, KeymapError> {
+        serde_json::from_str(json).map_err(|e| KeymapError::Json(e.to_string()))
+    }
+
+    // The keymap for a ROM. `layout` is used instead of the config's own
+    // layout if given, the keys moved by the config still apply on top
+    pub fn keymap(
+        &self,
+        layout: Option<&str>,
+        rom_name: &str,
+        rom_hash: &str,
+    ) -> Result<Keymap, KeymapError> {
+        let mut keymap = Keymap::default();
+        keymap.apply(layout.or(self.layout.as_deref()), &self.keys)?;
+        let rom = self
+            .roms
+            .get(rom_name)
+            .or_else(|| self.roms.get(&rom_hash.to_ascii_lowercase()));
+        if let Some(mapping) = rom {
+            keymap.apply(mapping.layout.as_deref(), &mapping.keys)?;
+        }
+        Ok(keymap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "layout": "azerty",
+        "keys": { "5": ["Z", "Up"] },
+        "roms": {
+            "pong.ch8": { "layout": "numpad" },
+            "00112233445566778899aabbccddeeff00112233": { "keys": { "a": ["Space"] } }
+        }
+    }"#;
+
+    #[test]
+    fn test_layouts() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.keys[0x1], ["Key1"]);
+        assert_eq!(keymap.keys[0xF], ["V"]);
+        assert_eq!(Keymap::layout("AZERTY").unwrap().keys[0x4], ["A"]);
+        assert!(Keymap::layout("colemak").is_none());
+    }
+
+    #[test]
+    fn test_add_keys() {
+        let mut keymap = Keymap::default();
+        keymap.add_keys(&[(5, "Up".to_string()), (6, "w".to_string())]);
+        assert_eq!(keymap.keys[0x5], ["W", "Up"]);
+        assert_eq!(keymap.keys[0x6], ["E"]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = KeymapConfig::parse(CONFIG).unwrap();
+        let keymap = config.keymap(None, "tetris.ch8", "ff").unwrap();
+        assert_eq!(keymap.keys[0x4], ["A"]);
+        assert_eq!(keymap.keys[0x5], ["Z", "Up"]);
+
+        // A ROM's own layout replaces the top level keys
+        let keymap = config.keymap(None, "pong.ch8", "ff").unwrap();
+        assert_eq!(keymap.keys[0x5], ["NumPad5"]);
+
+        let hash = "00112233445566778899AABBCCDDEEFF00112233";
+        let keymap = config.keymap(None, "game.ch8", hash).unwrap();
+        assert_eq!(keymap.keys[0xA], ["Space"]);
+        assert_eq!(keymap.keys[0x5], ["Z", "Up"]);
+
+        let keymap = config.keymap(Some("dvorak"), "tetris.ch8", "ff").unwrap();
+        assert_eq!(keymap.keys[0x4], ["Apostrophe"]);
+        assert_eq!(keymap.keys[0x5], ["Z", "Up"]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let parse = |json| KeymapConfig::parse(json).and_then(|c| c.keymap(None, "", ""));
+        assert_eq!(
+            parse(r#"{ "layout": "colemak" }"#),
+            Err(KeymapError::UnknownLayout("colemak".to_string()))
+        );
+        assert_eq!(
+            parse(r#"{ "keys": { "10": ["A"] } }"#),
+            Err(KeymapError::InvalidKey("10".to_string()))
+        );
+        assert!(matches!(
+            parse("{ \"layuot\": 1 }"),
+            Err(KeymapError::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+pub mod audio;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod fontset;
+pub mod frontend;
+pub mod gdb;
+pub mod headless;
+pub mod keymap;
+pub mod machine;
+pub mod memview;
+pub mod movie;
+pub mod octo;
+pub mod quirks;
+pub mod random;
+mod rewind;
+pub mod rom_loader;
+pub mod romdb;
+pub mod savestate;
+pub mod screen;
+pub mod trace;
+pub mod tracediff;
+pub mod tty;
+#[cfg(feature = "window")]
+pub mod window;
+
+pub use cpu::CPU;
+pub use display::{Display, NullDisplay};
+pub use error::Chip8Error;
+pub use fontset::FONTSET;
+pub use machine::{Machine, MachineConfig};
+pub use quirks::Quirks;
+pub use rom_loader::{Ch8RomLoader, HexRomLoader, OctoRomLoader, RomLoader};
@@ -0,0 +1,172 @@
+use crate::cpu::{StepOutcome, CPU, CYCLES_PER_FRAME, FRAMERATE};
+use crate::display::NullDisplay;
+use crate::error::{Chip8Error, RomError};
+use crate::quirks::Quirks;
+use crate::random::Generator;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MachineConfig {
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    // Where CXNN gets its numbers from
+    pub generator: Generator,
+    // Seed for CXNN, or None for different numbers on every run
+    pub seed: Option<u64>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            quirks: Quirks::default(),
+            cycles_per_frame: CYCLES_PER_FRAME,
+            generator: Generator::Xorshift,
+            seed: None,
+        }
+    }
+}
+
+// A CHIP-8 machine for embedding in other programs. It has no window or
+// clock of its own: the caller sets the keys, runs it a frame at a time
+// and draws the framebuffer however it likes
+#[derive(Debug)]
+pub struct Machine {
+    cpu: CPU<NullDisplay>,
+    // Instructions run so far in the current frame
+    frame_cycle: u32,
+}
+
+impl Machine {
+    pub fn new(config: MachineConfig) -> Machine {
+        let mut cpu = CPU::new(NullDisplay::new(), config.quirks);
+        cpu.cycles_per_frame = config.cycles_per_frame.max(1);
+        cpu.random = config
+            .generator
+            .with_seed(config.seed.unwrap_or_else(rand::random));
+        cpu.initialize();
+        Machine {
+            cpu,
+            frame_cycle: 0,
+        }
+    }
+
+    // Reset the machine and load a program at 0x200
+    pub fn load(&mut self, rom: Vec<u8>) -> Result<(), RomError> {
+        self.cpu.initialize();
+        self.frame_cycle = 0;
+        self.cpu.load(rom)
+    }
+
+    // Run a single instruction. The timers count down once every
+    // `cycles_per_frame` steps
+    pub fn step(&mut self) -> Result<StepOutcome, Chip8Error> {
+        let frame_time = (1_000_000 / FRAMERATE) as u128;
+        let cycles_per_frame = self.cpu.cycles_per_frame;
+        let time_since_frame = frame_time * self.frame_cycle as u128 / cycles_per_frame as u128;
+        let outcome = self.cpu.cycle(time_since_frame, frame_time)?;
+        self.frame_cycle += 1;
+        if self.frame_cycle >= cycles_per_frame {
+            self.frame_cycle = 0;
+            self.cpu.end_frame();
+        }
+        Ok(outcome)
+    }
+
+    // Run until the end of the current frame, or until the program exits
+    pub fn run_frame(&mut self) -> Result<StepOutcome, Chip8Error> {
+        loop {
+            let last = self.frame_cycle + 1 >= self.cpu.cycles_per_frame;
+            let outcome = self.step()?;
+            if outcome == StepOutcome::Exit || last {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    // The screen, a row at a time, with each pixel a color index 0-3
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.cpu.gfx
+    }
+
+    // Width and height of the framebuffer, which changes with hires mode
+    pub fn screen_size(&self) -> (usize, usize) {
+        (self.cpu.screen_width(), self.cpu.screen_height())
+    }
+
+    pub fn set_key(&mut self, key: u8, down: bool) {
+        self.cpu.keys[key as usize & 0xF] = down as u8;
+    }
+
+    // Whether the sound timer is running and a tone should be playing
+    pub fn is_beeping(&self) -> bool {
+        self.cpu.sound_timer > 0
+    }
+
+    pub fn cpu(&self) -> &CPU<NullDisplay> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<NullDisplay> {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_frame() {
+        // Count frames in v0 with the delay timer, waiting for key 5 to draw
+        // the 0 glyph
+        let mut machine = Machine::new(MachineConfig::default());
+        machine
+            .load(vec![
+                0x61, 0x05, 0x62, 0x01, 0xF2, 0x15, 0xF3, 0x07, 0x33, 0x00, 0x12, 0x06, 0x70, 0x01,
+                0xE1, 0xA1, 0x12, 0x14, 0x12, 0x04, 0xF4, 0x29, 0xD4, 0x45, 0x12, 0x18,
+            ])
+            .unwrap();
+        for _ in 0..10 {
+            assert_eq!(machine.run_frame(), Ok(StepOutcome::Continue));
+        }
+        assert_eq!(machine.cpu().v[0], 9);
+        assert!(machine.framebuffer().iter().all(|&p| p == 0));
+
+        machine.set_key(5, true);
+        machine.run_frame().unwrap();
+        machine.run_frame().unwrap();
+        assert_eq!(machine.screen_size(), (64, 32));
+        assert_eq!(&machine.framebuffer()[..4], [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut machine = Machine::new(MachineConfig {
+            cycles_per_frame: 2,
+            ..MachineConfig::default()
+        });
+        // Set the sound timer to 2, then loop
+        machine
+            .load(vec![0x60, 0x02, 0xF0, 0x18, 0x12, 0x04])
+            .unwrap();
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(machine.is_beeping());
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(!machine.is_beeping());
+        machine.cpu_mut().pc = 0x1000;
+        assert!(machine.step().is_err());
+    }
+
+    #[test]
+    fn test_generator() {
+        let mut machine = Machine::new(MachineConfig {
+            generator: Generator::Vip,
+            seed: Some(4),
+            ..MachineConfig::default()
+        });
+        machine.load(vec![0xC0, 0xFF]).unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.cpu().v[0], 0x67);
+    }
+}
@@ -1,58 +1,949 @@
-mod cpu;
-mod fontset;
-mod rom_loader;
+#[cfg(feature = "beeper")]
+use chip8_emu::audio::BeeperAudio;
+use chip8_emu::audio::{Audio, NullAudio, ToneConfig, WavAudio};
+use chip8_emu::cpu::{StopReason, CPU, CYCLES_PER_FRAME, FRAMERATE};
+use chip8_emu::dap::DapServer;
+use chip8_emu::debugger::{self, Debugger};
+use chip8_emu::disasm::Syntax;
+use chip8_emu::error::{Chip8Error, RomError};
+use chip8_emu::frontend::{Frontend, Hotkey};
+use chip8_emu::gdb::GdbStub;
+use chip8_emu::headless::{KeyPress, Limit};
+use chip8_emu::keymap::{Keymap, KeymapConfig};
+use chip8_emu::movie::Movie;
+use chip8_emu::quirks::Quirks;
+use chip8_emu::random::Generator;
+use chip8_emu::rom_loader::Ch8RomLoader;
+use chip8_emu::rom_loader::HexRomLoader;
+use chip8_emu::rom_loader::OctoRomLoader;
+use chip8_emu::rom_loader::{Rom, RomLoader};
+use chip8_emu::romdb::{RomDatabase, RomInfo};
+use chip8_emu::screen::ImageFormat;
+use chip8_emu::trace::{self, TraceFilter, TraceFormat, Tracer};
+use chip8_emu::tracediff::{self, DiffResult};
+use chip8_emu::tty::{self, Glyphs, TtyDisplay};
+#[cfg(feature = "window")]
+use chip8_emu::window::{self, WindowDisplay};
+use chip8_emu::{disasm, headless, screen};
 
-use crate::rom_loader::Ch8RomLoader;
-use crate::rom_loader::HexRomLoader;
-use crate::rom_loader::RomLoader;
+use chip8_emu::display::{Display, NullDisplay};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-extern crate emu_abstractions;
+struct Options {
+    filename: String,
+    // None to use the ROM database's setting, or the default
+    quirks: Option<Quirks>,
+    tone: ToneConfig,
+    wav: Option<String>,
+    debug: bool,
+    // Wait for GDB to connect on this port and let it drive the machine
+    gdb: Option<u16>,
+    load_state: Option<String>,
+    force_state: bool,
+    rewind: f32,
+    cycles_per_frame: Option<u32>,
+    turbo: bool,
+    generator: Generator,
+    seed: Option<u64>,
+    record: Option<String>,
+    play: Option<String>,
+    verify: bool,
+    keymap: Option<String>,
+    layout: Option<String>,
+    // Play in the terminal with these glyphs instead of in a window
+    tty: Option<Glyphs>,
+    trace: TraceOptions,
+}
 
-use emu_abstractions::display::MiniFBDisplay;
-use minifb::{Window, WindowOptions};
-use std::env;
-use std::path::Path;
-
-fn get_window() -> Window {
-    let scale = 10;
-    let width = 64 * scale;
-    let height = 32 * scale;
-
-    Window::new(
-        "CHIP-8 Emulator",
-        width,
-        height,
-        WindowOptions {
-            resize: false,
-            scale: minifb::Scale::X1,
-            ..WindowOptions::default()
-        },
-    )
-    .unwrap()
+// --trace and the options that go with it, for playing and headless runs
+struct TraceOptions {
+    path: Option<String>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    // Only keep this many instructions, and write them out on a fault
+    last: Option<usize>,
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <rom_file>", args[0]);
-        std::process::exit(1);
+impl TraceOptions {
+    fn new() -> TraceOptions {
+        TraceOptions {
+            path: None,
+            format: TraceFormat::Text,
+            filter: TraceFilter::default(),
+            last: None,
+        }
     }
 
-    let filename = &args[1];
-    let program;
-    if filename.ends_with(".hex") {
-        program = HexRomLoader::read(Path::new(filename));
-    } else if filename.ends_with(".ch8") || filename.ends_with(".8o") {
-        program = Ch8RomLoader::read(Path::new(filename));
+    // Handle `arg` if it's a trace option, returning false if it isn't
+    fn parse(&mut self, arg: &str, iter: &mut std::slice::Iter<String>) -> Result<bool, String> {
+        if !arg.starts_with("--trace") {
+            return Ok(false);
+        }
+        let value = iter
+            .next()
+            .map(String::as_str)
+            .ok_or(format!("Missing value for {}", arg))?;
+        match arg {
+            "--trace" => self.path = Some(value.to_string()),
+            "--trace-format" => self.format = value.parse()?,
+            "--trace-pc" => self.filter.pcs = Some(TraceFilter::parse_pcs(value)?),
+            "--trace-ops" => self.filter.classes = Some(TraceFilter::parse_classes(value)?),
+            "--trace-frames" => self.filter.frames = Some(TraceFilter::parse_frames(value)?),
+            "--trace-last" => {
+                let last = value
+                    .parse()
+                    .map_err(|_| "--trace-last must be a number of instructions")?;
+                self.last = Some(last);
+            }
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+        Ok(true)
+    }
+
+    // The tracer asked for, if any. Without a file the instructions kept by
+    // --trace-last are written to stderr
+    fn tracer(&self) -> Result<Option<Tracer>, String> {
+        let out: Box<dyn Write> = match (&self.path, self.last) {
+            (Some(path), _) => Box::new(BufWriter::new(
+                File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+            (None, Some(_)) if self.format == TraceFormat::Text => Box::new(io::stderr()),
+            (None, Some(_)) => return Err("Binary traces need a --trace file".to_string()),
+            (None, None) => return Ok(None),
+        };
+        let mut tracer = Tracer::new(out, self.format);
+        tracer.set_filter(self.filter.clone());
+        if let Some(last) = self.last {
+            tracer.set_ring(last);
+        }
+        Ok(Some(tracer))
+    }
+
+    fn finish<D: Display>(&self, cpu: &mut CPU<D>) -> Result<(), String> {
+        match cpu.take_tracer() {
+            Some(tracer) => tracer
+                .finish()
+                .map_err(|e| format!("{}: {}", self.path.as_deref().unwrap_or("trace"), e)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The speeds stepped through by the speed hotkeys, in instructions per frame
+const SPEEDS: [u32; 15] = [1, 2, 3, 5, 7, 9, 11, 15, 20, 30, 50, 100, 200, 500, 1000];
+
+fn parse_quirks(name: &str) -> Result<Quirks, String> {
+    Quirks::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Quirks::PRESETS.iter().map(|(n, _)| *n).collect();
+        format!(
+            "Unknown quirks preset '{}', expected one of: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+// Instructions per frame from --ipf, or per second from --ips
+fn parse_speed(value: &str, per_second: bool) -> Result<u32, String> {
+    let speed: u32 = value
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or("Speed must be a positive number of instructions")?;
+    Ok(if per_second {
+        ((speed + FRAMERATE / 2) / FRAMERATE).max(1)
     } else {
-        eprintln!("Unsupported file type: {}", filename);
+        speed
+    })
+}
+
+fn parse_seed(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| "Seed must be a whole number".to_string())
+}
+
+// addr:byte in hex, e.g. 1FF:01
+fn parse_poke(value: &str) -> Result<(u16, u8), String> {
+    let invalid = || format!("Invalid poke '{}', expected addr:byte", value);
+    let (addr, byte) = value.split_once(':').ok_or_else(invalid)?;
+    let addr = debugger::parse_address(addr).map_err(|_| invalid())?;
+    let byte = u8::from_str_radix(byte, 16).map_err(|_| invalid())?;
+    Ok((addr, byte))
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut quirks = None;
+    let mut tone = ToneConfig::default();
+    let mut wav = None;
+    let mut debug = false;
+    let mut gdb = None;
+    let mut load_state = None;
+    let mut force_state = false;
+    let mut rewind = 10.0;
+    let mut cycles_per_frame = None;
+    let mut turbo = false;
+    let mut generator = Generator::Xorshift;
+    let mut seed = None;
+    let mut record = None;
+    let mut play = None;
+    let mut verify = false;
+    let mut keymap = None;
+    let mut layout = None;
+    // Builds without the window feature only have the terminal
+    let mut tty = !cfg!(feature = "window");
+    let mut glyphs = Glyphs::HalfBlock;
+    let mut trace = TraceOptions::new();
+    let mut filename = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if trace.parse(arg, &mut iter)? {
+            continue;
+        }
+        let mut value = || {
+            iter.next()
+                .map(String::as_str)
+                .ok_or(format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--quirks" => quirks = Some(parse_quirks(value()?)?),
+            "--tone" => {
+                tone.frequency = value()?
+                    .parse()
+                    .map_err(|_| "Tone frequency must be a number of Hz")?;
+            }
+            "--volume" => {
+                tone.volume = value()?
+                    .parse()
+                    .map_err(|_| "Volume must be a number from 0.0 to 1.0")?;
+            }
+            "--waveform" => tone.waveform = value()?.parse()?,
+            "--wav" => wav = Some(value()?.to_string()),
+            "--debug" => debug = true,
+            "--gdb" => {
+                let port = value()?.parse().map_err(|_| "GDB port must be a number")?;
+                gdb = Some(port);
+            }
+            "--load-state" => load_state = Some(value()?.to_string()),
+            "--force-state" => force_state = true,
+            "--rewind" => {
+                rewind = value()?
+                    .parse()
+                    .map_err(|_| "Rewind length must be a number of seconds")?;
+            }
+            "--ipf" => cycles_per_frame = Some(parse_speed(value()?, false)?),
+            "--ips" => cycles_per_frame = Some(parse_speed(value()?, true)?),
+            "--turbo" => turbo = true,
+            "--rng" => generator = value()?.parse()?,
+            "--seed" => seed = Some(parse_seed(value()?)?),
+            "--record" => record = Some(value()?.to_string()),
+            "--play" => play = Some(value()?.to_string()),
+            "--verify" => verify = true,
+            "--keymap" => keymap = Some(value()?.to_string()),
+            "--layout" => layout = Some(value()?.to_string()),
+            "--frontend" => {
+                tty = match value()? {
+                    "window" if cfg!(feature = "window") => false,
+                    "window" => {
+                        return Err("This build has no window frontend, use tty".to_string())
+                    }
+                    "tty" => true,
+                    other => {
+                        return Err(format!(
+                            "Unknown frontend '{}', expected window or tty",
+                            other
+                        ))
+                    }
+                }
+            }
+            "--glyphs" => glyphs = value()?.parse()?,
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    if debug && gdb.is_some() {
+        return Err("--debug and --gdb can't be used together".to_string());
+    }
+    // Movies start from power on and need every frame to run the same way
+    if record.is_some() && play.is_some() {
+        return Err("--record and --play can't be used together".to_string());
+    }
+    if (record.is_some() || play.is_some()) && (debug || gdb.is_some() || load_state.is_some()) {
+        return Err("Movies can't be used with --debug, --gdb or --load-state".to_string());
+    }
+    if verify && play.is_none() {
+        return Err("--verify needs a movie to --play".to_string());
+    }
+    // The debugger reads its commands from stdin, where the keys come from
+    if tty && debug {
+        return Err("--debug can't be used with the tty frontend".to_string());
+    }
+    Ok(Options {
+        filename: filename.ok_or("Missing ROM file")?,
+        quirks,
+        tone,
+        wav,
+        debug,
+        gdb,
+        load_state,
+        force_state,
+        rewind,
+        cycles_per_frame,
+        turbo,
+        generator,
+        seed,
+        record,
+        play,
+        verify,
+        keymap,
+        layout,
+        tty: tty.then_some(glyphs),
+        trace,
+    })
+}
+
+fn get_audio(options: &Options) -> Box<dyn Audio> {
+    if let Some(path) = &options.wav {
+        match WavAudio::create(Path::new(path), options.tone) {
+            Ok(audio) => return Box::new(audio),
+            Err(e) => eprintln!("Could not create {}: {}", path, e),
+        }
+    }
+    #[cfg(feature = "beeper")]
+    match BeeperAudio::new(options.tone) {
+        Ok(audio) => return Box::new(audio),
+        Err(e) => eprintln!("Sound disabled: {}", e),
+    }
+    Box::new(NullAudio::new())
+}
+
+fn load_rom(filename: &str) -> Result<Rom, String> {
+    let path = Path::new(filename);
+    let program = if filename.ends_with(".hex") {
+        HexRomLoader::read(path)
+    } else if filename.ends_with(".ch8") {
+        Ch8RomLoader::read(path)
+    } else if filename.ends_with(".8o") {
+        OctoRomLoader::read(path)
+    } else {
+        return Err(format!("Unsupported file type: {}", filename));
+    };
+    program.map_err(|e| match e {
+        // Parse errors start with the line and column
+        RomError::InvalidHex { .. } | RomError::Assembly(_) => format!("{}:{}", filename, e),
+        _ => format!("{}: {}", filename, e),
+    })
+}
+
+// Save state slots are kept next to the ROM, e.g. pong.ch8.state1
+fn state_path(rom: &str, slot: usize) -> String {
+    format!("{}.state{}", rom, slot)
+}
+
+fn load_state_file<D: Display>(cpu: &mut CPU<D>, path: &str, force: bool) -> Result<(), String> {
+    let state = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    cpu.load_state(&state, force)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+// A file in ~/.config/chip8_emu, or $XDG_CONFIG_HOME/chip8_emu, if it's there
+fn config_file(name: &str) -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    let path = config.join("chip8_emu").join(name);
+    path.exists().then_some(path)
+}
+
+// The keymap file given with --keymap, or the one in the config directory
+fn keymap_path(options: &Options) -> Option<PathBuf> {
+    match &options.keymap {
+        Some(path) => Some(PathBuf::from(path)),
+        None => config_file("keymap.json"),
+    }
+}
+
+// The bundled ROM database, with the entries in roms.json in the config
+// directory taking precedence
+fn load_rom_database() -> Result<RomDatabase, String> {
+    let mut database = RomDatabase::bundled();
+    if let Some(path) = config_file("roms.json") {
+        let json = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let local = RomDatabase::parse(&json).map_err(|e| format!("{}: {}", path.display(), e))?;
+        database.add_overrides(local);
+    }
+    Ok(database)
+}
+
+// The keymap from the config, plus the keys the ROM database suggests
+fn load_keymap(options: &Options, rom: &Rom, info: Option<&RomInfo>) -> Result<Keymap, String> {
+    let config = match keymap_path(options) {
+        Some(path) => {
+            let json =
+                fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            KeymapConfig::parse(&json).map_err(|e| format!("{}: {}", path.display(), e))?
+        }
+        None => KeymapConfig::default(),
+    };
+    let name = Path::new(&options.filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut keymap = config
+        .keymap(options.layout.as_deref(), &name, &rom.sha1())
+        .map_err(|e| e.to_string())?;
+    if let Some(info) = info {
+        keymap.add_keys(&info.keys);
+    }
+    Ok(keymap)
+}
+
+fn load_movie(path: &str) -> Result<Movie, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    Movie::from_bytes(&data).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Write out the movie being recorded, or check the one played back ended
+// where its recording did. A mismatch is only an error with `verify`
+fn finish_movie<D: Display>(
+    cpu: &mut CPU<D>,
+    record: Option<&str>,
+    verify: bool,
+) -> Result<(), String> {
+    let ended = cpu.movie_ended();
+    let Some(movie) = cpu.finish_movie() else {
+        return Ok(());
+    };
+    if let Some(path) = record {
+        fs::write(path, movie.to_bytes()).map_err(|e| format!("{}: {}", path, e))?;
+        println!("Recorded {} frames to {}", movie.frames.len(), path);
+        return Ok(());
+    }
+    let result = if ended {
+        movie.verify(cpu).map_err(|e| e.to_string())
+    } else {
+        Err("Playback stopped before the end of the movie".to_string())
+    };
+    match result {
+        Ok(()) => eprintln!("Playback matches the recording"),
+        Err(e) if verify => return Err(e),
+        Err(e) => eprintln!("{}", e),
+    }
+    Ok(())
+}
+
+// Run until the window is closed, handling the save state, rewind and
+// speed keys
+fn run<D: Frontend>(cpu: &mut CPU<D>, rom: &str) -> Result<(), Chip8Error> {
+    let frame_time = Duration::from_micros(1_000_000 / 60);
+    loop {
+        // Rewinding, loading states and changing speed would all break
+        // a movie being recorded or played back
+        let movie = cpu.has_movie();
+        let stop = cpu.run_until(|cpu| {
+            cpu.display().hotkey().is_some() || (cpu.display().is_rewinding() && !movie)
+        })?;
+        match stop {
+            StopReason::Paused => {}
+            StopReason::Exit | StopReason::Closed | StopReason::MovieEnd => return Ok(()),
+        }
+        // Go back a frame at a time for as long as the key is held
+        while cpu.display().is_rewinding() && cpu.display().is_open() && !movie {
+            cpu.rewind();
+            cpu.refresh_display();
+            thread::sleep(frame_time);
+        }
+        match cpu.display_mut().take_hotkey() {
+            Some(Hotkey::SaveState(slot)) => {
+                let path = state_path(rom, slot);
+                match fs::write(&path, cpu.save_state()) {
+                    Ok(()) => println!("Saved state to {}", path),
+                    Err(e) => eprintln!("Could not save {}: {}", path, e),
+                }
+            }
+            Some(Hotkey::LoadState(_) | Hotkey::Slower | Hotkey::Faster) if movie => {
+                eprintln!("Not available while recording or playing a movie");
+            }
+            Some(Hotkey::LoadState(slot)) => {
+                let path = state_path(rom, slot);
+                match load_state_file(cpu, &path, false) {
+                    Ok(()) => println!("Loaded state from {}", path),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            Some(hotkey @ (Hotkey::Slower | Hotkey::Faster)) => {
+                let speed = cpu.cycles_per_frame;
+                cpu.cycles_per_frame = if hotkey == Hotkey::Faster {
+                    SPEEDS.into_iter().find(|&s| s > speed).unwrap_or(speed)
+                } else {
+                    SPEEDS
+                        .into_iter()
+                        .rev()
+                        .find(|&s| s < speed)
+                        .unwrap_or(speed)
+                };
+                println!(
+                    "Speed: {} instructions per frame ({} per second)",
+                    cpu.cycles_per_frame,
+                    cpu.cycles_per_frame * FRAMERATE
+                );
+            }
+            Some(Hotkey::Turbo) => {
+                cpu.turbo = !cpu.turbo;
+                println!("Turbo {}", if cpu.turbo { "on" } else { "off" });
+            }
+            None => {}
+        }
+    }
+}
+
+// disasm [--syntax <octo|cowgod>] <rom_file>
+fn disasm(args: &[String]) -> Result<(), String> {
+    let mut syntax = Syntax::Octo;
+    let mut filename = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = iter.next().ok_or("Missing value for --syntax")?.parse()?;
+            }
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    let filename = filename.ok_or("Missing ROM file")?;
+    let rom = load_rom(filename)?;
+    let lines = disasm::disassemble(&rom.data, syntax);
+    print!("{}", disasm::format_listing(&lines, syntax));
+    Ok(())
+}
+
+// trace-dump [--syntax <octo|cowgod>] <trace_file>
+fn trace_dump(args: &[String]) -> Result<(), String> {
+    let mut syntax = Syntax::Octo;
+    let mut filename = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = iter.next().ok_or("Missing value for --syntax")?.parse()?;
+            }
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    let filename = filename.ok_or("Missing trace file")?;
+    let data = fs::read(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    let records = trace::decode(&data).map_err(|e| format!("{}: {}", filename, e))?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    for record in &records {
+        writeln!(out, "{}", trace::format_record(record, syntax)).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+// headless [--quirks <preset>] [--ipf <n> | --ips <n>]
+//          [--rng <xorshift|vip>] [--seed <n>]
+//          [--frames <n> | --cycles <n>] [--play <movie> [--verify]]
+//          [--press <frame:key[:frames]>]... [--poke <addr:byte>]...
+//          [--output <file>] [--trace <file> ...] <rom_file>
+fn headless(args: &[String]) -> Result<(), String> {
+    let mut quirks = Quirks::default();
+    let mut cycles_per_frame = CYCLES_PER_FRAME;
+    // Fixed by default so runs can be compared
+    let mut generator = Generator::Xorshift;
+    let mut seed = 0;
+    let mut limit = None;
+    let mut play = None;
+    let mut verify = false;
+    let mut presses: Vec<KeyPress> = Vec::new();
+    let mut pokes = Vec::new();
+    let mut output = None;
+    let mut trace = TraceOptions::new();
+    let mut filename = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if trace.parse(arg, &mut iter)? {
+            continue;
+        }
+        let mut value = || {
+            iter.next()
+                .map(String::as_str)
+                .ok_or(format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--quirks" => quirks = parse_quirks(value()?)?,
+            "--ipf" => cycles_per_frame = parse_speed(value()?, false)?,
+            "--ips" => cycles_per_frame = parse_speed(value()?, true)?,
+            "--rng" => generator = value()?.parse()?,
+            "--seed" => seed = parse_seed(value()?)?,
+            "--frames" => {
+                let frames = value()?.parse().map_err(|_| "Frames must be a number")?;
+                limit = Some(Limit::Frames(frames));
+            }
+            "--cycles" => {
+                let cycles = value()?.parse().map_err(|_| "Cycles must be a number")?;
+                limit = Some(Limit::Cycles(cycles));
+            }
+            "--play" => play = Some(value()?.to_string()),
+            "--verify" => verify = true,
+            "--press" => presses.push(value()?.parse()?),
+            "--poke" => pokes.push(parse_poke(value()?)?),
+            "--output" => output = Some(value()?.to_string()),
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    let filename = filename.ok_or("Missing ROM file")?;
+    if verify && play.is_none() {
+        return Err("--verify needs a movie to --play".to_string());
+    }
+    let rom = load_rom(filename)?;
+
+    let mut cpu = CPU::new(NullDisplay::new(), quirks);
+    cpu.cycles_per_frame = cycles_per_frame;
+    cpu.random = generator.with_seed(seed);
+    cpu.initialize();
+    cpu.load(rom.data)
+        .map_err(|e| format!("{}: {}", filename, e))?;
+    // Written after loading, so ROMs can be told what to do without a menu
+    for (addr, byte) in pokes {
+        let cell = cpu.memory.get_mut(addr as usize);
+        *cell.ok_or(format!("Can't poke 0x{:X}, past the end of memory", addr))? = byte;
+    }
+    if let Some(path) = &play {
+        cpu.play_movie(load_movie(path)?)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    // Movies play to the end unless told otherwise
+    let limit = limit.unwrap_or(match play {
+        Some(_) => Limit::Frames(u64::MAX),
+        None => Limit::Frames(60),
+    });
+    cpu.set_tracer(trace.tracer()?);
+    let result = headless::run(&mut cpu, limit, &presses);
+    trace.finish(&mut cpu)?;
+    finish_movie(&mut cpu, None, verify)?;
+    result.map_err(|e| format!("Error: {}", e))?;
+
+    let (width, height) = (cpu.screen_width(), cpu.screen_height());
+    match output {
+        Some(path) => {
+            let format = ImageFormat::from_path(Path::new(&path));
+            let mut w =
+                BufWriter::new(File::create(&path).map_err(|e| format!("{}: {}", path, e))?);
+            screen::write_image(&mut w, &cpu.gfx, width, height, format)
+                .and_then(|_| w.flush())
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        None => screen::write_image(
+            io::stdout().lock(),
+            &cpu.gfx,
+            width,
+            height,
+            ImageFormat::Ascii,
+        )
+        .map_err(|e| e.to_string()),
+    }
+}
+
+// trace-diff [--quirks <preset>] [--ipf <n> | --ips <n>]
+//            [--rng <xorshift|vip>] [--seed <n>] [--frames <n> | --cycles <n>]
+//            [--press <frame:key[:frames]>]... [--context <n>] <rom_file> <reference_trace>
+fn trace_diff(args: &[String]) -> Result<(), String> {
+    let mut quirks = Quirks::default();
+    let mut cycles_per_frame = CYCLES_PER_FRAME;
+    let mut generator = Generator::Xorshift;
+    let mut seed = 0;
+    // Ten minutes, in case the program ends up waiting for a key
+    let mut limit = Limit::Frames(10 * 60 * FRAMERATE as u64);
+    let mut presses: Vec<KeyPress> = Vec::new();
+    let mut context = 5;
+    let mut files = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .map(String::as_str)
+                .ok_or(format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--quirks" => quirks = parse_quirks(value()?)?,
+            "--ipf" => cycles_per_frame = parse_speed(value()?, false)?,
+            "--ips" => cycles_per_frame = parse_speed(value()?, true)?,
+            "--rng" => generator = value()?.parse()?,
+            "--seed" => seed = parse_seed(value()?)?,
+            "--frames" => {
+                limit = Limit::Frames(value()?.parse().map_err(|_| "Frames must be a number")?);
+            }
+            "--cycles" => {
+                limit = Limit::Cycles(value()?.parse().map_err(|_| "Cycles must be a number")?);
+            }
+            "--press" => presses.push(value()?.parse()?),
+            "--context" => {
+                context = value()?
+                    .parse()
+                    .map_err(|_| "Context must be a number of instructions")?;
+            }
+            _ if files.len() < 2 && !arg.starts_with("--") => files.push(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    let [filename, reference_path] = files[..] else {
+        return Err("Missing ROM or reference trace file".to_string());
+    };
+    let rom = load_rom(filename)?;
+    let text =
+        fs::read_to_string(reference_path).map_err(|e| format!("{}: {}", reference_path, e))?;
+    let reference =
+        tracediff::parse_reference(&text).map_err(|e| format!("{}:{}", reference_path, e))?;
+
+    let mut cpu = CPU::new(NullDisplay::new(), quirks);
+    cpu.cycles_per_frame = cycles_per_frame;
+    cpu.random = generator.with_seed(seed);
+    cpu.initialize();
+    cpu.load(rom.data)
+        .map_err(|e| format!("{}: {}", filename, e))?;
+    match tracediff::diff(&mut cpu, limit, &presses, &reference, context) {
+        DiffResult::Matched(n) => {
+            println!("All {} instructions match", n);
+            Ok(())
+        }
+        DiffResult::Diverged(divergence) => {
+            print!("{}", tracediff::report(&divergence, &reference));
+            std::process::exit(1);
+        }
+        DiffResult::Ended(n, error) => {
+            println!(
+                "Run stopped after {} matching instructions, {} short of the reference",
+                n,
+                reference.len() - n
+            );
+            if let Some(e) = error {
+                println!("Error: {}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// dap [--layout <qwerty|azerty|dvorak|numpad>]
+// Serve the Debug Adapter Protocol on stdin and stdout. The ROM is given by
+// the client's launch request
+fn dap(args: &[String]) -> Result<(), String> {
+    let mut keymap = Keymap::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--layout" => {
+                let layout = iter.next().ok_or("Missing value for --layout")?;
+                keymap = Keymap::layout(layout).ok_or(format!("Unknown layout '{}'", layout))?;
+            }
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    #[cfg(feature = "window")]
+    let display = {
+        let keys = window::host_keys(&keymap)?;
+        WindowDisplay::new("CHIP-8 Emulator", keys)
+            .map_err(|e| format!("Could not open window: {}", e))?
+    };
+    // Without a window the program runs unseen, with no keys pressed
+    #[cfg(not(feature = "window"))]
+    let display = {
+        let _ = keymap;
+        NullDisplay::new()
+    };
+    DapServer::stdio(display)
+        .run()
+        .map_err(|e| format!("Debug adapter: {}", e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let subcommand = match args.get(1).map(String::as_str) {
+        Some("dap") => Some((
+            dap as fn(&[String]) -> Result<(), String>,
+            "dap [--layout <qwerty|azerty|dvorak|numpad>]",
+        )),
+        Some("disasm") => Some((
+            disasm as fn(&[String]) -> Result<(), String>,
+            "disasm [--syntax <octo|cowgod>] <rom_file>",
+        )),
+        Some("headless") => Some((
+            headless as fn(&[String]) -> Result<(), String>,
+            "headless [--quirks <preset>] [--ipf <n> | --ips <n>] \
+             [--rng <xorshift|vip>] [--seed <n>] [--frames <n> | --cycles <n>] [--play <movie> [--verify]] \
+             [--press <frame:key[:frames]>]... [--poke <addr:byte>]... \
+             [--output <file.png|file.pbm|file.txt>] \
+             [--trace <file>] [--trace-format <text|binary>] [--trace-pc <start-end>] \
+             [--trace-ops <0-F,...>] [--trace-frames <start-end>] [--trace-last <n>] <rom_file>",
+        )),
+        Some("trace-diff") => Some((
+            trace_diff as fn(&[String]) -> Result<(), String>,
+            "trace-diff [--quirks <preset>] [--ipf <n> | --ips <n>] \
+             [--rng <xorshift|vip>] [--seed <n>] [--frames <n> | --cycles <n>] [--press <frame:key[:frames]>]... \
+             [--context <n>] <rom_file> <reference_trace>",
+        )),
+        Some("trace-dump") => Some((
+            trace_dump as fn(&[String]) -> Result<(), String>,
+            "trace-dump [--syntax <octo|cowgod>] <trace_file>",
+        )),
+        _ => None,
+    };
+    if let Some((command, usage)) = subcommand {
+        if let Err(e) = command(&args[2..]) {
+            eprintln!("{}", e);
+            eprintln!("Usage: {} {}", args[0], usage);
+            std::process::exit(1);
+        }
         return;
     }
-    let window = get_window();
-    let display = MiniFBDisplay::new(window);
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: {} [--quirks <preset>] [--tone <hz>] [--volume <0-1>] \
+                 [--waveform <square|triangle|sawtooth|sine>] [--wav <file>] [--debug | --gdb <port>] \
+                 [--load-state <file> [--force-state]] [--rewind <seconds>] \
+                 [--ipf <n> | --ips <n>] [--turbo] [--rng <xorshift|vip>] [--seed <n>] \
+                 [--record <movie> | --play <movie> [--verify]] \
+                 [--keymap <file>] [--layout <qwerty|azerty|dvorak|numpad>] \
+                 [--frontend <window|tty>] [--glyphs <halfblock|braille>] \
+                 [trace options] <rom_file>",
+                args[0]
+            );
+            eprintln!(
+                "Trace options: [--trace <file>] [--trace-format <text|binary>] \
+                 [--trace-pc <start-end>] [--trace-ops <0-F,...>] \
+                 [--trace-frames <start-end>] [--trace-last <n>]"
+            );
+            eprintln!("       {} dap ...", args[0]);
+            eprintln!("       {} disasm ...", args[0]);
+            eprintln!("       {} headless ...", args[0]);
+            eprintln!("       {} trace-diff ...", args[0]);
+            eprintln!("       {} trace-dump ...", args[0]);
+            std::process::exit(1);
+        }
+    };
+
+    let rom = match load_rom(&options.filename) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let info = match load_rom_database() {
+        Ok(database) => database.lookup(&rom.sha1()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(info) = &info {
+        if info.authors.is_empty() {
+            println!("{}", info.title);
+        } else {
+            println!("{} by {}", info.title, info.authors.join(", "));
+        }
+    }
+    let keymap = match load_keymap(&options, &rom, info.as_ref()) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Settings given on the command line win over the ROM database's
+    let quirks = options
+        .quirks
+        .or(info.as_ref().and_then(|info| info.quirks))
+        .unwrap_or_default();
+    let cycles_per_frame = options
+        .cycles_per_frame
+        .or(info.as_ref().and_then(|info| info.cycles_per_frame))
+        .unwrap_or(CYCLES_PER_FRAME);
+    let result = match options.tty {
+        Some(glyphs) => tty::host_keys(&keymap).and_then(|keys| {
+            let display = TtyDisplay::new(glyphs, keys)
+                .map_err(|e| format!("Could not set up the terminal: {}", e))?;
+            play(display, &options, rom, quirks, cycles_per_frame)
+        }),
+        #[cfg(feature = "window")]
+        None => window::host_keys(&keymap).and_then(|keys| {
+            let mut display = WindowDisplay::new("CHIP-8 Emulator", keys)
+                .map_err(|e| format!("Could not open window: {}", e))?;
+            if let Some(palette) = info.as_ref().and_then(|info| info.palette) {
+                display.set_palette(palette);
+            }
+            play(display, &options, rom, quirks, cycles_per_frame)
+        }),
+        #[cfg(not(feature = "window"))]
+        None => unreachable!("the tty frontend is the only one without the window feature"),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut cpu = cpu::CPU::new(display);
+// Set up the machine and play in `display` until it's closed. Errors are
+// returned rather than exiting, so the display is dropped and puts the
+// terminal back first
+fn play<D: Frontend>(
+    display: D,
+    options: &Options,
+    rom: Rom,
+    quirks: Quirks,
+    cycles_per_frame: u32,
+) -> Result<(), String> {
+    let mut cpu = CPU::new(display, quirks);
+    cpu.set_audio(get_audio(options));
+    cpu.set_rewind(options.rewind);
+    cpu.cycles_per_frame = cycles_per_frame;
+    cpu.turbo = options.turbo;
+    let seed = options.seed.unwrap_or_else(rand::random);
+    cpu.random = options.generator.with_seed(seed);
     cpu.initialize();
-    cpu.load(program);
-    cpu.run();
+    cpu.load(rom.data)
+        .map_err(|e| format!("{}: {}", options.filename, e))?;
+    if let Some(path) = &options.load_state {
+        load_state_file(&mut cpu, path, options.force_state)?;
+    }
+    if options.record.is_some() {
+        cpu.record_movie(options.generator, seed);
+    }
+    if let Some(path) = &options.play {
+        let movie = load_movie(path)?;
+        cpu.play_movie(movie)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    cpu.set_tracer(options.trace.tracer()?);
+    if options.debug {
+        Debugger::new().run(&mut cpu);
+        return options.trace.finish(&mut cpu);
+    }
+    if let Some(port) = options.gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|e| format!("Could not listen on port {}: {}", port, e))?;
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        GdbStub::new(stream)
+            .and_then(|mut stub| stub.run(&mut cpu))
+            .map_err(|e| format!("GDB connection: {}", e))?;
+        return options.trace.finish(&mut cpu);
+    }
+    let result = run(&mut cpu, &options.filename);
+    options.trace.finish(&mut cpu)?;
+    finish_movie(&mut cpu, options.record.as_deref(), options.verify)?;
+    result.map_err(|e| format!("Error: {}", e))
 }
@@ -0,0 +1,200 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::cpu::{BIG_FONTSET_START, CPU, FONTSET_START, PROGRAM_START};
+use crate::display::Display;
+use crate::fontset::{BIG_FONTSET, FONTSET};
+use crate::tty::{self, Glyphs};
+
+/*
+   Memory views for the debugger
+   * Hex dumps have 8 bytes a row, followed by the bytes as ASCII and a
+     preview of them as sprite rows, 4 rows to each braille cell
+   * Rows are labelled with the regions they overlap: the small and big
+     fonts, and the loaded program
+   * Bytes are marked with > at PC, * at I and ^ at each return address on
+     the stack, since the stack itself isn't in memory
+*/
+
+const ROW: usize = 8;
+
+// Named areas of memory, which may be empty
+pub fn regions<D: Display>(cpu: &CPU<D>) -> [(&'static str, Range<usize>); 3] {
+    let program = PROGRAM_START as usize;
+    [
+        ("font", FONTSET_START..FONTSET_START + FONTSET.len()),
+        (
+            "big font",
+            BIG_FONTSET_START..BIG_FONTSET_START + BIG_FONTSET.len(),
+        ),
+        ("program", program..program + cpu.rom_size),
+    ]
+}
+
+fn marker<D: Display>(cpu: &CPU<D>, addr: usize) -> char {
+    let returns = &cpu.stack[..cpu.sp as usize];
+    if addr == cpu.pc as usize {
+        '>'
+    } else if addr == cpu.i as usize {
+        '*'
+    } else if returns.iter().any(|&r| r as usize == addr) {
+        '^'
+    } else {
+        ' '
+    }
+}
+
+// `len` bytes from `start`, cut short at the end of memory
+pub fn hex_dump<D: Display>(cpu: &CPU<D>, start: usize, len: usize) -> String {
+    let end = start.saturating_add(len).min(cpu.memory.len());
+    let regions = regions(cpu);
+    let mut out = String::new();
+    for row in (start..end).step_by(ROW) {
+        let bytes = &cpu.memory[row..(row + ROW).min(end)];
+        let names: Vec<&str> = regions
+            .iter()
+            .filter(|(_, range)| range.start < row + bytes.len() && row < range.end)
+            .map(|&(name, _)| name)
+            .collect();
+        write!(out, "0x{:03X} {:<16}", row, names.join(", ")).unwrap();
+        for (n, byte) in bytes.iter().enumerate() {
+            write!(out, "{}{:02X}", marker(cpu, row + n), byte).unwrap();
+        }
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7F).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let padding = 3 * (ROW - bytes.len());
+        write!(out, "{:padding$}  |{:<ROW$}| ", "", ascii).unwrap();
+        for rows in bytes.chunks(4) {
+            write!(out, " {}", sprite_preview(rows)).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Up to 4 sprite rows as a line of braille cells
+fn sprite_preview(rows: &[u8]) -> String {
+    let pixels: Vec<u8> = rows
+        .iter()
+        .flat_map(|&byte| (0..8).map(move |bit| byte & (0x80 >> bit)))
+        .collect();
+    let (cells, _) = tty::render(Glyphs::Braille, &pixels, 8, rows.len());
+    cells.into_iter().collect()
+}
+
+// Each byte as a row of an 8 pixel wide sprite
+pub fn sprites(memory: &[u8], start: usize, len: usize) -> String {
+    let end = start.saturating_add(len).min(memory.len());
+    let mut out = String::new();
+    for (n, &byte) in memory[start.min(end)..end].iter().enumerate() {
+        let pixels: String = (0..8)
+            .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+            .collect();
+        writeln!(out, "0x{:03X}  {:02X}  {}", start + n, byte, pixels).unwrap();
+    }
+    out
+}
+
+// Every address where `pattern` matches, with None matching any byte
+pub fn search(memory: &[u8], pattern: &[Option<u8>]) -> Vec<usize> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+    memory
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| {
+            window
+                .iter()
+                .zip(pattern)
+                .all(|(&byte, expected)| expected.is_none_or(|e| e == byte))
+        })
+        .map(|(addr, _)| addr)
+        .collect()
+}
+
+// Hex bytes, as separate words or run together, e.g. "F0 90" or "0xF090",
+// with ?? for any byte when `wildcards` is set
+pub fn parse_bytes(words: &[&str], wildcards: bool) -> Result<Vec<Option<u8>>, String> {
+    let mut bytes = Vec::new();
+    for word in words {
+        let digits = word.trim_start_matches("0x").trim_start_matches("0X");
+        if digits.is_empty() || digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(format!("Invalid bytes '{}'", word));
+        }
+        for pair in digits.as_bytes().chunks(2) {
+            let pair = std::str::from_utf8(pair).unwrap();
+            if pair == "??" && wildcards {
+                bytes.push(None);
+            } else {
+                let byte = u8::from_str_radix(pair, 16)
+                    .map_err(|_| format!("Invalid bytes '{}'", word))?;
+                bytes.push(Some(byte));
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn test_hex_dump() {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        cpu.load(b"\x22\x06AB\x12\x04\xFF\x81\x00\xEE".to_vec())
+            .unwrap();
+        cpu.i = 0x206;
+        cpu.stack[0] = 0x202;
+        cpu.sp = 1;
+        let dump = hex_dump(&cpu, 0x200, 10);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "0x200 program         >22 06^41 42 12 04*FF 81  |\".AB....|  ⢠⠁⠐⡣ ⡤⠬⠴⢥",
+                "0x208 program          00 EE                    |..      |  ⠒⠂⠒⠂",
+            ]
+        );
+        // 0x9C-0xA3 ends the small font and starts the big one
+        assert!(hex_dump(&cpu, 0x9C, 8).starts_with("0x09C font, big font  "));
+        // Cut short at the end of memory
+        assert_eq!(hex_dump(&cpu, 0xFFC, 8).lines().count(), 1);
+        assert_eq!(hex_dump(&cpu, 0x1000, 8), "");
+    }
+
+    #[test]
+    fn test_sprites() {
+        let memory = [0xF0, 0x90, 0x81];
+        assert_eq!(
+            sprites(&memory, 1, 4),
+            "0x001  90  #..#....\n0x002  81  #......#\n"
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let pattern = parse_bytes(&["F0", "??90"], true).unwrap();
+        assert_eq!(pattern, [Some(0xF0), None, Some(0x90)]);
+        let memory = [0xF0, 0x90, 0x90, 0xF0, 0x10, 0x90];
+        assert_eq!(search(&memory, &pattern), [0, 3]);
+        assert_eq!(search(&memory, &[]), Vec::<usize>::new());
+
+        assert_eq!(parse_bytes(&["0xA2"], false), Ok(vec![Some(0xA2)]));
+        assert!(parse_bytes(&["??"], false).is_err());
+        assert!(parse_bytes(&["F"], true).is_err());
+        assert!(parse_bytes(&["G0"], true).is_err());
+    }
+}
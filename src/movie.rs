@@ -0,0 +1,258 @@
+use crate::display::Display;
+
+use crate::cpu::CPU;
+use crate::error::MovieError;
+use crate::quirks::Quirks;
+use crate::random::Generator;
+use crate::savestate::{quirks_from_bits, quirks_to_bits, SUPER_CHIP_BIT};
+
+// Movie layout, numbers are big endian:
+//   "C8MV", version, SHA-1 of the ROM, quirks as a bitmask (u16 from
+//   version 3, u8 before)
+//   instructions per frame (u32), random seed (u64)
+//   random number generator (u8, from version 2)
+//   SHA-1 of the save state at the end of the recording
+//   the keypad for each frame, as runs of (keys, frames) pairs of u16s
+//   with bit n of keys set while key n is down
+const MAGIC: &[u8; 4] = b"C8MV";
+const VERSION: u8 = 3;
+
+// Everything needed to replay a run exactly: the machine it started on
+// and the keys held down in every frame since
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: [u8; 20],
+    pub quirks: Quirks,
+    pub cycles_per_frame: u32,
+    pub generator: Generator,
+    pub seed: u64,
+    pub final_hash: [u8; 20],
+    pub frames: Vec<u16>,
+}
+
+// A movie being recorded, or played back with the index of the next frame
+#[derive(Debug)]
+pub enum MovieState {
+    Recording(Movie),
+    Playing(Movie, usize),
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.rom_hash);
+        out.extend_from_slice(&quirks_to_bits(&self.quirks).to_be_bytes());
+        out.extend_from_slice(&self.cycles_per_frame.to_be_bytes());
+        out.extend_from_slice(&self.seed.to_be_bytes());
+        out.push(self.generator.to_byte());
+        out.extend_from_slice(&self.final_hash);
+        let mut frames = self.frames.iter().peekable();
+        while let Some(&keys) = frames.next() {
+            let mut run: u16 = 1;
+            while run < u16::MAX && frames.next_if_eq(&&keys).is_some() {
+                run += 1;
+            }
+            out.extend_from_slice(&keys.to_be_bytes());
+            out.extend_from_slice(&run.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let (header, rest) = data.split_at_checked(MAGIC.len()).unwrap_or((data, &[]));
+        if header != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let (&version, rest) = rest.split_first().ok_or(MovieError::Corrupt)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let (hash, rest) = rest.split_at_checked(20).ok_or(MovieError::Corrupt)?;
+        // Before version 3 the quirks fit in a u8, and the SUPER-CHIP
+        // opcodes always ran
+        let (quirks, rest) = if version >= 3 {
+            let (bits, rest) = rest.split_at_checked(2).ok_or(MovieError::Corrupt)?;
+            (u16::from_be_bytes(bits.try_into().unwrap()), rest)
+        } else {
+            let (&bits, rest) = rest.split_first().ok_or(MovieError::Corrupt)?;
+            (bits as u16 | SUPER_CHIP_BIT, rest)
+        };
+        // Version 1 movies were all made with xorshift
+        let (header, rest) = rest.split_at_checked(12).ok_or(MovieError::Corrupt)?;
+        let (generator, rest) = if version >= 2 {
+            let (&byte, rest) = rest.split_first().ok_or(MovieError::Corrupt)?;
+            (Generator::from_byte(byte).ok_or(MovieError::Corrupt)?, rest)
+        } else {
+            (Generator::Xorshift, rest)
+        };
+        let (final_hash, runs) = rest.split_at_checked(20).ok_or(MovieError::Corrupt)?;
+        if runs.len() % 4 != 0 {
+            return Err(MovieError::Corrupt);
+        }
+        let mut frames = Vec::new();
+        for run in runs.chunks(4) {
+            let keys = u16::from_be_bytes([run[0], run[1]]);
+            let length = u16::from_be_bytes([run[2], run[3]]);
+            frames.extend(std::iter::repeat_n(keys, length as usize));
+        }
+        Ok(Movie {
+            rom_hash: hash.try_into().unwrap(),
+            quirks: quirks_from_bits(quirks),
+            cycles_per_frame: u32::from_be_bytes(header[0..4].try_into().unwrap()),
+            generator,
+            seed: u64::from_be_bytes(header[4..12].try_into().unwrap()),
+            final_hash: final_hash.try_into().unwrap(),
+            frames,
+        })
+    }
+
+    // Check that a machine that has played this movie back ended up where
+    // the recording did
+    pub fn verify<D: Display>(&self, cpu: &CPU<D>) -> Result<(), MovieError> {
+        if state_hash(cpu) == self.final_hash {
+            Ok(())
+        } else {
+            Err(MovieError::Diverged)
+        }
+    }
+}
+
+pub fn state_hash<D: Display>(cpu: &CPU<D>) -> [u8; 20] {
+    sha1_smol::Sha1::from(cpu.save_state()).digest().bytes()
+}
+
+pub fn keys_to_bits(keys: &[u8; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .fold(0, |bits, (n, &key)| bits | ((key != 0) as u16) << n)
+}
+
+pub fn keys_from_bits(bits: u16) -> [u8; 16] {
+    std::array::from_fn(|n| (bits >> n & 1) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+    use crate::headless::{self, Limit};
+
+    fn setup() -> CPU<NullDisplay> {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::MODERN);
+        cpu.initialize();
+        cpu.load(vec![0x12, 0x00]).unwrap();
+        cpu
+    }
+
+    fn movie(cpu: &CPU<NullDisplay>, frames: Vec<u16>) -> Movie {
+        Movie {
+            rom_hash: cpu.rom_hash,
+            quirks: Quirks::XO_CHIP,
+            cycles_per_frame: 30,
+            generator: Generator::Vip,
+            seed: 1234,
+            final_hash: [0; 20],
+            frames,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cpu = setup();
+        let mut frames = vec![0; 70000];
+        frames[5] = 0x8001;
+        frames.push(0x0002);
+        let movie = movie(&cpu, frames);
+        let bytes = movie.to_bytes();
+        // Long runs of the same keys pack down small
+        assert!(bytes.len() < 100);
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie));
+
+        assert_eq!(Movie::from_bytes(b"C8"), Err(MovieError::NotAMovie));
+        let mut bad = bytes.clone();
+        bad[39] = 9;
+        assert_eq!(Movie::from_bytes(&bad), Err(MovieError::Corrupt));
+        assert_eq!(
+            Movie::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(MovieError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn test_version_1() {
+        let cpu = setup();
+        let mut movie = movie(&cpu, vec![1, 1, 2]);
+        let mut bytes = movie.to_bytes();
+        // Version 1 had a u8 of quirks and no generator, which follows
+        // the seed
+        bytes[4] = 1;
+        bytes.remove(25);
+        bytes.remove(38);
+        movie.generator = Generator::Xorshift;
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie));
+    }
+
+    #[test]
+    fn test_version_2() {
+        let cpu = setup();
+        let mut movie = movie(&cpu, vec![1, 1, 2]);
+        movie.quirks = Quirks::COSMAC_VIP;
+        let mut bytes = movie.to_bytes();
+        // Version 2 had a u8 of quirks, and ran the SUPER-CHIP opcodes
+        // whatever they were
+        bytes[4] = 2;
+        bytes.remove(25);
+        movie.quirks.super_chip = true;
+        assert_eq!(Movie::from_bytes(&bytes), Ok(movie));
+    }
+
+    #[test]
+    fn test_playback() {
+        // Count the instructions run with key 3 down in v5 while drawing
+        // random pixels, with the delay timer counting the frames
+        let prog = vec![
+            0x60, 0xFF, 0xF0, 0x15, 0x63, 0x03, 0xE3, 0xA1, 0x75, 0x01, 0xC0, 0xFF, 0xA3, 0x00,
+            0xF0, 0x55, 0xD0, 0x01, 0x12, 0x06,
+        ];
+        let play = |frames: &[u16], final_hash| {
+            let mut cpu = CPU::new(NullDisplay::new(), Quirks::MODERN);
+            cpu.initialize();
+            cpu.load(prog.clone()).unwrap();
+            let mut movie = movie(&cpu, frames.to_vec());
+            movie.final_hash = final_hash;
+            cpu.play_movie(movie).unwrap();
+            headless::run(&mut cpu, Limit::Frames(1000), &[]).unwrap();
+            // Stopped at the end of the movie rather than running 1000 frames
+            assert_eq!(cpu.delay_timer, 0xFF - frames.len() as u8);
+            assert!(cpu.v[5] > 0);
+            let movie = cpu.finish_movie().unwrap();
+            (state_hash(&cpu), movie.verify(&cpu))
+        };
+        let frames = [0, 0, 1 << 3, 1 << 3, 0, 1 << 3, 0];
+        let (hash, _) = play(&frames, [0; 20]);
+        assert_eq!(play(&frames, hash), (hash, Ok(())));
+        let mut changed = frames;
+        changed[4] = 1 << 3;
+        assert_eq!(play(&changed, hash).1, Err(MovieError::Diverged));
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let cpu = setup();
+        let movie = movie(&cpu, vec![0; 10]);
+        let mut other = CPU::new(NullDisplay::new(), Quirks::MODERN);
+        other.load(vec![0x12, 0x02]).unwrap();
+        assert_eq!(other.play_movie(movie), Err(MovieError::RomMismatch));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut keys = [0; 16];
+        keys[0] = 1;
+        keys[0xF] = 1;
+        assert_eq!(keys_to_bits(&keys), 0x8001);
+        assert_eq!(keys_from_bits(0x8001), keys);
+    }
+}
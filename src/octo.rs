@@ -0,0 +1,1074 @@
+// Assembler for Octo (https://github.com/JohnEarnest/Octo) source files
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+const PROGRAM_START: u16 = 0x200;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AssemblyError {}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+    // The line the token's code is credited to in the source map: where
+    // the macro it came from was used, otherwise `line`
+    origin: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Into<String>) -> AssemblyError {
+        AssemblyError {
+            line: self.line,
+            column: self.column,
+            message: message.into(),
+        }
+    }
+}
+
+// Split source into whitespace separated tokens, dropping # comments
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (n, line) in source.lines().enumerate() {
+        let mut chars = line.chars().enumerate().peekable();
+        while let Some(&(start, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let mut text = String::new();
+            while let Some(&(_, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                text.push(c);
+                chars.next();
+            }
+            tokens.push_back(Token {
+                text,
+                line: n + 1,
+                column: start + 1,
+                origin: n + 1,
+            });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+// Places in the ROM waiting for a label defined further down
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // Low 12 bits of the opcode at pos
+    Address(usize),
+    // The 16 bit word after F000
+    Long(usize),
+    // The NN bytes of the v0 := and v1 := emitted by :unpack
+    Unpack { hi: usize, lo: usize, nibble: u8 },
+}
+
+// Open control structures, with the positions of jumps still to be patched
+#[derive(Debug)]
+enum Flow {
+    If {
+        jump: usize,
+        token: Token,
+    },
+    Else {
+        jump: usize,
+        token: Token,
+    },
+    Loop {
+        start: u16,
+        whiles: Vec<usize>,
+        token: Token,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+// A condition compiles to setup instructions followed by a skip, which skips
+// the next instruction when the condition is false
+struct Condition {
+    setup: Vec<u16>,
+    skip: u16,
+}
+
+// A jump to `target`, for the blocks of control flow ending at `token`
+fn jump_op(target: u16, token: &Token) -> Result<u16, AssemblyError> {
+    if target > 0xFFF {
+        return Err(token.error(format!("Address {} does not fit in 12 bits", target)));
+    }
+    Ok(0x1000 | target)
+}
+
+// The skip for the opposite condition
+fn invert_skip(skip: u16) -> u16 {
+    match skip & 0xF000 {
+        0x3000 => skip + 0x1000,
+        0x4000 => skip - 0x1000,
+        0x5000 => skip + 0x4000,
+        0x9000 => skip - 0x4000,
+        _ if skip & 0xFF == 0x9E => skip + (0xA1 - 0x9E),
+        _ => skip - (0xA1 - 0x9E),
+    }
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    // Last token read, for errors at the end of the file
+    last: Token,
+    rom: Vec<u8>,
+    here: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(Fixup, Token)>,
+    flow: Vec<Flow>,
+    // Source line of the statement being assembled, 0 for none
+    line: usize,
+    source_map: SourceMap,
+}
+
+// Source lines of the instructions in an assembled program
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    lines: BTreeMap<u16, usize>,
+}
+
+impl SourceMap {
+    // The line of the instruction at `addr`
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.lines.get(&addr).copied()
+    }
+
+    // The first instruction on `line`, or on the next line with code, as
+    // (address, line)
+    pub fn address(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines
+            .iter()
+            .filter(|&(_, &l)| l >= line)
+            .min_by_key(|&(&addr, &l)| (l, addr))
+            .map(|(&addr, &l)| (addr, l))
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssemblyError> {
+    assemble_with_map(source).map(|(rom, _)| rom)
+}
+
+pub fn assemble_with_map(source: &str) -> Result<(Vec<u8>, SourceMap), AssemblyError> {
+    let tokens = tokenize(source);
+    let last = Token {
+        text: String::new(),
+        line: 1,
+        column: 1,
+        origin: 1,
+    };
+    let mut assembler = Assembler {
+        tokens,
+        last,
+        rom: Vec::new(),
+        here: PROGRAM_START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        flow: Vec::new(),
+        line: 0,
+        source_map: SourceMap::default(),
+    };
+    assembler.run()?;
+    Ok((assembler.rom, assembler.source_map))
+}
+
+impl Assembler {
+    fn run(&mut self) -> Result<(), AssemblyError> {
+        // Programs start with a jump to main, unless main comes first
+        let starts_with_main =
+            self.tokens.len() >= 2 && self.tokens[0].text == ":" && self.tokens[1].text == "main";
+        if !starts_with_main {
+            let main = Token {
+                text: "main".to_string(),
+                line: 1,
+                column: 1,
+                origin: 1,
+            };
+            self.fixups.push((Fixup::Address(0), main));
+            self.emit_op(0x1000)?;
+        }
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(flow) = self.flow.pop() {
+            return Err(match flow {
+                Flow::If { token, .. } => token.error("'if' without 'end'"),
+                Flow::Else { token, .. } => token.error("'else' without 'end'"),
+                Flow::Loop { token, .. } => token.error("'loop' without 'again'"),
+            });
+        }
+
+        for (fixup, token) in std::mem::take(&mut self.fixups) {
+            let addr = *self
+                .labels
+                .get(&token.text)
+                .ok_or_else(|| token.error(format!("Undefined name '{}'", token.text)))?;
+            match fixup {
+                Fixup::Address(pos) => {
+                    if addr > 0xFFF {
+                        return Err(token.error(format!(
+                            "Address 0x{:X} of '{}' does not fit in 12 bits",
+                            addr, token.text
+                        )));
+                    }
+                    self.rom[pos] |= (addr >> 8) as u8;
+                    self.rom[pos + 1] = addr as u8;
+                }
+                Fixup::Long(pos) => {
+                    self.rom[pos] = (addr >> 8) as u8;
+                    self.rom[pos + 1] = addr as u8;
+                }
+                Fixup::Unpack { hi, lo, nibble } => {
+                    self.rom[hi] = nibble << 4 | (addr >> 8) as u8 & 0xF;
+                    self.rom[lo] = addr as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token, AssemblyError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last = token.clone();
+                Ok(token)
+            }
+            None => Err(self.last.error("Unexpected end of file")),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().is_some_and(|t| t.text == text)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AssemblyError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(token.error(format!("Expected '{}', found '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), AssemblyError> {
+        let pos = (self.here - PROGRAM_START) as usize;
+        if pos >= self.rom.len() {
+            self.rom.resize(pos + 1, 0);
+        }
+        self.rom[pos] = byte;
+        self.here = self
+            .here
+            .checked_add(1)
+            .ok_or_else(|| self.last.error("Program does not fit in 64K of memory"))?;
+        Ok(())
+    }
+
+    fn emit_op(&mut self, opcode: u16) -> Result<(), AssemblyError> {
+        if self.line > 0 {
+            self.source_map.lines.insert(self.here, self.line);
+        }
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    fn pos(&self) -> usize {
+        (self.here - PROGRAM_START) as usize
+    }
+
+    fn define_label(&mut self, token: &Token, addr: u16) -> Result<(), AssemblyError> {
+        if self.labels.insert(token.text.clone(), addr).is_some() {
+            return Err(token.error(format!("Label '{}' is already defined", token.text)));
+        }
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        let text = token.text.to_ascii_lowercase();
+        match text.strip_prefix('v') {
+            Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16).ok(),
+            _ => self.aliases.get(&token.text).copied(),
+        }
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AssemblyError> {
+        let token = self.next()?;
+        self.register(&token)
+            .ok_or_else(|| token.error(format!("Expected a register, found '{}'", token.text)))
+    }
+
+    // A number, constant, defined label or { expression }
+    fn value(&mut self, token: &Token) -> Result<Option<f64>, AssemblyError> {
+        if token.text == "{" {
+            return self.calc().map(Some);
+        }
+        if let Some(n) = parse_number(&token.text) {
+            return Ok(Some(n as f64));
+        }
+        if let Some(&n) = self.constants.get(&token.text) {
+            return Ok(Some(n));
+        }
+        Ok(self.labels.get(&token.text).map(|&a| a as f64))
+    }
+
+    fn number(&mut self, min: i64, max: i64) -> Result<i64, AssemblyError> {
+        let token = self.next()?;
+        let value = self
+            .value(&token)?
+            .ok_or_else(|| token.error(format!("Undefined name '{}'", token.text)))?
+            as i64;
+        if value < min || value > max {
+            return Err(token.error(format!(
+                "Value {} is out of range {} to {}",
+                value, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, AssemblyError> {
+        self.number(-128, 255).map(|n| n as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssemblyError> {
+        self.number(0, 15).map(|n| n as u8)
+    }
+
+    // An address for the low 12 bits of `opcode`, which may be a label
+    // defined later
+    fn emit_address_op(&mut self, opcode: u16) -> Result<(), AssemblyError> {
+        let token = self.next()?;
+        let addr = match self.value(&token)? {
+            Some(addr) => addr as i64,
+            None => {
+                self.fixups
+                    .push((Fixup::Address(self.pos()), token.clone()));
+                0
+            }
+        };
+        if !(0..=0xFFF).contains(&addr) {
+            return Err(token.error(format!("Address {} does not fit in 12 bits", addr)));
+        }
+        self.emit_op(opcode | addr as u16)
+    }
+
+    fn statement(&mut self) -> Result<(), AssemblyError> {
+        let token = self.next()?;
+        self.line = token.origin;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                let here = self.here;
+                self.define_label(&name, here)?;
+            }
+            ":next" => {
+                let name = self.next()?;
+                let here = self
+                    .here
+                    .checked_add(1)
+                    .ok_or_else(|| name.error("':next' points past the end of memory"))?;
+                self.define_label(&name, here)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.number(i64::MIN, i64::MAX)?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":org" => self.here = self.number(PROGRAM_START as i64, 0xFFFF)? as u16,
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte)?;
+            }
+            ":call" => self.emit_address_op(0x2000)?,
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let label = self.next()?;
+                let addr = match self.value(&label)? {
+                    Some(addr) => addr as u16,
+                    None => {
+                        let hi = self.pos() + 1;
+                        let fixup = Fixup::Unpack {
+                            hi,
+                            lo: hi + 2,
+                            nibble,
+                        };
+                        self.fixups.push((fixup, label));
+                        0
+                    }
+                };
+                self.emit_op(0x6000 | (nibble as u16) << 4 | (addr >> 8) & 0xF)?;
+                self.emit_op(0x6100 | addr & 0xFF)?;
+            }
+            ":macro" => self.define_macro()?,
+            // Debugger hints for Octo's own emulator
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "clear" => self.emit_op(0x00E0)?,
+            "return" | ";" => self.emit_op(0x00EE)?,
+            "exit" => self.emit_op(0x00FD)?,
+            "lores" => self.emit_op(0x00FE)?,
+            "hires" => self.emit_op(0x00FF)?,
+            "scroll-right" => self.emit_op(0x00FB)?,
+            "scroll-left" => self.emit_op(0x00FC)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit_op(0x00C0 | n as u16)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit_op(0x00D0 | n as u16)?;
+            }
+            "audio" => self.emit_op(0xF002)?,
+            "plane" => {
+                let n = self.number(0, 3)? as u16;
+                self.emit_op(0xF001 | n << 8)?;
+            }
+            "jump" => self.emit_address_op(0x1000)?,
+            "jump0" => self.emit_address_op(0xB000)?,
+            "native" => self.emit_address_op(0x0000)?,
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let n = self.nibble()? as u16;
+                self.emit_op(0xD000 | x << 8 | y << 4 | n)?;
+            }
+            "bcd" => self.register_op(0xF033)?,
+            "saveflags" => self.register_op(0xF075)?,
+            "loadflags" => self.register_op(0xF085)?,
+            "save" | "load" => {
+                let x = self.expect_register()? as u16;
+                if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.expect_register()? as u16;
+                    let op = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit_op(op | x << 8 | y << 4)?;
+                } else {
+                    let op = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.emit_op(op | x << 8)?;
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let op = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.register_op(op)?;
+            }
+            "i" => self.i_statement()?,
+            "if" => self.if_statement(token)?,
+            "else" => match self.flow.pop() {
+                Some(Flow::If { jump, .. }) => {
+                    let end = self.pos();
+                    self.emit_op(0x1000)?;
+                    self.patch_jump(jump, &token)?;
+                    self.flow.push(Flow::Else { jump: end, token });
+                }
+                _ => return Err(token.error("'else' without 'if ... begin'")),
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::If { jump, .. }) | Some(Flow::Else { jump, .. }) => {
+                    self.patch_jump(jump, &token)?
+                }
+                _ => return Err(token.error("'end' without 'if ... begin'")),
+            },
+            "loop" => self.flow.push(Flow::Loop {
+                start: self.here,
+                whiles: Vec::new(),
+                token,
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                let jump = self.pos() + 2 * (condition.setup.len() + 1);
+                let Some(Flow::Loop { whiles, .. }) = self
+                    .flow
+                    .iter_mut()
+                    .rev()
+                    .find(|f| matches!(f, Flow::Loop { .. }))
+                else {
+                    return Err(token.error("'while' outside 'loop'"));
+                };
+                whiles.push(jump);
+                for op in condition.setup {
+                    self.emit_op(op)?;
+                }
+                self.emit_op(invert_skip(condition.skip))?;
+                self.emit_op(0x1000)?;
+            }
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, whiles, .. }) => {
+                    self.emit_op(jump_op(start, &token)?)?;
+                    for jump in whiles {
+                        self.patch_jump(jump, &token)?;
+                    }
+                }
+                _ => return Err(token.error("'again' without 'loop'")),
+            },
+            _ => {
+                if let Some(x) = self.register(&token) {
+                    self.register_statement(x)?;
+                } else if let Some(m) = self.macros.get(&token.text).cloned() {
+                    self.expand_macro(&m)?;
+                } else if self.labels.contains_key(&token.text) {
+                    self.tokens.push_front(token);
+                    self.emit_address_op(0x2000)?;
+                } else if let Some(value) = self.value(&token)? {
+                    if !(-128.0..=255.0).contains(&value) {
+                        return Err(token.error(format!("Byte {} is out of range", value)));
+                    }
+                    self.emit(value as i64 as u8)?;
+                } else if token.text.starts_with(':') || token.text == "{" {
+                    return Err(token.error(format!("Unknown directive '{}'", token.text)));
+                } else {
+                    // A bare name calls a subroutine defined later
+                    self.tokens.push_front(token);
+                    self.emit_address_op(0x2000)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn register_op(&mut self, opcode: u16) -> Result<(), AssemblyError> {
+        let x = self.expect_register()? as u16;
+        self.emit_op(opcode | x << 8)
+    }
+
+    // Point the jump at `pos` here, for the end of a block
+    fn patch_jump(&mut self, pos: usize, token: &Token) -> Result<(), AssemblyError> {
+        let op = jump_op(self.here, token)?;
+        self.rom[pos] = (op >> 8) as u8;
+        self.rom[pos + 1] = op as u8;
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), AssemblyError> {
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => {
+                if self.peek_is("hex") || self.peek_is("bighex") {
+                    let font = if self.next()?.text == "hex" {
+                        0xF029
+                    } else {
+                        0xF030
+                    };
+                    self.register_op(font)
+                } else if self.peek_is("long") {
+                    self.next()?;
+                    let token = self.next()?;
+                    let addr = match self.value(&token)? {
+                        Some(addr) => addr as i64,
+                        None => {
+                            self.fixups
+                                .push((Fixup::Long(self.pos() + 2), token.clone()));
+                            0
+                        }
+                    };
+                    if !(0..=0xFFFF).contains(&addr) {
+                        return Err(
+                            token.error(format!("Address {} does not fit in 16 bits", addr))
+                        );
+                    }
+                    self.emit_op(0xF000)?;
+                    self.emit_op(addr as u16)
+                } else {
+                    self.emit_address_op(0xA000)
+                }
+            }
+            "+=" => self.register_op(0xF01E),
+            _ => Err(op.error(format!("Unknown operator 'i {}'", op.text))),
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), AssemblyError> {
+        let x = x as u16;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = self.register(&rhs).map(|y| y as u16);
+        let opcode = match (op.text.as_str(), y) {
+            (":=", Some(y)) => 0x8000 | x << 8 | y << 4,
+            (":=", None) => match rhs.text.as_str() {
+                "random" => 0xC000 | x << 8 | self.byte()? as u16,
+                "key" => 0xF00A | x << 8,
+                "delay" => 0xF007 | x << 8,
+                _ => {
+                    self.tokens.push_front(rhs);
+                    0x6000 | x << 8 | self.byte()? as u16
+                }
+            },
+            ("+=", Some(y)) => 0x8004 | x << 8 | y << 4,
+            ("+=", None) => {
+                self.tokens.push_front(rhs);
+                0x7000 | x << 8 | self.byte()? as u16
+            }
+            ("-=", Some(y)) => 0x8005 | x << 8 | y << 4,
+            ("-=", None) => {
+                self.tokens.push_front(rhs);
+                0x7000 | x << 8 | self.byte()?.wrapping_neg() as u16
+            }
+            ("|=", Some(y)) => 0x8001 | x << 8 | y << 4,
+            ("&=", Some(y)) => 0x8002 | x << 8 | y << 4,
+            ("^=", Some(y)) => 0x8003 | x << 8 | y << 4,
+            (">>=", Some(y)) => 0x8006 | x << 8 | y << 4,
+            ("=-", Some(y)) => 0x8007 | x << 8 | y << 4,
+            ("<<=", Some(y)) => 0x800E | x << 8 | y << 4,
+            ("|=" | "&=" | "^=" | ">>=" | "=-" | "<<=", None) => {
+                return Err(rhs.error(format!("Expected a register, found '{}'", rhs.text)))
+            }
+            _ => return Err(op.error(format!("Unknown operator '{}'", op.text))),
+        };
+        self.emit_op(opcode)
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssemblyError> {
+        let x = self.expect_register()? as u16;
+        let op = self.next()?;
+        let mut setup = Vec::new();
+        let skip = match op.text.as_str() {
+            "key" => 0xE0A1 | x << 8,
+            "-key" => 0xE09E | x << 8,
+            "==" | "!=" => {
+                let rhs = self.next()?;
+                let equal = op.text == "==";
+                match self.register(&rhs) {
+                    Some(y) if equal => 0x9000 | x << 8 | (y as u16) << 4,
+                    Some(y) => 0x5000 | x << 8 | (y as u16) << 4,
+                    None => {
+                        self.tokens.push_front(rhs);
+                        let nn = self.byte()? as u16;
+                        if equal {
+                            0x4000 | x << 8 | nn
+                        } else {
+                            0x3000 | x << 8 | nn
+                        }
+                    }
+                }
+            }
+            "<" | ">" | "<=" | ">=" => {
+                // Compare through vF: vF := rhs, then subtract so vF holds
+                // the no borrow flag
+                let rhs = self.next()?;
+                setup.push(match self.register(&rhs) {
+                    Some(y) => 0x8F00 | (y as u16) << 4,
+                    None => {
+                        self.tokens.push_front(rhs);
+                        0x6F00 | self.byte()? as u16
+                    }
+                });
+                let (subtract, flag) = match op.text.as_str() {
+                    // vF := x - rhs, flag is x >= rhs
+                    "<" => (0x8F07, 0),
+                    ">=" => (0x8F07, 1),
+                    // vF := rhs - x, flag is rhs >= x
+                    ">" => (0x8F05, 0),
+                    _ => (0x8F05, 1),
+                };
+                setup.push(subtract | x << 4);
+                0x4F00 | flag
+            }
+            _ => return Err(op.error(format!("Unknown comparison '{}'", op.text))),
+        };
+        Ok(Condition { setup, skip })
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<(), AssemblyError> {
+        let condition = self.condition()?;
+        for &op in &condition.setup {
+            self.emit_op(op)?;
+        }
+        let body = self.next()?;
+        match body.text.as_str() {
+            "then" => self.emit_op(condition.skip),
+            "begin" => {
+                self.emit_op(invert_skip(condition.skip))?;
+                let jump = self.pos();
+                self.emit_op(0x1000)?;
+                self.flow.push(Flow::If { jump, token });
+                Ok(())
+            }
+            _ => Err(body.error(format!("Expected 'then' or 'begin', found '{}'", body.text))),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssemblyError> {
+        let name = self.next()?;
+        let mut args = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            args.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, m: &Macro) -> Result<(), AssemblyError> {
+        let mut values = HashMap::new();
+        for arg in &m.args {
+            values.insert(arg.as_str(), self.next()?.text);
+        }
+        for token in m.body.iter().rev() {
+            let mut token = token.clone();
+            token.origin = self.line;
+            if let Some(value) = values.get(token.text.as_str()) {
+                token.text = value.clone();
+            }
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Evaluate the rest of a { expression }. Binary operators all have the
+    // same precedence and group to the right, as in Octo
+    fn calc(&mut self) -> Result<f64, AssemblyError> {
+        let value = self.calc_expression()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expression(&mut self) -> Result<f64, AssemblyError> {
+        let lhs = self.calc_term()?;
+        let Some(op) = self.tokens.front().map(|t| t.text.clone()) else {
+            return Ok(lhs);
+        };
+        let apply: fn(f64, f64) -> f64 = match op.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| ((a as i64) << (b as i64)) as f64,
+            ">>" => |a, b| ((a as i64) >> (b as i64)) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as i64 as f64,
+            ">" => |a, b| (a > b) as i64 as f64,
+            "<=" => |a, b| (a <= b) as i64 as f64,
+            ">=" => |a, b| (a >= b) as i64 as f64,
+            "==" => |a, b| (a == b) as i64 as f64,
+            "!=" => |a, b| (a != b) as i64 as f64,
+            _ => return Ok(lhs),
+        };
+        self.next()?;
+        let rhs = self.calc_expression()?;
+        Ok(apply(lhs, rhs))
+    }
+
+    fn calc_term(&mut self) -> Result<f64, AssemblyError> {
+        let token = self.next()?;
+        let unary: fn(f64) -> f64 = match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression()?;
+                self.expect(")")?;
+                return Ok(value);
+            }
+            "HERE" => return Ok(self.here as f64),
+            "PI" => return Ok(std::f64::consts::PI),
+            "E" => return Ok(std::f64::consts::E),
+            "@" => {
+                let addr = self.calc_term()? as i64;
+                let pos = addr - PROGRAM_START as i64;
+                return Ok(usize::try_from(pos)
+                    .ok()
+                    .and_then(|pos| self.rom.get(pos))
+                    .copied()
+                    .unwrap_or(0) as f64);
+            }
+            "-" => |a| -a,
+            "~" => |a| !(a as i64) as f64,
+            "!" => |a| (a == 0.0) as i64 as f64,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "floor" => f64::floor,
+            "ceil" => f64::ceil,
+            "sign" => f64::signum,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            _ => {
+                return self
+                    .value(&token)?
+                    .ok_or_else(|| token.error(format!("Undefined name '{}'", token.text)))
+            }
+        };
+        Ok(unary(self.calc_term()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn test_instructions() {
+        let rom = assemble(
+            ": main
+               clear
+               v0 := 0x12  v1 += 3  v2 := v1  v3 -= 1
+               i := hex v0  sprite v0 v1 5
+               save v3  load v0 - v2
+               i := long 0x1234
+               ;",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x00, 0xE0, 0x60, 0x12, 0x71, 0x03, 0x82, 0x10, 0x73, 0xFF, 0xF0, 0x29, 0xD0, 0x15,
+                0xF3, 0x55, 0x50, 0x23, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xEE,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_jump_to_main() {
+        let rom = assemble(
+            ": draw
+               sprite v0 v0 1
+               return
+             : main
+               i := dot
+               draw
+               jump main
+             : dot
+               0x80",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x12, 0x06, // jump main
+                0xD0, 0x01, 0x00, 0xEE, // draw
+                0xA2, 0x0C, 0x22, 0x02, 0x12, 0x06, // main
+                0x80, // dot
+            ]
+        );
+    }
+
+    #[test]
+    fn test_directives() {
+        let rom = assemble(
+            ": main
+             :const SPEED 3
+             :alias x v4
+             :calc DOUBLE { 1 + SPEED * 2 }
+             :macro add-twice reg n { reg += n reg += n }
+               x := DOUBLE
+               add-twice x SPEED
+               :next target v5 := 0
+               :byte { target - 0x200 }
+             :org 0x210
+               :unpack 0xA main",
+        )
+        .unwrap();
+        let mut expected = vec![
+            0x64, 0x07, 0x74, 0x03, 0x74, 0x03, 0x65, 0x00, 0x07, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        expected.extend([0x60, 0xA2, 0x61, 0x00]);
+        assert_eq!(rom, expected);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let rom = assemble(
+            ": main
+               loop
+                 while v0 != 5
+                 v0 += 1
+                 if v0 == 3 begin
+                   v1 := 1
+                 else
+                   v1 := 2
+                 end
+               again",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x40, 0x05, 0x12, 0x12, // while: skip the exit when v0 != 5
+                0x70, 0x01, //
+                0x30, 0x03, 0x12, 0x0E, // if: skip the jump to else when v0 == 3
+                0x61, 0x01, 0x12, 0x10, //
+                0x61, 0x02, //
+                0x12, 0x00, // again
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let (_, map) = assemble_with_map(
+            ":macro twice X { X += 1
+               X += 1 }
+             : main
+               v0 := 1
+
+               twice v0
+               if v0 == 3 then v1 := 1
+             : data 0x12",
+        )
+        .unwrap();
+        // The jump to main isn't from any line
+        assert_eq!(map.line(0x200), None);
+        assert_eq!(map.line(0x202), Some(4));
+        // Macro code is credited to where the macro was used
+        assert_eq!(map.line(0x204), Some(6));
+        assert_eq!(map.line(0x206), Some(6));
+        assert_eq!(map.line(0x208), Some(7));
+        assert_eq!(map.line(0x20A), Some(7));
+        assert_eq!(map.line(0x20C), None);
+        assert_eq!(map.address(5), Some((0x204, 6)));
+        assert_eq!(map.address(8), None);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        // Each comparison stores its result in v2 to v5
+        let rom = assemble(
+            ": main
+               v0 := 5 v1 := 7
+               if v0 < v1 then v2 := 1
+               if v0 > v1 then v3 := 1
+               if v0 <= 5 then v4 := 1
+               if v1 >= 8 then v5 := 1
+             : done jump done",
+        )
+        .unwrap();
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::COSMAC_VIP);
+        cpu.initialize();
+        cpu.load(rom).unwrap();
+        for _ in 0..20 {
+            cpu.cycle(0, 1).unwrap();
+        }
+        assert_eq!(&cpu.v[2..6], &[1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble(": main\n  v0 := 300").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+        assert_eq!(
+            error.to_string(),
+            "2:9: Value 300 is out of range -128 to 255"
+        );
+
+        let error = assemble(": main\n  jump nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 8));
+
+        let error = assemble(": main\n  if v0 == 1 begin\n  v1 := 2").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 3));
+
+        let error = assemble("v0 := 1").unwrap_err();
+        assert_eq!(error.message, "Undefined name 'main'");
+
+        let error = assemble(": main\n  v0 :=").unwrap_err();
+        assert_eq!(error.message, "Unexpected end of file");
+
+        // Control flow jumps need 12 bit addresses too
+        let error = assemble(": main\n:org 0x1000\n  loop\n  again").unwrap_err();
+        assert_eq!((error.line, error.column), (4, 3));
+        assert_eq!(error.message, "Address 4096 does not fit in 12 bits");
+        let error =
+            assemble(": main\n:org 0xFFC\n  if v0 == 1 begin\n  v1 := 2\n  end").unwrap_err();
+        assert_eq!((error.line, error.column), (5, 3));
+        let error = assemble(": main\n:org 0xFFF0\n  loop\n  while v0 != 1\n  again").unwrap_err();
+        assert_eq!(error.message, "Address 65520 does not fit in 12 bits");
+
+        let error = assemble(": main\n:org 0xFFFF\n:next end").unwrap_err();
+        assert_eq!(error.message, "':next' points past the end of memory");
+    }
+}
@@ -0,0 +1,123 @@
+// Behaviours that differ between CHIP-8 implementations.
+// See https://chip8.gulrak.net/#quirks for a comparison table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY1, 8XY2 and 8XY3 reset vF to 0
+    pub vf_reset: bool,
+    // FX55 and FX65 leave I pointing past the last register touched
+    pub memory_increment: bool,
+    // With memory_increment, I only moves on by X as on the CHIP-48, so it
+    // ends up pointing at vX's byte instead of past it
+    pub memory_increment_by_x: bool,
+    // DXYN waits for the start of the next frame before drawing
+    pub display_wait: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around
+    pub clipping: bool,
+    // 8XY6 and 8XYE shift vX in place instead of storing shifted vY in vX
+    pub shifting: bool,
+    // BNNN behaves as BXNN and jumps to XNN + vX instead of NNN + v0
+    pub jumping: bool,
+    // SUPER-CHIP extensions: the high resolution mode, scrolling, 16x16
+    // sprites, the big font, RPL flags and 00FD to exit
+    pub super_chip: bool,
+    // XO-CHIP extensions: 64K of memory, two bitplanes and the 4 byte
+    // F000 NNNN instruction, which skips have to step over
+    pub xo_chip: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        vf_reset: true,
+        memory_increment: true,
+        memory_increment_by_x: false,
+        display_wait: true,
+        clipping: true,
+        shifting: false,
+        jumping: false,
+        super_chip: false,
+        xo_chip: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        vf_reset: false,
+        memory_increment: true,
+        memory_increment_by_x: true,
+        display_wait: false,
+        clipping: true,
+        shifting: true,
+        jumping: true,
+        super_chip: false,
+        xo_chip: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory_increment: false,
+        memory_increment_by_x: false,
+        display_wait: false,
+        clipping: true,
+        shifting: true,
+        jumping: true,
+        super_chip: true,
+        xo_chip: false,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory_increment: true,
+        memory_increment_by_x: false,
+        display_wait: false,
+        clipping: false,
+        shifting: false,
+        jumping: false,
+        super_chip: true,
+        xo_chip: true,
+    };
+
+    // What most modern interpreters and newly written ROMs expect
+    pub const MODERN: Quirks = Quirks {
+        vf_reset: false,
+        memory_increment: false,
+        memory_increment_by_x: false,
+        display_wait: false,
+        clipping: true,
+        shifting: false,
+        jumping: false,
+        super_chip: true,
+        xo_chip: false,
+    };
+
+    pub const PRESETS: [(&'static str, Quirks); 5] = [
+        ("vip", Quirks::COSMAC_VIP),
+        ("chip48", Quirks::CHIP_48),
+        ("schip", Quirks::SUPER_CHIP),
+        ("xochip", Quirks::XO_CHIP),
+        ("modern", Quirks::MODERN),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        Quirks::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|&(_, quirks)| quirks)
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Quirks::from_name("vip"), Some(Quirks::COSMAC_VIP));
+        assert_eq!(Quirks::from_name("SCHIP"), Some(Quirks::SUPER_CHIP));
+        assert_eq!(Quirks::from_name("xochip"), Some(Quirks::XO_CHIP));
+        assert_eq!(Quirks::from_name("nonsense"), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Where CXNN gets its random bytes from
+pub trait RandomSource: fmt::Debug {
+    fn next_byte(&mut self) -> u8;
+    // Called at every 60Hz frame, for generators that depend on timing
+    fn end_frame(&mut self) {}
+    fn generator(&self) -> Generator;
+    // The whole state of the generator, kept in save states and rewind
+    // frames so a restored machine makes the same numbers again
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+// xorshift64*, the same seed always gives the same sequence
+#[derive(Debug, Clone)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        let mut random = SeededRandom { state: 0 };
+        random.set_state(splitmix64(seed));
+        random
+    }
+
+    // Seeded differently on every run
+    pub fn from_entropy() -> SeededRandom {
+        SeededRandom::new(rand::random())
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_byte(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn generator(&self) -> Generator {
+        Generator::Xorshift
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        // xorshift never leaves 0
+        self.state = state.max(1);
+    }
+}
+
+// The COSMAC VIP interpreter's CXNN. R9 counts up once per CXNN and at
+// every 60Hz interrupt. Its low byte picks a byte of the interpreter's own
+// code, which is added to its high byte and mixed with a shift and add
+#[derive(Debug, Clone)]
+pub struct VipRandom {
+    r9: u16,
+}
+
+impl VipRandom {
+    pub fn new(seed: u64) -> VipRandom {
+        VipRandom { r9: seed as u16 }
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self) -> u8 {
+        // INC R9, then RE points at 0x1NN with NN the low byte of R9
+        self.r9 = self.r9.wrapping_add(1);
+        let [high, low] = self.r9.to_be_bytes();
+        // ADD, SHRC to shift the carry into bit 7, then ADD the sum back
+        let (sum, carry) = VIP_PAGE[low as usize].overflowing_add(high);
+        let mixed = (sum >> 1 | (carry as u8) << 7).wrapping_add(sum);
+        self.r9 = u16::from_be_bytes([mixed, low]);
+        mixed
+    }
+
+    fn end_frame(&mut self) {
+        self.r9 = self.r9.wrapping_add(1);
+    }
+
+    fn generator(&self) -> Generator {
+        Generator::Vip
+    }
+
+    fn state(&self) -> u64 {
+        self.r9 as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.r9 = state as u16;
+    }
+}
+
+// Bytes 0x100-0x1FF of the COSMAC VIP CHIP-8 interpreter, which VipRandom
+// reads from. This is the second half of the FXNN handlers and the rest of
+// the instructions, ending with the 00E0 and 004B that start every program
+#[rustfmt::skip]
+const VIP_PAGE: [u8; 256] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x45, 0xA3, 0x98, 0x56, 0xD4, 0xF8, 0x81, 0xBC, 0xF8, 0x95, 0xAC,
+    0x22, 0xDC, 0x12, 0x56, 0xD4, 0x06, 0xB8, 0xD4, 0x06, 0xA8, 0xD4, 0x64, 0x0A, 0x01, 0xE6, 0x8A,
+    0xF4, 0xAA, 0x3B, 0x28, 0x9A, 0xFC, 0x01, 0xBA, 0xD4, 0xF8, 0x81, 0xBA, 0x06, 0xFA, 0x0F, 0xAA,
+    0x0A, 0xAA, 0xD4, 0xE6, 0x06, 0xBF, 0x93, 0xBE, 0xF8, 0x1B, 0xAE, 0x2A, 0x1A, 0xF8, 0x00, 0x5A,
+    0x0E, 0xF5, 0x3B, 0x4B, 0x56, 0x0A, 0xFC, 0x01, 0x5A, 0x30, 0x40, 0x4E, 0xF6, 0x3B, 0x3C, 0x9F,
+    0x56, 0x2A, 0x2A, 0xD4, 0x00, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x07, 0x5A, 0x87, 0xF3, 0x17,
+    0x1A, 0x3A, 0x5B, 0x12, 0xD4, 0x22, 0x86, 0x52, 0xF8, 0xF0, 0xA7, 0x0A, 0x57, 0x87, 0xF3, 0x17,
+    0x1A, 0x3A, 0x6B, 0x12, 0xD4, 0x15, 0x85, 0x22, 0x73, 0x95, 0x52, 0x25, 0x45, 0xA5, 0x86, 0xFA,
+    0x0F, 0xB5, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x82, 0x15, 0x15, 0xD4, 0x45, 0xE6, 0xF3, 0x3A, 0x88,
+    0xD4, 0x45, 0x07, 0x30, 0x8C, 0x45, 0x07, 0x30, 0x84, 0xE6, 0x62, 0x26, 0x45, 0xA3, 0x36, 0x88,
+    0xD4, 0x3E, 0x88, 0xD4, 0xF8, 0xF0, 0xA7, 0xE7, 0x45, 0xF4, 0xA5, 0x86, 0xFA, 0x0F, 0x3B, 0xB2,
+    0xFC, 0x01, 0xB5, 0xD4, 0x45, 0x56, 0xD4, 0x45, 0xE6, 0xF4, 0x56, 0xD4, 0x45, 0xFA, 0x0F, 0x3A,
+    0xC4, 0x07, 0x56, 0xD4, 0xAF, 0x22, 0xF8, 0xD3, 0x73, 0x8F, 0xF9, 0xF0, 0x52, 0xE6, 0x07, 0xD2,
+    0x56, 0xF8, 0xFF, 0xA6, 0xF8, 0x00, 0x7E, 0x56, 0xD4, 0x19, 0x89, 0xAE, 0x93, 0xBE, 0x99, 0xEE,
+    0xF4, 0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45, 0xF2, 0x56, 0xD4, 0x45, 0xAA, 0x86, 0xFA, 0x0F,
+    0xBA, 0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B,
+];
+
+// Which RandomSource CXNN uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Generator {
+    #[default]
+    Xorshift,
+    Vip,
+}
+
+impl Generator {
+    pub fn with_seed(self, seed: u64) -> Box<dyn RandomSource> {
+        match self {
+            Generator::Xorshift => Box::new(SeededRandom::new(seed)),
+            Generator::Vip => Box::new(VipRandom::new(seed)),
+        }
+    }
+
+    // Numbers used to store the generator in save states and movies
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Generator::Xorshift => 0,
+            Generator::Vip => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Generator> {
+        match byte {
+            0 => Some(Generator::Xorshift),
+            1 => Some(Generator::Vip),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "xorshift" => Ok(Generator::Xorshift),
+            "vip" => Ok(Generator::Vip),
+            _ => Err(format!(
+                "Unknown random number generator '{}', expected xorshift or vip",
+                s
+            )),
+        }
+    }
+}
+
+// Spreads the bits of small seeds like 1, 2, 3 across the whole state
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(random: &mut dyn RandomSource, n: usize) -> Vec<u8> {
+        (0..n).map(|_| random.next_byte()).collect()
+    }
+
+    #[test]
+    fn test_seeded() {
+        let first = bytes(&mut SeededRandom::new(1), 16);
+        assert_eq!(first, bytes(&mut SeededRandom::new(1), 16));
+        assert_ne!(first, bytes(&mut SeededRandom::new(2), 16));
+        // Not stuck on a few values
+        let mut seen = [false; 256];
+        for byte in bytes(&mut SeededRandom::new(0), 4096) {
+            seen[byte as usize] = true;
+        }
+        assert!(seen.iter().filter(|&&s| s).count() > 250);
+    }
+
+    #[test]
+    fn test_state() {
+        let mut random = SeededRandom::new(7);
+        random.next_byte();
+        let state = random.state();
+        let expected = bytes(&mut random, 8);
+        random.set_state(state);
+        assert_eq!(bytes(&mut random, 8), expected);
+    }
+
+    #[test]
+    fn test_vip() {
+        // 0x101-0x104 are zeros, then 0x45 at 0x105 gives 0x22 + 0x45, and
+        // 0xA3 + 0x67 at 0x106 carries into bit 7: 0x85 + 0x0A
+        let mut random = VipRandom::new(0);
+        assert_eq!(
+            bytes(&mut random, 12),
+            [0x00, 0x00, 0x00, 0x00, 0x67, 0x8F, 0xBA, 0x98, 0x22, 0xA7, 0xBC, 0x34]
+        );
+        assert_eq!(random.state(), 0x340C);
+        // Interrupts move R9 on too
+        let mut random = VipRandom::new(0);
+        random.end_frame();
+        assert_eq!(bytes(&mut random, 5), [0x00, 0x00, 0x00, 0x67, 0x8F]);
+
+        random.set_state(0x01D8);
+        assert_eq!(
+            bytes(&mut random, 8),
+            [0x27, 0x08, 0x11, 0xF6, 0x8E, 0xBA, 0x7C, 0x28]
+        );
+    }
+
+    #[test]
+    fn test_generator() {
+        for generator in [Generator::Xorshift, Generator::Vip] {
+            assert_eq!(generator.with_seed(1).generator(), generator);
+            assert_eq!(Generator::from_byte(generator.to_byte()), Some(generator));
+        }
+        assert_eq!("VIP".parse(), Ok(Generator::Vip));
+        assert!("lcg".parse::<Generator>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+
+use crate::display::Display;
+
+use crate::cpu::{CPU, FRAMERATE};
+use crate::quirks::Quirks;
+
+// Everything in the machine except memory and the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    quirks: Quirks,
+    opcode: u16,
+    pc: u16,
+    i: u16,
+    sp: u16,
+    v: [u8; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    stack: [u16; 16],
+    keys: [u8; 16],
+    hires: bool,
+    planes: u8,
+    rpl: [u8; 16],
+    audio_pattern: [u8; 16],
+    pitch: u8,
+    random: u64,
+}
+
+impl Registers {
+    fn capture<D: Display>(cpu: &CPU<D>) -> Registers {
+        Registers {
+            quirks: cpu.quirks,
+            opcode: cpu.opcode,
+            pc: cpu.pc,
+            i: cpu.i,
+            sp: cpu.sp,
+            v: cpu.v,
+            delay_timer: cpu.delay_timer,
+            sound_timer: cpu.sound_timer,
+            stack: cpu.stack,
+            keys: cpu.keys,
+            hires: cpu.hires,
+            planes: cpu.planes,
+            rpl: cpu.rpl,
+            audio_pattern: cpu.audio_pattern,
+            pitch: cpu.pitch,
+            random: cpu.random.state(),
+        }
+    }
+
+    fn restore<D: Display>(&self, cpu: &mut CPU<D>) {
+        cpu.quirks = self.quirks;
+        cpu.opcode = self.opcode;
+        cpu.pc = self.pc;
+        cpu.i = self.i;
+        cpu.sp = self.sp;
+        cpu.v = self.v;
+        cpu.delay_timer = self.delay_timer;
+        cpu.sound_timer = self.sound_timer;
+        cpu.stack = self.stack;
+        cpu.keys = self.keys;
+        cpu.hires = self.hires;
+        cpu.planes = self.planes;
+        cpu.rpl = self.rpl;
+        cpu.audio_pattern = self.audio_pattern;
+        cpu.pitch = self.pitch;
+        cpu.random.set_state(self.random);
+    }
+}
+
+// The bytes that turn one buffer back into an older one
+#[derive(Debug)]
+struct Delta {
+    len: usize,
+    runs: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    fn between(newer: &[u8], older: &[u8]) -> Delta {
+        if newer.len() != older.len() {
+            return Delta {
+                len: older.len(),
+                runs: vec![(0, older.to_vec())],
+            };
+        }
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (pos, (&new, &old)) in newer.iter().zip(older).enumerate() {
+            if new == old {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == pos => bytes.push(old),
+                _ => runs.push((pos, vec![old])),
+            }
+        }
+        Delta {
+            len: older.len(),
+            runs,
+        }
+    }
+
+    fn apply(&self, buffer: &mut Vec<u8>) {
+        buffer.resize(self.len, 0);
+        for (start, bytes) in &self.runs {
+            buffer[*start..*start + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    registers: Registers,
+    memory: Vec<u8>,
+    gfx: Vec<u8>,
+}
+
+// Ring buffer of the last few seconds of frames. The newest frame is kept
+// whole and each older one as the changes needed to step back to it
+#[derive(Debug)]
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Frame>,
+    // Newest first
+    history: VecDeque<(Registers, Delta, Delta)>,
+}
+
+impl Rewind {
+    pub fn new(seconds: f32) -> Rewind {
+        Rewind {
+            capacity: (seconds * FRAMERATE as f32).round().max(1.0) as usize,
+            latest: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn push<D: Display>(&mut self, cpu: &CPU<D>) {
+        let frame = Frame {
+            registers: Registers::capture(cpu),
+            memory: cpu.memory.clone(),
+            gfx: cpu.gfx.clone(),
+        };
+        if let Some(previous) = self.latest.replace(frame) {
+            let latest = self.latest.as_ref().unwrap();
+            self.history.push_front((
+                previous.registers,
+                Delta::between(&latest.memory, &previous.memory),
+                Delta::between(&latest.gfx, &previous.gfx),
+            ));
+            self.history.truncate(self.capacity - 1);
+        }
+    }
+
+    // Restore the newest frame and drop it, so each call goes one frame
+    // further back. Returns false once there is nothing left
+    pub fn pop<D: Display>(&mut self, cpu: &mut CPU<D>) -> bool {
+        let Some(mut frame) = self.latest.take() else {
+            return false;
+        };
+        frame.registers.restore(cpu);
+        cpu.memory.clone_from(&frame.memory);
+        cpu.gfx.clone_from(&frame.gfx);
+
+        if let Some((registers, memory, gfx)) = self.history.pop_front() {
+            memory.apply(&mut frame.memory);
+            gfx.apply(&mut frame.gfx);
+            frame.registers = registers;
+            self.latest = Some(frame);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+
+    // Counts up in v0, storing the count to memory and drawing it each step
+    const PROGRAM: [u8; 12] = [
+        0x70, 0x01, 0xA3, 0x00, 0xF0, 0x33, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x00,
+    ];
+
+    fn setup() -> CPU<NullDisplay> {
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::MODERN);
+        cpu.initialize();
+        cpu.load(PROGRAM.to_vec()).unwrap();
+        cpu
+    }
+
+    fn run_frame(cpu: &mut CPU<NullDisplay>) {
+        for _ in 0..6 {
+            cpu.cycle(0, 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut cpu = setup();
+        let mut rewind = Rewind::new(1.0);
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            run_frame(&mut cpu);
+            rewind.push(&cpu);
+            states.push(cpu.save_state());
+        }
+        for state in states.iter().rev() {
+            assert!(rewind.pop(&mut cpu));
+            assert_eq!(&cpu.save_state(), state);
+        }
+        assert!(!rewind.pop(&mut cpu));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cpu = setup();
+        let mut rewind = Rewind::new(0.1);
+        for _ in 0..20 {
+            run_frame(&mut cpu);
+            rewind.push(&cpu);
+        }
+        let mut frames = 0;
+        while rewind.pop(&mut cpu) {
+            frames += 1;
+        }
+        assert_eq!(frames, 6);
+        // The oldest frame kept is the 15th
+        assert_eq!(cpu.v[0], 15);
+    }
+}
@@ -1,28 +1,60 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::path::Path;
 
+use crate::error::RomError;
+use crate::octo::{self, SourceMap};
+
+// A program read from a file, ready to be loaded at 0x200
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rom {
+    pub data: Vec<u8>,
+}
+
+impl Rom {
+    // SHA-1 of the data in lowercase hex, as the ROM database is keyed by
+    pub fn sha1(&self) -> String {
+        sha1_smol::Sha1::from(&self.data).digest().to_string()
+    }
+}
+
 pub trait RomLoader {
-    fn read(file: &Path) -> Vec<u8>;
+    fn read(file: &Path) -> Result<Rom, RomError>;
 }
 
-// Rom in format of hex strings, 2 per line
+// Rom in format of hex strings, any number of bytes per line, with an
+// optional 0x prefix. Anything after // or # is a comment
 pub struct HexRomLoader;
 
-impl RomLoader for HexRomLoader {
-    fn read(file: &Path) -> Vec<u8> {
-        let mut buffer = Vec::<u8>::new();
-        let mut f = File::open(file).unwrap();
-        let mut s = String::new();
-        f.read_to_string(&mut s).unwrap();
-        for line in s.lines() {
-            let mut iter = line.split_whitespace();
-            let a = u8::from_str_radix(iter.next().unwrap(), 16).unwrap();
-            let b = u8::from_str_radix(iter.next().unwrap(), 16).unwrap();
-            buffer.push(a);
-            buffer.push(b);
+impl HexRomLoader {
+    pub fn parse(text: &str) -> Result<Rom, RomError> {
+        let mut data = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let code = line.split("//").next().unwrap_or("");
+            let code = code.split('#').next().unwrap_or("");
+            for token in code.split_whitespace() {
+                let digits = token
+                    .strip_prefix("0x")
+                    .or_else(|| token.strip_prefix("0X"))
+                    .unwrap_or(token);
+                let valid = (1..=2).contains(&digits.len())
+                    && digits.chars().all(|c| c.is_ascii_hexdigit());
+                if !valid {
+                    return Err(RomError::InvalidHex {
+                        line: n + 1,
+                        column: token.as_ptr() as usize - line.as_ptr() as usize + 1,
+                        text: token.to_string(),
+                    });
+                }
+                data.push(u8::from_str_radix(digits, 16).unwrap());
+            }
         }
-        buffer
+        Ok(Rom { data })
+    }
+}
+
+impl RomLoader for HexRomLoader {
+    fn read(file: &Path) -> Result<Rom, RomError> {
+        HexRomLoader::parse(&fs::read_to_string(file)?)
     }
 }
 
@@ -30,16 +62,32 @@ impl RomLoader for HexRomLoader {
 pub struct Ch8RomLoader;
 
 impl RomLoader for Ch8RomLoader {
-    fn read(file: &Path) -> Vec<u8> {
-        let mut buffer = Vec::<u8>::new();
-        let mut f = File::open(file).unwrap();
-        f.read_to_end(&mut buffer).unwrap();
-        buffer
+    fn read(file: &Path) -> Result<Rom, RomError> {
+        Ok(Rom {
+            data: fs::read(file)?,
+        })
     }
 }
 
-#[cfg(test)]
+// Assembles .8o Octo source files
+pub struct OctoRomLoader;
+
+impl OctoRomLoader {
+    // Also gives the source line of each instruction, for debuggers
+    pub fn read_with_map(file: &Path) -> Result<(Rom, SourceMap), RomError> {
+        let source = fs::read_to_string(file)?;
+        let (data, map) = octo::assemble_with_map(&source).map_err(RomError::Assembly)?;
+        Ok((Rom { data }, map))
+    }
+}
 
+impl RomLoader for OctoRomLoader {
+    fn read(file: &Path) -> Result<Rom, RomError> {
+        OctoRomLoader::read_with_map(file).map(|(rom, _)| rom)
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -63,8 +111,38 @@ mod tests {
             0x60, 0x00, // reset character to 0
             0x12, 0x04, // Jump back to font loading (F029)
         ];
-        let read_program = HexRomLoader::read(Path::new("src/programs/font_cycle.hex"));
+        let read_program = HexRomLoader::read(Path::new("src/programs/font_cycle.hex")).unwrap();
+
+        assert_eq!(expected_program, read_program.data);
+    }
+
+    #[test]
+    fn test_hex_parse() {
+        let text = "\n# header\n60 0A 0x61\n\n  0B  // comment\nF\n";
+        assert_eq!(
+            HexRomLoader::parse(text).unwrap().data,
+            [0x60, 0x0A, 0x61, 0x0B, 0x0F]
+        );
+
+        let error = HexRomLoader::parse("60 00\n61  0G1 // bad\n").unwrap_err();
+        assert!(matches!(
+            error,
+            RomError::InvalidHex { line: 2, column: 5, ref text } if text == "0G1"
+        ));
+        assert_eq!(error.to_string(), "2:5: Invalid hex byte '0G1'");
+        assert!(HexRomLoader::parse("6000").is_err());
+    }
 
-        assert_eq!(expected_program, read_program);
+    #[test]
+    fn test_missing_file() {
+        let error = Ch8RomLoader::read(Path::new("src/programs/missing.ch8")).unwrap_err();
+        assert!(matches!(error, RomError::Io(_)));
+    }
+
+    #[test]
+    fn test_octo_loader() {
+        let hex_program = HexRomLoader::read(Path::new("src/programs/font_cycle.hex")).unwrap();
+        let octo_program = OctoRomLoader::read(Path::new("src/programs/font_cycle.8o")).unwrap();
+        assert_eq!(hex_program, octo_program);
     }
 }
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::quirks::Quirks;
+use crate::screen::PALETTE;
+
+// ROM database in the format of the community chip-8-database's
+// programs.json (https://github.com/chip-8/chip-8-database): an array of
+// programs, each with the ROMs it was released as keyed by SHA-1. Only the
+// ROMs in src/programs are bundled; the full programs.json can be used in
+// its place, or as a local override file
+const BUNDLED: &str = include_str!("programs/database.json");
+
+// Database platform ids and the quirks preset closest to each
+const PLATFORMS: [(&str, Quirks); 7] = [
+    ("originalChip8", Quirks::COSMAC_VIP),
+    ("hybridVIP", Quirks::COSMAC_VIP),
+    ("modernChip8", Quirks::MODERN),
+    ("chip48", Quirks::CHIP_48),
+    ("superchip1", Quirks::SUPER_CHIP),
+    ("superchip", Quirks::SUPER_CHIP),
+    ("xochip", Quirks::XO_CHIP),
+];
+
+// Host keys for the database's directional and action keys
+const GAME_KEYS: [(&str, &str); 6] = [
+    ("up", "Up"),
+    ("down", "Down"),
+    ("left", "Left"),
+    ("right", "Right"),
+    ("a", "Space"),
+    ("b", "Enter"),
+];
+
+#[derive(Debug, Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, RomEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RomEntry {
+    #[serde(default)]
+    platforms: Vec<String>,
+    tickrate: Option<u32>,
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    // Quirks that differ from the platform's usual ones
+    #[serde(default)]
+    quirky_platforms: HashMap<String, HashMap<String, bool>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+// Everything the database knows about a ROM that the emulator can use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub quirks: Option<Quirks>,
+    pub cycles_per_frame: Option<u32>,
+    pub palette: Option<[u32; 4]>,
+    // Extra host keys for keypad keys, as (keypad key, host key name)
+    pub keys: Vec<(u8, String)>,
+}
+
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    programs: Vec<Program>,
+}
+
+impl RomDatabase {
+    pub fn bundled() -> RomDatabase {
+        RomDatabase::parse(BUNDLED).expect("bundled ROM database is invalid")
+    }
+
+    pub fn parse(json: &str) -> Result<RomDatabase, String> {
+        let programs = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Ok(RomDatabase { programs })
+    }
+
+    // Entries in `overrides` are found before those already here
+    pub fn add_overrides(&mut self, overrides: RomDatabase) {
+        let programs = std::mem::take(&mut self.programs);
+        self.programs = overrides.programs;
+        self.programs.extend(programs);
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<RomInfo> {
+        let sha1 = sha1.to_ascii_lowercase();
+        let (program, rom) = self
+            .programs
+            .iter()
+            .find_map(|program| Some((program, program.roms.get(&sha1)?)))?;
+        Some(RomInfo {
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            quirks: rom.quirks(),
+            cycles_per_frame: rom.tickrate.filter(|&n| n > 0),
+            palette: rom.colors.as_ref().and_then(Colors::palette),
+            keys: GAME_KEYS
+                .iter()
+                .filter_map(|(name, host)| {
+                    let &key = rom.keys.get(*name)?;
+                    (key < 16).then(|| (key, host.to_string()))
+                })
+                .collect(),
+        })
+    }
+}
+
+impl RomEntry {
+    // The preset for the first platform the ROM runs on that we support,
+    // with any quirks the ROM needs changed from it
+    fn quirks(&self) -> Option<Quirks> {
+        let (platform, mut quirks) = self.platforms.iter().find_map(|platform| {
+            PLATFORMS
+                .iter()
+                .find(|(id, _)| id == platform)
+                .map(|&(_, quirks)| (platform, quirks))
+        })?;
+        let changes = self.quirky_platforms.get(platform);
+        for (quirk, &on) in changes.into_iter().flatten() {
+            match quirk.as_str() {
+                "shift" => quirks.shifting = on,
+                "memoryLeaveIUnchanged" => quirks.memory_increment = !on,
+                "memoryIncrementByX" => quirks.memory_increment_by_x = on,
+                "wrap" => quirks.clipping = !on,
+                "jump" => quirks.jumping = on,
+                "vblank" => quirks.display_wait = on,
+                "logic" => quirks.vf_reset = on,
+                _ => {}
+            }
+        }
+        // Moving I on by X still moves it, unless it's also left unchanged
+        let leave_i = changes.and_then(|changes| changes.get("memoryLeaveIUnchanged"));
+        if quirks.memory_increment_by_x && leave_i != Some(&true) {
+            quirks.memory_increment = true;
+        }
+        Some(quirks)
+    }
+}
+
+impl Colors {
+    // "#rrggbb" colors for pixel values 0, 1, 2 and 3. Missing ones keep
+    // the default colors
+    fn palette(&self) -> Option<[u32; 4]> {
+        if self.pixels.is_empty() {
+            return None;
+        }
+        let mut palette = PALETTE;
+        for (color, pixel) in palette.iter_mut().zip(&self.pixels) {
+            let hex = pixel.strip_prefix('#')?;
+            if hex.len() != 6 {
+                return None;
+            }
+            *color = u32::from_str_radix(hex, 16).ok()?;
+        }
+        Some(palette)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_loader::{HexRomLoader, RomLoader};
+    use std::path::Path;
+
+    const DATABASE: &str = r##"[
+        {
+            "title": "Paddles",
+            "authors": ["Someone", "Someone Else"],
+            "release": "1990",
+            "roms": {
+                "00112233445566778899aabbccddeeff00112233": {
+                    "file": "paddles.ch8",
+                    "platforms": ["megachip8", "superchip", "xochip"],
+                    "tickrate": 30,
+                    "colors": { "pixels": ["#102030", "#ffeedd"], "buzzer": "#ffffff" },
+                    "keys": { "up": 1, "down": 4, "a": 6, "b": 16 },
+                    "quirkyPlatforms": {
+                        "superchip": { "shift": false, "wrap": true, "memoryIncrementByX": true }
+                    }
+                }
+            }
+        }
+    ]"##;
+
+    #[test]
+    fn test_lookup() {
+        let database = RomDatabase::parse(DATABASE).unwrap();
+        let info = database
+            .lookup("00112233445566778899AABBCCDDEEFF00112233")
+            .unwrap();
+        assert_eq!(info.title, "Paddles");
+        assert_eq!(info.authors, ["Someone", "Someone Else"]);
+        assert_eq!(info.cycles_per_frame, Some(30));
+        assert_eq!(
+            info.quirks,
+            Some(Quirks {
+                shifting: false,
+                clipping: false,
+                memory_increment: true,
+                memory_increment_by_x: true,
+                ..Quirks::SUPER_CHIP
+            })
+        );
+        assert_eq!(info.palette, Some([0x102030, 0xFFEEDD, 0xAAAAAA, 0x555555]));
+        assert_eq!(
+            info.keys,
+            [
+                (1, "Up".to_string()),
+                (4, "Down".to_string()),
+                (6, "Space".to_string())
+            ]
+        );
+        assert_eq!(database.lookup("ff"), None);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut database = RomDatabase::parse(DATABASE).unwrap();
+        let local = r#"[{ "title": "Paddles (fixed)", "roms": {
+            "00112233445566778899aabbccddeeff00112233": { "platforms": ["modernChip8"] }
+        } }]"#;
+        database.add_overrides(RomDatabase::parse(local).unwrap());
+        let info = database
+            .lookup("00112233445566778899aabbccddeeff00112233")
+            .unwrap();
+        assert_eq!(info.title, "Paddles (fixed)");
+        assert_eq!(info.quirks, Some(Quirks::MODERN));
+        assert_eq!(info.cycles_per_frame, None);
+    }
+
+    #[test]
+    fn test_memory_quirks() {
+        let quirks_on = |platform: &str, changes: &str| {
+            let json = format!(
+                r#"[{{ "title": "Loader", "roms": {{ "ab": {{
+                    "platforms": ["{0}"],
+                    "quirkyPlatforms": {{ "{0}": {1} }}
+                }} }} }}]"#,
+                platform, changes
+            );
+            let info = RomDatabase::parse(&json).unwrap().lookup("ab").unwrap();
+            let quirks = info.quirks.unwrap();
+            (quirks.memory_increment, quirks.memory_increment_by_x)
+        };
+        let quirks = |changes| quirks_on("modernChip8", changes);
+        assert_eq!(quirks("{}"), (false, false));
+        assert_eq!(
+            quirks(r#"{ "memoryLeaveIUnchanged": false }"#),
+            (true, false)
+        );
+        assert_eq!(quirks(r#"{ "memoryIncrementByX": true }"#), (true, true));
+        assert_eq!(
+            quirks(r#"{ "memoryIncrementByX": true, "memoryLeaveIUnchanged": true }"#),
+            (false, true)
+        );
+        // The CHIP-48 moves I on by X unless told otherwise
+        assert_eq!(quirks_on("chip48", "{}"), (true, true));
+        assert_eq!(
+            quirks_on("chip48", r#"{ "memoryIncrementByX": false }"#),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn test_bundled() {
+        let rom = HexRomLoader::read(Path::new("src/programs/font_cycle.hex")).unwrap();
+        let info = RomDatabase::bundled().lookup(&rom.sha1()).unwrap();
+        assert_eq!(info.title, "Font Cycle");
+        assert_eq!(info.quirks, Some(Quirks::COSMAC_VIP));
+    }
+}
@@ -0,0 +1,414 @@
+use crate::display::Display;
+
+use crate::cpu::{
+    CPU, HIRES_SCREEN_HEIGHT, HIRES_SCREEN_WIDTH, MEMORY_SIZE, SCREEN_HEIGHT, SCREEN_WIDTH,
+    XO_MEMORY_SIZE,
+};
+use crate::error::StateError;
+use crate::quirks::Quirks;
+use crate::random::Generator;
+
+// Save state layout, numbers are big endian:
+//   "C8SS", version, SHA-1 of the ROM, quirks as a bitmask (u16 from
+//   version 4, u8 before)
+//   opcode, pc, i, sp, v0-vF, delay and sound timers, stack
+//   keys, hires, planes, RPL flags, audio pattern, pitch
+//   random number generator (u8, from version 3), its state (u64, from
+//   version 2)
+//   memory length (u32) followed by the memory with runs of zeros packed
+//   the screen, 4 pixels per byte
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u8 = 4;
+
+impl<D: Display> CPU<D> {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.rom_hash);
+        out.extend_from_slice(&quirks_to_bits(&self.quirks).to_be_bytes());
+
+        out.extend_from_slice(&self.opcode.to_be_bytes());
+        out.extend_from_slice(&self.pc.to_be_bytes());
+        out.extend_from_slice(&self.i.to_be_bytes());
+        out.push(self.sp as u8);
+        out.extend_from_slice(&self.v);
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        for addr in self.stack {
+            out.extend_from_slice(&addr.to_be_bytes());
+        }
+        out.extend_from_slice(&self.keys);
+        out.push(self.hires as u8);
+        out.push(self.planes);
+        out.extend_from_slice(&self.rpl);
+        out.extend_from_slice(&self.audio_pattern);
+        out.push(self.pitch);
+        out.push(self.random.generator().to_byte());
+        out.extend_from_slice(&self.random.state().to_be_bytes());
+
+        out.extend_from_slice(&(self.memory.len() as u32).to_be_bytes());
+        pack_zeros(&self.memory, &mut out);
+        for pixels in self.gfx.chunks(4) {
+            out.push(pixels.iter().fold(0, |byte, &p| byte << 2 | (p & 3)));
+        }
+        out
+    }
+
+    // Restore a state made by `save_state`. States from a different ROM are
+    // refused unless `force` is set. Nothing changes if an error is returned
+    pub fn load_state(&mut self, state: &[u8], force: bool) -> Result<(), StateError> {
+        let mut r = Reader { data: state };
+        if r.bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(StateError::NotASaveState);
+        }
+        let version = r.u8()?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let rom_hash: [u8; 20] = r.array()?;
+        if rom_hash != self.rom_hash && !force {
+            return Err(StateError::RomMismatch);
+        }
+        // Before version 4 the SUPER-CHIP opcodes always ran
+        let quirks = if version >= 4 {
+            quirks_from_bits(r.u16()?)
+        } else {
+            quirks_from_bits(r.u8()? as u16 | SUPER_CHIP_BIT)
+        };
+
+        let opcode = r.u16()?;
+        let pc = r.u16()?;
+        let i = r.u16()?;
+        let sp = r.u8()? as u16;
+        let v = r.array()?;
+        let delay_timer = r.u8()?;
+        let sound_timer = r.u8()?;
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        let keys = r.array()?;
+        let hires = r.u8()? != 0;
+        let planes = r.u8()?;
+        let rpl = r.array()?;
+        let audio_pattern = r.array()?;
+        let pitch = r.u8()?;
+        // Version 1 states leave the generator as it is, and version 2
+        // states only set the state of the one in use
+        let generator = if version >= 3 {
+            Some(Generator::from_byte(r.u8()?).ok_or(StateError::Corrupt)?)
+        } else {
+            None
+        };
+        let random = if version >= 2 { Some(r.u64()?) } else { None };
+
+        let memory_size = r.u32()? as usize;
+        let expected_size = if quirks.xo_chip {
+            XO_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        };
+        if memory_size != expected_size || sp as usize > stack.len() {
+            return Err(StateError::Corrupt);
+        }
+        let memory = unpack_zeros(&mut r, memory_size)?;
+        let (width, height) = if hires {
+            (HIRES_SCREEN_WIDTH, HIRES_SCREEN_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        };
+        let mut gfx = Vec::with_capacity(width * height);
+        for &byte in r.bytes(width * height / 4)? {
+            gfx.extend([byte >> 6, byte >> 4 & 3, byte >> 2 & 3, byte & 3]);
+        }
+        if !r.data.is_empty() {
+            return Err(StateError::Corrupt);
+        }
+
+        self.rom_hash = rom_hash;
+        self.quirks = quirks;
+        self.opcode = opcode;
+        self.pc = pc;
+        self.i = i;
+        self.sp = sp;
+        self.v = v;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.stack = stack;
+        self.keys = keys;
+        self.hires = hires;
+        self.planes = planes;
+        self.rpl = rpl;
+        self.audio_pattern = audio_pattern;
+        self.pitch = pitch;
+        if let Some(generator) = generator {
+            self.random = generator.with_seed(0);
+        }
+        if let Some(random) = random {
+            self.random.set_state(random);
+        }
+        self.memory = memory;
+        self.gfx = gfx;
+        Ok(())
+    }
+}
+
+// The super_chip quirk's bit, which bitmasks from before it was added
+// should have set
+pub const SUPER_CHIP_BIT: u16 = 1 << 8;
+
+pub fn quirks_to_bits(quirks: &Quirks) -> u16 {
+    [
+        quirks.vf_reset,
+        quirks.memory_increment,
+        quirks.display_wait,
+        quirks.clipping,
+        quirks.shifting,
+        quirks.jumping,
+        quirks.xo_chip,
+        quirks.memory_increment_by_x,
+        quirks.super_chip,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (n, &set)| bits | (set as u16) << n)
+}
+
+pub fn quirks_from_bits(bits: u16) -> Quirks {
+    let bit = |n: u16| bits & (1 << n) != 0;
+    Quirks {
+        vf_reset: bit(0),
+        memory_increment: bit(1),
+        display_wait: bit(2),
+        clipping: bit(3),
+        shifting: bit(4),
+        jumping: bit(5),
+        xo_chip: bit(6),
+        memory_increment_by_x: bit(7),
+        super_chip: bit(8),
+    }
+}
+
+// Memory is mostly zeros, so each run of zeros is stored as a 0 followed by
+// the length of the run, up to 255
+fn pack_zeros(data: &[u8], out: &mut Vec<u8>) {
+    let mut iter = data.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        out.push(byte);
+        if byte == 0 {
+            let mut run = 1;
+            while run < 255 && iter.next_if_eq(&&0).is_some() {
+                run += 1;
+            }
+            out.push(run);
+        }
+    }
+}
+
+fn unpack_zeros(r: &mut Reader, len: usize) -> Result<Vec<u8>, StateError> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        match r.u8()? {
+            0 => {
+                let run = r.u8()? as usize;
+                if run == 0 {
+                    return Err(StateError::Corrupt);
+                }
+                data.resize(data.len() + run, 0);
+            }
+            byte => data.push(byte),
+        }
+    }
+    if data.len() != len {
+        return Err(StateError::Corrupt);
+    }
+    Ok(data)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < n {
+            return Err(StateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+
+    fn setup(prog: Vec<u8>, quirks: Quirks) -> CPU<NullDisplay> {
+        let mut cpu = CPU::new(NullDisplay::new(), quirks);
+        cpu.initialize();
+        cpu.load(prog).unwrap();
+        cpu
+    }
+
+    // Program drawing a character, then calling a subroutine that loops
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x22, 0x08, 0x12, 0x08];
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = setup(PROGRAM.to_vec(), Quirks::XO_CHIP);
+        for _ in 0..4 {
+            cpu.cycle(0, 1).unwrap();
+        }
+        cpu.delay_timer = 12;
+        cpu.rpl[3] = 7;
+        cpu.memory[0xFFF0] = 0xAB;
+        let state = cpu.save_state();
+        // 64K of mostly zero memory packs down small
+        assert!(state.len() < 2048);
+
+        let mut restored = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        restored.load_state(&state, false).unwrap();
+        assert_eq!(restored.quirks, Quirks::XO_CHIP);
+        assert_eq!(restored.pc, cpu.pc);
+        assert_eq!(restored.i, cpu.i);
+        assert_eq!(restored.v, cpu.v);
+        assert_eq!(restored.sp, 1);
+        assert_eq!(restored.stack, cpu.stack);
+        assert_eq!(restored.delay_timer, 12);
+        assert_eq!(restored.rpl, cpu.rpl);
+        assert_eq!(restored.memory, cpu.memory);
+        assert_eq!(restored.gfx, cpu.gfx);
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.random.next_byte(), cpu.random.next_byte());
+    }
+
+    #[test]
+    fn test_version_1() {
+        let mut cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        cpu.v[3] = 0x33;
+        let mut state = cpu.save_state();
+        // Version 1 had a u8 of quirks and no generator or state, which
+        // follow the pitch
+        state[4] = 1;
+        state.remove(25);
+        state.drain(134..143);
+
+        let mut restored = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        let random = restored.random.state();
+        restored.load_state(&state, false).unwrap();
+        assert_eq!(restored.v[3], 0x33);
+        assert_eq!(restored.random.state(), random);
+    }
+
+    #[test]
+    fn test_version_2() {
+        let mut cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        cpu.random = Generator::Vip.with_seed(0x1234);
+        let mut state = cpu.save_state();
+        // Version 2 had a u8 of quirks and only the state of the generator
+        state[4] = 2;
+        state.remove(25);
+        state.remove(134);
+
+        let mut restored = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        restored.load_state(&state, false).unwrap();
+        assert_eq!(restored.random.generator(), Generator::Xorshift);
+        assert_eq!(restored.random.state(), 0x1234);
+    }
+
+    #[test]
+    fn test_version_3() {
+        let cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        let mut state = cpu.save_state();
+        // Version 3 had a u8 of quirks, and ran the SUPER-CHIP opcodes
+        // whatever they were
+        state[4] = 3;
+        state.remove(25);
+
+        let mut restored = setup(PROGRAM.to_vec(), Quirks::MODERN);
+        restored.load_state(&state, false).unwrap();
+        assert_eq!(
+            restored.quirks,
+            Quirks {
+                super_chip: true,
+                ..Quirks::COSMAC_VIP
+            }
+        );
+    }
+
+    #[test]
+    fn test_generator() {
+        let mut cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        cpu.random = Generator::Vip.with_seed(0x1234);
+        cpu.random.next_byte();
+        let state = cpu.save_state();
+
+        let mut restored = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        restored.load_state(&state, false).unwrap();
+        assert_eq!(restored.random.generator(), Generator::Vip);
+        assert_eq!(restored.random.state(), cpu.random.state());
+        assert_eq!(restored.random.next_byte(), cpu.random.next_byte());
+
+        let mut state = state;
+        state[135] = 9;
+        assert_eq!(restored.load_state(&state, false), Err(StateError::Corrupt));
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        let state = cpu.save_state();
+
+        let mut other = setup(vec![0x12, 0x00], Quirks::COSMAC_VIP);
+        assert_eq!(
+            other.load_state(&state, false),
+            Err(StateError::RomMismatch)
+        );
+        assert_eq!(other.memory[0x200], 0x12);
+        other.load_state(&state, true).unwrap();
+        assert_eq!(other.memory[0x200], 0x60);
+    }
+
+    #[test]
+    fn test_invalid_states() {
+        let mut cpu = setup(PROGRAM.to_vec(), Quirks::COSMAC_VIP);
+        let mut state = cpu.save_state();
+
+        assert_eq!(
+            cpu.load_state(b"nonsense", false),
+            Err(StateError::NotASaveState)
+        );
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1], false),
+            Err(StateError::Corrupt)
+        );
+        state[4] = 99;
+        assert_eq!(
+            cpu.load_state(&state, false),
+            Err(StateError::UnsupportedVersion(99))
+        );
+    }
+}
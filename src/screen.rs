@@ -0,0 +1,133 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+// Colors for the four XO-CHIP color indices
+pub const PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+
+// Characters for the color indices in ASCII art
+const ASCII: [char; 4] = ['.', '#', '+', '@'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    // Binary PBM, set pixels are 1
+    Pbm,
+    Ascii,
+}
+
+impl ImageFormat {
+    // Chosen by extension, anything other than .png or .pbm is ASCII art
+    pub fn from_path(path: &Path) -> ImageFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => ImageFormat::Png,
+            Some(ext) if ext.eq_ignore_ascii_case("pbm") => ImageFormat::Pbm,
+            _ => ImageFormat::Ascii,
+        }
+    }
+}
+
+// Write a screen of color indices, as in `CPU::gfx`
+pub fn write_image<W: Write>(
+    w: W,
+    gfx: &[u8],
+    width: usize,
+    height: usize,
+    format: ImageFormat,
+) -> io::Result<()> {
+    match format {
+        ImageFormat::Png => write_png(w, gfx, width, height),
+        ImageFormat::Pbm => write_pbm(w, gfx, width, height),
+        ImageFormat::Ascii => write_ascii(w, gfx, width),
+    }
+}
+
+fn write_png<W: Write>(w: W, gfx: &[u8], width: usize, height: usize) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let rgb: Vec<u8> = gfx
+        .iter()
+        .flat_map(|&color| {
+            let [_, r, g, b] = PALETTE[color as usize & 3].to_be_bytes();
+            [r, g, b]
+        })
+        .collect();
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(&rgb).map_err(io::Error::other)
+}
+
+fn write_pbm<W: Write>(mut w: W, gfx: &[u8], width: usize, height: usize) -> io::Result<()> {
+    write!(w, "P4\n{} {}\n", width, height)?;
+    for row in gfx.chunks(width) {
+        // 8 pixels per byte, most significant bit first
+        let bytes: Vec<u8> = row
+            .chunks(8)
+            .map(|pixels| {
+                pixels
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (n, &p)| byte | ((p != 0) as u8) << (7 - n))
+            })
+            .collect();
+        w.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+fn write_ascii<W: Write>(mut w: W, gfx: &[u8], width: usize) -> io::Result<()> {
+    for row in gfx.chunks(width) {
+        let line: String = row.iter().map(|&color| ASCII[color as usize & 3]).collect();
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8x2 screen with a diagonal and one pixel of color 2
+    const GFX: [u8; 16] = [1, 0, 0, 0, 0, 0, 0, 2, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    fn image(format: ImageFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_image(&mut out, &GFX, 8, 2, format).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(
+            String::from_utf8(image(ImageFormat::Ascii)).unwrap(),
+            "#......+\n.#......\n"
+        );
+    }
+
+    #[test]
+    fn test_pbm() {
+        assert_eq!(image(ImageFormat::Pbm), b"P4\n8 2\n\x81\x40");
+    }
+
+    #[test]
+    fn test_png() {
+        let png = image(ImageFormat::Png);
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (8, 2));
+        assert_eq!(&pixels[0..3], &[0xFF, 0xFF, 0xFF]);
+        assert_eq!(&pixels[3..6], &[0x00, 0x00, 0x00]);
+        assert_eq!(&pixels[21..24], &[0xAA, 0xAA, 0xAA]);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ImageFormat::from_path(Path::new("a.PNG")), ImageFormat::Png);
+        assert_eq!(ImageFormat::from_path(Path::new("a.pbm")), ImageFormat::Pbm);
+        assert_eq!(
+            ImageFormat::from_path(Path::new("a.txt")),
+            ImageFormat::Ascii
+        );
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::disasm::{self, Syntax};
+use crate::error::Chip8Error;
+
+// Binary trace layout, numbers are big endian:
+//   "C8TR", version
+//   then records, each starting with a tag byte:
+//   0: an instruction. Frame (u32), pc, opcode, the word after it if the
+//      opcode is F000, the number of changes (u8), then for each change
+//      the register (0-15 for V0-VF, 16 for I) and its old and new values,
+//      as bytes for V registers and u16s for I
+//   1: a fault. pc, then the message as a length (u16) and UTF-8
+const MAGIC: &[u8; 4] = b"C8TR";
+const VERSION: u8 = 1;
+
+const INSTRUCTION: u8 = 0;
+const FAULT: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One instruction per line
+    Text,
+    // Records as described above
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!(
+                "Unknown trace format '{}', expected text or binary",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+}
+
+// A register an instruction changed, with its old and new values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub register: Register,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceRecord {
+    Instruction {
+        frame: u64,
+        pc: u16,
+        opcode: u16,
+        // Only needed to disassemble F000 NNNN
+        next: u16,
+        changes: Vec<Change>,
+    },
+    Fault {
+        pc: u16,
+        message: String,
+    },
+}
+
+// Which instructions are logged. Unset parts match everything
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub pcs: Option<RangeInclusive<u16>>,
+    // Bit n set to log opcodes whose top nibble is n
+    pub classes: Option<u16>,
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, frame: u64, pc: u16, opcode: u16) -> bool {
+        self.pcs.as_ref().is_none_or(|pcs| pcs.contains(&pc))
+            && self
+                .classes
+                .is_none_or(|classes| classes & 1 << (opcode >> 12) != 0)
+            && self
+                .frames
+                .as_ref()
+                .is_none_or(|frames| frames.contains(&frame))
+    }
+
+    // start-end in hex, e.g. 200-2FF
+    pub fn parse_pcs(s: &str) -> Result<RangeInclusive<u16>, String> {
+        parse_range(s, |n| {
+            u16::from_str_radix(n.trim_start_matches("0x"), 16).ok()
+        })
+        .ok_or(format!(
+            "Invalid address range '{}', expected e.g. 200-2FF",
+            s
+        ))
+    }
+
+    // Top nibbles of the opcodes to log, e.g. 8,D
+    pub fn parse_classes(s: &str) -> Result<u16, String> {
+        s.split(',')
+            .try_fold(0, |classes, class| match u8::from_str_radix(class, 16) {
+                Ok(n) if n < 16 => Ok(classes | 1 << n),
+                _ => Err(format!("Invalid opcode class '{}', expected 0-F", class)),
+            })
+    }
+
+    // start-end, counting from frame 0
+    pub fn parse_frames(s: &str) -> Result<RangeInclusive<u64>, String> {
+        parse_range(s, |n| n.parse().ok()).ok_or(format!(
+            "Invalid frame range '{}', expected e.g. 100-200",
+            s
+        ))
+    }
+}
+
+fn parse_range<T: PartialOrd>(
+    s: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<RangeInclusive<T>> {
+    let (start, end) = s.split_once('-')?;
+    let (start, end) = (parse(start)?, parse(end)?);
+    (start <= end).then_some(start..=end)
+}
+
+// Logs executed instructions to `out`. With a ring buffer only the last
+// few are kept, and they're only written out if the program faults
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    syntax: Syntax,
+    filter: TraceFilter,
+    ring: Option<(usize, VecDeque<TraceRecord>)>,
+    frame: u64,
+    started: bool,
+    // The first write error, reported by `finish`
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Tracer {
+        Tracer {
+            out,
+            format,
+            syntax: Syntax::Octo,
+            filter: TraceFilter::default(),
+            ring: None,
+            frame: 0,
+            started: false,
+            error: None,
+        }
+    }
+
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    pub fn set_filter(&mut self, filter: TraceFilter) {
+        self.filter = filter;
+    }
+
+    // Keep only the last `size` instructions, to be written on a fault
+    pub fn set_ring(&mut self, size: usize) {
+        self.ring = Some((size, VecDeque::with_capacity(size)));
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        pc: u16,
+        opcode: u16,
+        next: u16,
+        before: (&[u8; 16], u16),
+        after: (&[u8; 16], u16),
+    ) {
+        if !self.filter.matches(self.frame, pc, opcode) {
+            return;
+        }
+        let mut changes: Vec<Change> = (0..16)
+            .filter(|&x| before.0[x] != after.0[x])
+            .map(|x| Change {
+                register: Register::V(x as u8),
+                old: before.0[x] as u16,
+                new: after.0[x] as u16,
+            })
+            .collect();
+        if before.1 != after.1 {
+            changes.push(Change {
+                register: Register::I,
+                old: before.1,
+                new: after.1,
+            });
+        }
+        let record = TraceRecord::Instruction {
+            frame: self.frame,
+            pc,
+            opcode,
+            next,
+            changes,
+        };
+        match &mut self.ring {
+            Some((size, ring)) => {
+                if ring.len() == *size {
+                    ring.pop_front();
+                }
+                if *size > 0 {
+                    ring.push_back(record);
+                }
+            }
+            None => self.write(&record),
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    // Log a fault, after whatever led up to it if we're keeping a ring
+    pub(crate) fn fault(&mut self, pc: u16, error: &Chip8Error) {
+        if let Some((_, ring)) = &mut self.ring {
+            let records = std::mem::take(ring);
+            for record in &records {
+                self.write(record);
+            }
+        }
+        self.write(&TraceRecord::Fault {
+            pc,
+            message: error.to_string(),
+        });
+    }
+
+    fn write(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut bytes = Vec::new();
+        match self.format {
+            TraceFormat::Text => {
+                bytes.extend(format_record(record, self.syntax).bytes());
+                bytes.push(b'\n');
+            }
+            TraceFormat::Binary => {
+                if !self.started {
+                    bytes.extend_from_slice(MAGIC);
+                    bytes.push(VERSION);
+                }
+                encode_record(record, &mut bytes);
+            }
+        }
+        self.started = true;
+        if let Err(e) = self.out.write_all(&bytes) {
+            self.error = Some(e);
+        }
+    }
+
+    // Flush the output, reporting any error writing the trace
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .field("frame", &self.frame)
+            .finish()
+    }
+}
+
+// frame, pc, opcode, the instruction and the registers it changed, e.g.
+//      12 0204 7A01  va += 0x01            VA:04->05
+pub fn format_record(record: &TraceRecord, syntax: Syntax) -> String {
+    match record {
+        TraceRecord::Instruction {
+            frame,
+            pc,
+            opcode,
+            next,
+            changes,
+        } => {
+            let mnemonic = disasm::decode(*opcode, *next)
+                .map(|instruction| instruction.format(syntax, &|_| None))
+                .unwrap_or("???".to_string());
+            let mut line = format!("{:>7} {:04X} {:04X}  {:<20}", frame, pc, opcode, mnemonic);
+            for change in changes {
+                match change.register {
+                    Register::V(x) => {
+                        write!(line, " V{:X}:{:02X}->{:02X}", x, change.old, change.new)
+                    }
+                    Register::I => write!(line, " I:{:04X}->{:04X}", change.old, change.new),
+                }
+                .unwrap();
+            }
+            line.trim_end().to_string()
+        }
+        TraceRecord::Fault { pc, message } => format!("fault at {:04X}: {}", pc, message),
+    }
+}
+
+fn encode_record(record: &TraceRecord, out: &mut Vec<u8>) {
+    match record {
+        TraceRecord::Instruction {
+            frame,
+            pc,
+            opcode,
+            next,
+            changes,
+        } => {
+            out.push(INSTRUCTION);
+            out.extend_from_slice(&(*frame as u32).to_be_bytes());
+            out.extend_from_slice(&pc.to_be_bytes());
+            out.extend_from_slice(&opcode.to_be_bytes());
+            if *opcode == 0xF000 {
+                out.extend_from_slice(&next.to_be_bytes());
+            }
+            out.push(changes.len() as u8);
+            for change in changes {
+                match change.register {
+                    Register::V(x) => out.extend([x, change.old as u8, change.new as u8]),
+                    Register::I => {
+                        out.push(16);
+                        out.extend_from_slice(&change.old.to_be_bytes());
+                        out.extend_from_slice(&change.new.to_be_bytes());
+                    }
+                }
+            }
+        }
+        TraceRecord::Fault { pc, message } => {
+            out.push(FAULT);
+            out.extend_from_slice(&pc.to_be_bytes());
+            out.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            out.extend_from_slice(message.as_bytes());
+        }
+    }
+}
+
+// Reads big endian numbers from the front of a slice
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let (bytes, rest) = self
+            .data
+            .split_at_checked(n)
+            .ok_or("Trace is corrupt".to_string())?;
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+// Read back a binary trace. A ring buffer that never saw a fault leaves
+// the file empty
+pub fn decode(data: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let data = data
+        .strip_prefix(MAGIC)
+        .ok_or("Not a binary trace".to_string())?;
+    let mut r = Reader { data };
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(format!("Unsupported trace version {}", version));
+    }
+    let mut records = Vec::new();
+    while !r.data.is_empty() {
+        let record = match r.u8()? {
+            INSTRUCTION => {
+                let frame = r.u32()? as u64;
+                let pc = r.u16()?;
+                let opcode = r.u16()?;
+                let next = if opcode == 0xF000 { r.u16()? } else { 0 };
+                let count = r.u8()?;
+                let mut changes = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    changes.push(match r.u8()? {
+                        x @ 0..=15 => Change {
+                            register: Register::V(x),
+                            old: r.u8()? as u16,
+                            new: r.u8()? as u16,
+                        },
+                        16 => Change {
+                            register: Register::I,
+                            old: r.u16()?,
+                            new: r.u16()?,
+                        },
+                        _ => return Err("Trace is corrupt".to_string()),
+                    });
+                }
+                TraceRecord::Instruction {
+                    frame,
+                    pc,
+                    opcode,
+                    next,
+                    changes,
+                }
+            }
+            FAULT => {
+                let pc = r.u16()?;
+                let length = r.u16()? as usize;
+                let message = String::from_utf8(r.bytes(length)?.to_vec())
+                    .map_err(|_| "Trace is corrupt".to_string())?;
+                TraceRecord::Fault { pc, message }
+            }
+            _ => return Err("Trace is corrupt".to_string()),
+        };
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{StepOutcome, CPU};
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A writer whose contents can still be read after the tracer has it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // v0 := 5, v1 := 7, v0 += v1, i := 0x300, then a bad opcode
+    const PROGRAM: [u8; 10] = [0x60, 0x05, 0x61, 0x07, 0x80, 0x14, 0xA3, 0x00, 0x00, 0x00];
+
+    fn run(tracer: Tracer) -> Vec<u8> {
+        let out = Shared::default();
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        cpu.load(PROGRAM.to_vec()).unwrap();
+        let mut tracer = tracer;
+        tracer.out = Box::new(out.clone());
+        cpu.set_tracer(Some(tracer));
+        for _ in 0..3 {
+            assert_eq!(cpu.cycle(0, 0), Ok(StepOutcome::Continue));
+        }
+        cpu.end_frame();
+        assert_eq!(cpu.cycle(0, 0), Ok(StepOutcome::Continue));
+        assert!(cpu.cycle(0, 0).is_err());
+        cpu.take_tracer().unwrap().finish().unwrap();
+        out.0.take()
+    }
+
+    fn tracer(format: TraceFormat) -> Tracer {
+        Tracer::new(Box::new(io::sink()), format)
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let text = String::from_utf8(run(tracer(TraceFormat::Text))).unwrap();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                "      0 0200 6005  v0 := 0x05           V0:00->05",
+                "      0 0202 6107  v1 := 0x07           V1:00->07",
+                "      0 0204 8014  v0 += v1             V0:05->0C",
+                "      1 0206 A300  i := 0x300           I:0000->0300",
+                "fault at 0208: Unknown opcode 0x0000 at 0x208",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filtered = tracer(TraceFormat::Text);
+        filtered.set_filter(TraceFilter {
+            pcs: Some(TraceFilter::parse_pcs("202-2FF").unwrap()),
+            classes: Some(TraceFilter::parse_classes("6,8").unwrap()),
+            frames: Some(TraceFilter::parse_frames("0-0").unwrap()),
+        });
+        let text = String::from_utf8(run(filtered)).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with("      0 0202 6107"));
+
+        assert!(TraceFilter::parse_pcs("300-200").is_err());
+        assert!(TraceFilter::parse_classes("8,G").is_err());
+        assert_eq!(TraceFilter::parse_classes("0,F"), Ok(0x8001));
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut ring = tracer(TraceFormat::Text);
+        ring.set_ring(2);
+        let text = String::from_utf8(run(ring)).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with("      0 0204 8014"));
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let data = run(tracer(TraceFormat::Binary));
+        let records = decode(&data).unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            records[3],
+            TraceRecord::Instruction {
+                frame: 1,
+                pc: 0x206,
+                opcode: 0xA300,
+                next: 0,
+                changes: vec![Change {
+                    register: Register::I,
+                    old: 0,
+                    new: 0x300
+                }],
+            }
+        );
+        assert_eq!(
+            format_record(&records[2], Syntax::Cowgod),
+            "      0 0204 8014  ADD V0, V1           V0:05->0C"
+        );
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert_eq!(decode(&[]), Ok(Vec::new()));
+    }
+}
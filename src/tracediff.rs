@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use crate::cpu::{StepOutcome, CPU};
+use crate::display::Display;
+use crate::error::Chip8Error;
+use crate::headless::{self, KeyPress, Limit};
+
+/*
+   Reference trace format:
+   * One line per executed instruction, giving the state just before it ran
+   * Each line is a list of FIELD:value pairs separated by spaces, with the
+     values in hex. `=` can be used in place of `:`, and a 0x prefix is
+     allowed
+     * PC, OP (the opcode), I and SP
+     * V0 to VF, or V with all 16 registers as 32 hex digits
+     * Field names are case-insensitive and other fields are ignored, so
+       only what's in the reference is compared
+   * Blank lines and lines starting with # are skipped
+   e.g.
+       PC:0200 OP:6005 I:0000 SP:0 V:00000000000000000000000000000000
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Pc,
+    Opcode,
+    I,
+    Sp,
+    V(u8),
+}
+
+impl Field {
+    fn name(&self) -> String {
+        match self {
+            Field::Pc => "PC".to_string(),
+            Field::Opcode => "OP".to_string(),
+            Field::I => "I".to_string(),
+            Field::Sp => "SP".to_string(),
+            Field::V(x) => format!("V{:X}", x),
+        }
+    }
+}
+
+// The registers at the start of an instruction, with the opcode it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub pc: u16,
+    pub opcode: u16,
+    pub i: u16,
+    pub sp: u16,
+    pub v: [u8; 16],
+}
+
+impl State {
+    fn of<D: Display>(cpu: &CPU<D>) -> State {
+        State {
+            pc: cpu.pc,
+            opcode: cpu.opcode,
+            i: cpu.i,
+            sp: cpu.sp,
+            v: cpu.v,
+        }
+    }
+
+    pub fn get(&self, field: Field) -> u16 {
+        match field {
+            Field::Pc => self.pc,
+            Field::Opcode => self.opcode,
+            Field::I => self.i,
+            Field::Sp => self.sp,
+            Field::V(x) => self.v[x as usize] as u16,
+        }
+    }
+
+    // As a line of the reference format
+    pub fn format(&self) -> String {
+        let mut line = format!(
+            "PC:{:04X} OP:{:04X} I:{:04X} SP:{:X} V:",
+            self.pc, self.opcode, self.i, self.sp
+        );
+        for v in self.v {
+            write!(line, "{:02X}", v).unwrap();
+        }
+        line
+    }
+}
+
+// A line of the reference trace, with its line number in the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReferenceLine {
+    pub number: usize,
+    pub text: String,
+    pub fields: Vec<(Field, u16)>,
+}
+
+pub fn parse_reference(text: &str) -> Result<Vec<ReferenceLine>, String> {
+    let mut lines = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |token: &str| format!("{}: Invalid field '{}'", n + 1, token);
+        let mut fields = Vec::new();
+        for token in line.split_whitespace() {
+            let Some((name, value)) = token.split_once([':', '=']) else {
+                continue;
+            };
+            let value = value.trim_start_matches("0x");
+            let number = || u16::from_str_radix(value, 16).map_err(|_| invalid(token));
+            match name.to_ascii_uppercase().as_str() {
+                "PC" => fields.push((Field::Pc, number()?)),
+                "OP" => fields.push((Field::Opcode, number()?)),
+                "I" => fields.push((Field::I, number()?)),
+                "SP" => fields.push((Field::Sp, number()?)),
+                "V" => {
+                    if value.len() != 32 || !value.is_ascii() {
+                        return Err(invalid(token));
+                    }
+                    for x in 0..16 {
+                        let byte = u8::from_str_radix(&value[x * 2..x * 2 + 2], 16)
+                            .map_err(|_| invalid(token))?;
+                        fields.push((Field::V(x as u8), byte as u16));
+                    }
+                }
+                name => {
+                    let register = name
+                        .strip_prefix('V')
+                        .filter(|x| x.len() == 1)
+                        .and_then(|x| u8::from_str_radix(x, 16).ok());
+                    if let Some(x) = register {
+                        let byte = u8::from_str_radix(value, 16).map_err(|_| invalid(token))?;
+                        fields.push((Field::V(x), byte as u16));
+                    }
+                }
+            }
+        }
+        if fields.is_empty() {
+            return Err(format!("{}: No PC, OP, I, SP or V fields", n + 1));
+        }
+        lines.push(ReferenceLine {
+            number: n + 1,
+            text: line.to_string(),
+            fields,
+        });
+    }
+    Ok(lines)
+}
+
+// The first instruction that differs from the reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Index into the reference, counting executed instructions from 0
+    pub index: usize,
+    pub frame: u64,
+    // The fields that differ, as (field, expected, actual)
+    pub mismatches: Vec<(Field, u16, u16)>,
+    // Our states for the instructions around it, starting at `context_start`
+    pub context_start: usize,
+    pub context: Vec<State>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffResult {
+    // Every reference line matched
+    Matched(usize),
+    Diverged(Divergence),
+    // The run stopped, or faulted, after this many instructions, before
+    // the reference ran out
+    Ended(usize, Option<Chip8Error>),
+}
+
+// Run `cpu` headlessly, comparing each instruction against the reference.
+// `context` is how many instructions to keep either side of a divergence
+pub fn diff<D: Display>(
+    cpu: &mut CPU<D>,
+    limit: Limit,
+    presses: &[KeyPress],
+    reference: &[ReferenceLine],
+    context: usize,
+) -> DiffResult {
+    if reference.is_empty() {
+        return DiffResult::Matched(0);
+    }
+    let mut before = State::of(cpu);
+    let mut recent: VecDeque<State> = VecDeque::new();
+    let mut divergence: Option<Divergence> = None;
+    let mut index = 0;
+    let result = headless::run_with(cpu, limit, presses, |cpu, frame, outcome| {
+        // Instructions waiting on a key or the display haven't run yet
+        if outcome == StepOutcome::Waiting {
+            return true;
+        }
+        let state = State {
+            opcode: cpu.opcode,
+            ..before
+        };
+        before = State::of(cpu);
+        if let Some(divergence) = &mut divergence {
+            divergence.context.push(state);
+            return divergence.context.len()
+                < divergence.index - divergence.context_start + 1 + context;
+        }
+        let mismatches: Vec<(Field, u16, u16)> = reference[index]
+            .fields
+            .iter()
+            .filter(|&&(field, expected)| state.get(field) != expected)
+            .map(|&(field, expected)| (field, expected, state.get(field)))
+            .collect();
+        recent.push_back(state);
+        if recent.len() > context + 1 {
+            recent.pop_front();
+        }
+        if !mismatches.is_empty() {
+            divergence = Some(Divergence {
+                index,
+                frame,
+                mismatches,
+                context_start: index + 1 - recent.len(),
+                context: recent.drain(..).collect(),
+            });
+            return context > 0;
+        }
+        index += 1;
+        index < reference.len()
+    });
+    match (divergence, result) {
+        (Some(divergence), _) => DiffResult::Diverged(divergence),
+        (None, _) if index == reference.len() => DiffResult::Matched(index),
+        (None, result) => DiffResult::Ended(index, result.err()),
+    }
+}
+
+// A description of the divergence with our states around it, and the
+// reference lines from the same instructions
+pub fn report(divergence: &Divergence, reference: &[ReferenceLine]) -> String {
+    let line = &reference[divergence.index];
+    let mut out = format!(
+        "First divergence at instruction {} (frame {}, reference line {}):\n",
+        divergence.index, divergence.frame, line.number
+    );
+    for &(field, expected, actual) in &divergence.mismatches {
+        writeln!(
+            out,
+            "  {} is {:X}, expected {:X}",
+            field.name(),
+            actual,
+            expected
+        )
+        .unwrap();
+    }
+    for (n, state) in divergence.context.iter().enumerate() {
+        let index = divergence.context_start + n;
+        let marker = if index == divergence.index { '>' } else { ' ' };
+        writeln!(out, "{} {:>8}  ours: {}", marker, index, state.format()).unwrap();
+        if let Some(line) = reference.get(index) {
+            writeln!(out, "{} {:>8}   ref: {}", marker, "", line.text).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::NullDisplay;
+    use crate::quirks::Quirks;
+
+    // v0 := 1, v0 += 2, then loop, adding 2 to v0 each time round
+    const PROGRAM: [u8; 6] = [0x60, 0x01, 0x70, 0x02, 0x12, 0x02];
+
+    fn run(reference: &str, context: usize) -> (DiffResult, Vec<ReferenceLine>) {
+        let reference = parse_reference(reference).unwrap();
+        let mut cpu = CPU::new(NullDisplay::new(), Quirks::default());
+        cpu.initialize();
+        cpu.load(PROGRAM.to_vec()).unwrap();
+        let result = diff(&mut cpu, Limit::Frames(10), &[], &reference, context);
+        (result, reference)
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let lines = parse_reference(
+            "# header\n\npc=0x200 op:6001 cycles:12 v:000102030405060708090A0B0C0D0E0F\n\
+             PC:202 vA:7 I:0",
+        )
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].number, 3);
+        assert_eq!(lines[0].fields.len(), 18);
+        assert_eq!(lines[0].fields[17], (Field::V(0xF), 0x0F));
+        assert_eq!(
+            lines[1].fields,
+            [(Field::Pc, 0x202), (Field::V(0xA), 7), (Field::I, 0)]
+        );
+
+        assert!(parse_reference("PC:20G").is_err());
+        assert!(parse_reference("V:0011").is_err());
+        assert!(parse_reference("cycles:1").is_err());
+    }
+
+    #[test]
+    fn test_matched() {
+        let (result, _) = run("PC:200 OP:6001 V0:0\nPC:202 V0:1\nPC:204 V0:3\nPC:202", 2);
+        assert_eq!(result, DiffResult::Matched(4));
+    }
+
+    #[test]
+    fn test_diverged() {
+        let reference = "PC:200\nPC:202\nPC:204 V0:3\nPC:202 V0:3\nPC:204 V0:4\nPC:202";
+        let (result, reference) = run(reference, 1);
+        let DiffResult::Diverged(divergence) = result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!(divergence.index, 4);
+        assert_eq!(divergence.mismatches, [(Field::V(0), 4, 5)]);
+        assert_eq!(divergence.context_start, 3);
+        assert_eq!(
+            divergence.context.iter().map(|s| s.pc).collect::<Vec<_>>(),
+            [0x202, 0x204, 0x202]
+        );
+        let report = report(&divergence, &reference);
+        assert!(report.contains("reference line 5"), "{}", report);
+        assert!(report.contains("  V0 is 5, expected 4\n"), "{}", report);
+        assert!(report.contains(
+            ">        4  ours: PC:0204 OP:1202 I:0000 SP:0 V:05000000000000000000000000000000\n"
+        ));
+    }
+
+    #[test]
+    fn test_ended() {
+        // More instructions than the 10 frames run
+        let mut reference = String::from("PC:200\n");
+        for _ in 0..11 * 10 {
+            reference.push_str("OP:7002\nOP:1202\n");
+        }
+        let (result, _) = run(&reference, 0);
+        assert_eq!(result, DiffResult::Ended(11 * 10, None));
+    }
+}
@@ -0,0 +1,530 @@
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::display::Display;
+use crate::frontend::{Frontend, Hotkey};
+use crate::keymap::Keymap;
+
+// Terminals only report key presses, with auto-repeat while a key is
+// held, so a key counts as down for this long after each press
+const KEY_HOLD: Duration = Duration::from_millis(200);
+
+// How pixels are packed into character cells
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glyphs {
+    // 1x2 pixels per cell with ▀ ▄ █
+    HalfBlock,
+    // 2x4 pixels per cell with the braille patterns, U+2800 on
+    Braille,
+}
+
+impl FromStr for Glyphs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "halfblock" => Ok(Glyphs::HalfBlock),
+            "braille" => Ok(Glyphs::Braille),
+            _ => Err(format!(
+                "Unknown glyphs '{}', expected halfblock or braille",
+                s
+            )),
+        }
+    }
+}
+
+// A key as read from the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyKey {
+    // Letters are always lowercase
+    Char(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+// What a burst of input bytes from the terminal means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    Key(TtyKey),
+    // F1-F4, and whether shift was held
+    Function(usize, bool),
+    Backspace,
+    // Escape or Ctrl-C
+    Quit,
+}
+
+// Keymap names that can be told apart in a terminal, besides letters,
+// digits and the number pad, which sends digits and symbols too
+const KEY_CHARS: [(&str, u8); 16] = [
+    ("Apostrophe", b'\''),
+    ("Backquote", b'`'),
+    ("Backslash", b'\\'),
+    ("Comma", b','),
+    ("Equal", b'='),
+    ("LeftBracket", b'['),
+    ("Minus", b'-'),
+    ("Period", b'.'),
+    ("RightBracket", b']'),
+    ("Semicolon", b';'),
+    ("Slash", b'/'),
+    ("Space", b' '),
+    ("Enter", b'\r'),
+    ("NumPadDot", b'.'),
+    ("NumPadSlash", b'/'),
+    ("NumPadAsterisk", b'*'),
+];
+
+fn tty_key(name: &str) -> Option<TtyKey> {
+    let lower = name.to_ascii_lowercase();
+    let digit = lower
+        .strip_prefix("key")
+        .or_else(|| lower.strip_prefix("numpad"));
+    match (lower.as_str(), digit) {
+        (_, Some(d)) if d.len() == 1 && d.as_bytes()[0].is_ascii_digit() => {
+            Some(TtyKey::Char(d.as_bytes()[0]))
+        }
+        (l, _) if l.len() == 1 && l.as_bytes()[0].is_ascii_lowercase() => {
+            Some(TtyKey::Char(l.as_bytes()[0]))
+        }
+        ("up", _) => Some(TtyKey::Up),
+        ("down", _) => Some(TtyKey::Down),
+        ("left", _) => Some(TtyKey::Left),
+        ("right", _) => Some(TtyKey::Right),
+        ("numpadminus", _) => Some(TtyKey::Char(b'-')),
+        ("numpadplus", _) => Some(TtyKey::Char(b'+')),
+        ("numpadenter", _) => Some(TtyKey::Char(b'\r')),
+        _ => KEY_CHARS
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, c)| TtyKey::Char(c)),
+    }
+}
+
+// The terminal keys for each keypad key, failing on keys a terminal
+// can't report, like the modifiers
+pub fn host_keys(keymap: &Keymap) -> Result<[Vec<TtyKey>; 16], String> {
+    let mut keys: [Vec<TtyKey>; 16] = Default::default();
+    for (keypad_key, names) in keymap.keys.iter().enumerate() {
+        for name in names {
+            let key = tty_key(name).ok_or(format!(
+                "Key '{}' for keypad key {:X} can't be read in a terminal",
+                name, keypad_key
+            ))?;
+            keys[keypad_key].push(key);
+        }
+    }
+    Ok(keys)
+}
+
+fn parse_input(bytes: &[u8]) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        i += 1;
+        let input = match byte {
+            0x03 => Input::Quit,
+            0x08 | 0x7F => Input::Backspace,
+            0x1B => match bytes.get(i) {
+                // A lone escape, rather than the start of a sequence
+                None => Input::Quit,
+                // ESC O P-S for F1-F4, ESC O A-D for the arrows in
+                // application mode
+                Some(b'O') => {
+                    let Some(&last) = bytes.get(i + 1) else { break };
+                    i += 2;
+                    match last {
+                        b'P'..=b'S' => Input::Function((last - b'P') as usize + 1, false),
+                        _ => match arrow(last) {
+                            Some(key) => Input::Key(key),
+                            None => continue,
+                        },
+                    }
+                }
+                // ESC [ params final, e.g. ESC [ A, ESC [ 1;2P or ESC [ 11~
+                Some(b'[') => {
+                    let start = i + 1;
+                    let Some(end) = bytes[start..]
+                        .iter()
+                        .position(|b| (0x40..=0x7E).contains(b))
+                        .map(|n| start + n)
+                    else {
+                        break;
+                    };
+                    i = end + 1;
+                    let params = &bytes[start..end];
+                    let shift = params.ends_with(b";2");
+                    match (bytes[end], params) {
+                        (b'P'..=b'S', _) => {
+                            Input::Function((bytes[end] - b'P') as usize + 1, shift)
+                        }
+                        (b'~', [b'1', n @ b'1'..=b'4', ..]) => {
+                            Input::Function((n - b'0') as usize, shift)
+                        }
+                        (last, _) => match arrow(last) {
+                            Some(key) => Input::Key(key),
+                            None => continue,
+                        },
+                    }
+                }
+                // Alt with another key
+                Some(_) => continue,
+            },
+            _ => Input::Key(TtyKey::Char(byte.to_ascii_lowercase())),
+        };
+        inputs.push(input);
+    }
+    inputs
+}
+
+fn arrow(byte: u8) -> Option<TtyKey> {
+    match byte {
+        b'A' => Some(TtyKey::Up),
+        b'B' => Some(TtyKey::Down),
+        b'C' => Some(TtyKey::Right),
+        b'D' => Some(TtyKey::Left),
+        _ => None,
+    }
+}
+
+// Pack the pixels into cells, returning them with the number of columns
+pub(crate) fn render(
+    glyphs: Glyphs,
+    buffer: &[u8],
+    width: usize,
+    height: usize,
+) -> (Vec<char>, usize) {
+    let lit = |x: usize, y: usize| y < height && buffer[y * width + x] != 0;
+    let (cell_width, cell_height) = match glyphs {
+        Glyphs::HalfBlock => (1, 2),
+        Glyphs::Braille => (2, 4),
+    };
+    let columns = width.div_ceil(cell_width);
+    let rows = height.div_ceil(cell_height);
+    let mut cells = Vec::with_capacity(columns * rows);
+    for row in 0..rows {
+        for column in 0..columns {
+            let (x, y) = (column * cell_width, row * cell_height);
+            let cell = match glyphs {
+                Glyphs::HalfBlock => match (lit(x, y), lit(x, y + 1)) {
+                    (false, false) => ' ',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (true, true) => '█',
+                },
+                Glyphs::Braille => {
+                    // Dots 1-3 and 4-6 are the top three rows of each
+                    // column, 7 and 8 the bottom row
+                    const DOTS: [(usize, usize, u32); 8] = [
+                        (0, 0, 0x01),
+                        (0, 1, 0x02),
+                        (0, 2, 0x04),
+                        (1, 0, 0x08),
+                        (1, 1, 0x10),
+                        (1, 2, 0x20),
+                        (0, 3, 0x40),
+                        (1, 3, 0x80),
+                    ];
+                    let bits = DOTS
+                        .iter()
+                        .filter(|&&(dx, dy, _)| x + dx < width && lit(x + dx, y + dy))
+                        .fold(0, |bits, &(_, _, bit)| bits | bit);
+                    char::from_u32(0x2800 + bits).unwrap()
+                }
+            };
+            cells.push(cell);
+        }
+    }
+    (cells, columns)
+}
+
+// The escape sequences to turn `previous` into `cells`, only writing the
+// cells that changed and only moving the cursor when it isn't already
+// in the right place
+fn redraw(previous: &[char], cells: &[char], columns: usize) -> String {
+    let mut out = String::new();
+    let mut cursor = None;
+    for (index, (&old, &new)) in previous.iter().zip(cells).enumerate() {
+        if old == new {
+            continue;
+        }
+        if cursor != Some(index) {
+            out.push_str(&format!(
+                "\x1b[{};{}H",
+                index / columns + 1,
+                index % columns + 1
+            ));
+        }
+        out.push(new);
+        // At the end of a row the cursor doesn't move on to the next one
+        cursor = (index % columns + 1 < columns).then_some(index + 1);
+    }
+    out
+}
+
+// Renders to the terminal and reads the keypad from stdin, for running
+// where no window can be opened, e.g. over SSH. Restores the terminal
+// when dropped
+pub struct TtyDisplay {
+    glyphs: Glyphs,
+    keys: [Vec<TtyKey>; 16],
+    input: Receiver<Vec<u8>>,
+    // Cells on screen, and the number of columns they're in
+    cells: Vec<char>,
+    columns: usize,
+    // When each keypad key, and the rewind key, was last pressed
+    pressed: [Option<Instant>; 16],
+    rewind_pressed: Option<Instant>,
+    hotkey: Option<Hotkey>,
+    open: bool,
+    _raw_mode: RawMode,
+}
+
+// The terminal's settings from before raw mode, put back when dropped
+#[cfg(unix)]
+struct RawMode(libc::termios);
+
+#[cfg(unix)]
+impl RawMode {
+    // Raw enough to get every key press straight away without echoing it,
+    // but keeping output processing so printed messages still start on a
+    // new line
+    fn enable() -> io::Result<RawMode> {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        termios.c_iflag &= !(libc::IXON | libc::ICRNL);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawMode(original))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+#[cfg(not(unix))]
+struct RawMode;
+
+#[cfg(not(unix))]
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the terminal frontend needs a Unix terminal",
+        ))
+    }
+}
+
+impl TtyDisplay {
+    pub fn new(glyphs: Glyphs, keys: [Vec<TtyKey>; 16]) -> io::Result<TtyDisplay> {
+        let raw_mode = RawMode::enable()?;
+
+        // Read stdin on its own thread so frames never wait for input
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            while let Ok(n @ 1..) = io::stdin().lock().read(&mut buffer) {
+                if sender.send(buffer[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Switch to the alternate screen and hide the cursor
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        io::stdout().flush()?;
+        Ok(TtyDisplay {
+            glyphs,
+            keys,
+            input,
+            cells: Vec::new(),
+            columns: 0,
+            pressed: [None; 16],
+            rewind_pressed: None,
+            hotkey: None,
+            open: true,
+            _raw_mode: raw_mode,
+        })
+    }
+
+    fn poll_input(&mut self) {
+        let now = Instant::now();
+        while let Ok(bytes) = self.input.try_recv() {
+            for input in parse_input(&bytes) {
+                match input {
+                    Input::Key(key) => {
+                        let mut keypad = false;
+                        for (n, keys) in self.keys.iter().enumerate() {
+                            if keys.contains(&key) {
+                                self.pressed[n] = Some(now);
+                                keypad = true;
+                            }
+                        }
+                        // - and = change the speed, Tab turns turbo mode
+                        // on and off, unless they're keypad keys
+                        self.hotkey = match key {
+                            _ if keypad => self.hotkey,
+                            TtyKey::Char(b'-') => Some(Hotkey::Slower),
+                            TtyKey::Char(b'=') => Some(Hotkey::Faster),
+                            TtyKey::Char(b'\t') => Some(Hotkey::Turbo),
+                            _ => self.hotkey,
+                        };
+                    }
+                    // F1-F4 load a save state slot, Shift+F1-F4 save to it
+                    Input::Function(slot, true) => self.hotkey = Some(Hotkey::SaveState(slot)),
+                    Input::Function(slot, false) => self.hotkey = Some(Hotkey::LoadState(slot)),
+                    Input::Backspace => self.rewind_pressed = Some(now),
+                    Input::Quit => self.open = false,
+                }
+            }
+        }
+    }
+}
+
+fn is_held(pressed: Option<Instant>) -> bool {
+    pressed.is_some_and(|time| time.elapsed() < KEY_HOLD)
+}
+
+impl Frontend for TtyDisplay {
+    fn hotkey(&self) -> Option<Hotkey> {
+        self.hotkey
+    }
+
+    fn take_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkey.take()
+    }
+
+    fn is_rewinding(&self) -> bool {
+        is_held(self.rewind_pressed)
+    }
+}
+
+impl Display for TtyDisplay {
+    fn update(&mut self, buffer: &[u8], width: usize, height: usize) {
+        let (cells, columns) = render(self.glyphs, buffer, width, height);
+        let mut out = String::new();
+        if columns != self.columns || cells.len() != self.cells.len() {
+            // Switched between lores and hires, start again
+            out.push_str("\x1b[2J");
+            self.cells = vec![' '; cells.len()];
+            self.columns = columns;
+        }
+        out.push_str(&redraw(&self.cells, &cells, columns));
+        if !out.is_empty() {
+            // Leave the cursor below the screen, where any messages go
+            let rows = cells.len() / columns;
+            out.push_str(&format!("\x1b[{};1H", rows + 2));
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout
+                .write_all(out.as_bytes())
+                .and_then(|_| stdout.flush())
+            {
+                eprintln!("Failed to draw to the terminal: {}", e);
+            }
+        }
+        self.cells = cells;
+        self.poll_input();
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn is_key_down(&self, key: usize) -> bool {
+        self.pressed
+            .get(key)
+            .is_some_and(|&pressed| is_held(pressed))
+    }
+}
+
+impl Drop for TtyDisplay {
+    fn drop(&mut self) {
+        // Back to the normal screen, then out of raw mode as the fields
+        // are dropped
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::LAYOUTS;
+
+    #[test]
+    fn test_render() {
+        // A 4x4 screen with the top row, and the left column, lit
+        let mut buffer = vec![0; 16];
+        buffer[..4].fill(1);
+        for y in 0..4 {
+            buffer[y * 4] = 1;
+        }
+        let (cells, columns) = render(Glyphs::HalfBlock, &buffer, 4, 4);
+        assert_eq!(columns, 4);
+        assert_eq!(cells.iter().collect::<String>(), "█▀▀▀█   ");
+
+        let (cells, columns) = render(Glyphs::Braille, &buffer, 4, 4);
+        assert_eq!(columns, 2);
+        assert_eq!(cells, ['⡏', '⠉']);
+    }
+
+    #[test]
+    fn test_redraw() {
+        let previous: Vec<char> = "  ab".chars().collect();
+        let cells: Vec<char> = "xyaz".chars().collect();
+        // Two columns, so y and a are at the end of their rows
+        assert_eq!(redraw(&previous, &cells, 2), "\x1b[1;1Hxy\x1b[2;2Hz");
+        assert_eq!(redraw(&cells, &cells, 2), "");
+    }
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(
+            parse_input(b"aW\x1b[A\x1bOP\x1b[1;2Q\x1b[13~\x7f"),
+            [
+                Input::Key(TtyKey::Char(b'a')),
+                Input::Key(TtyKey::Char(b'w')),
+                Input::Key(TtyKey::Up),
+                Input::Function(1, false),
+                Input::Function(2, true),
+                Input::Function(3, false),
+                Input::Backspace,
+            ]
+        );
+        assert_eq!(parse_input(b"\x1b"), [Input::Quit]);
+        assert_eq!(parse_input(b"\x03"), [Input::Quit]);
+    }
+
+    #[test]
+    fn test_host_keys() {
+        for (name, _) in LAYOUTS {
+            host_keys(&Keymap::layout(name).unwrap()).unwrap();
+        }
+        let keys = host_keys(&Keymap::default()).unwrap();
+        assert_eq!(keys[0x1], [TtyKey::Char(b'1')]);
+        assert_eq!(keys[0x5], [TtyKey::Char(b'w')]);
+
+        let mut keymap = Keymap::default();
+        keymap.keys[5] = vec!["Up".to_string(), "LeftCtrl".to_string()];
+        assert_eq!(
+            host_keys(&keymap),
+            Err("Key 'LeftCtrl' for keypad key 5 can't be read in a terminal".to_string())
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::display::Display;
+use crate::frontend::{Frontend, Hotkey};
+use crate::keymap::Keymap;
+use crate::screen::PALETTE;
+
+const SCALE: usize = 10;
+
+// Host key names as used in keymap files
+const KEY_NAMES: [(&str, Key); 77] = [
+    ("Key0", Key::Key0),
+    ("Key1", Key::Key1),
+    ("Key2", Key::Key2),
+    ("Key3", Key::Key3),
+    ("Key4", Key::Key4),
+    ("Key5", Key::Key5),
+    ("Key6", Key::Key6),
+    ("Key7", Key::Key7),
+    ("Key8", Key::Key8),
+    ("Key9", Key::Key9),
+    ("A", Key::A),
+    ("B", Key::B),
+    ("C", Key::C),
+    ("D", Key::D),
+    ("E", Key::E),
+    ("F", Key::F),
+    ("G", Key::G),
+    ("H", Key::H),
+    ("I", Key::I),
+    ("J", Key::J),
+    ("K", Key::K),
+    ("L", Key::L),
+    ("M", Key::M),
+    ("N", Key::N),
+    ("O", Key::O),
+    ("P", Key::P),
+    ("Q", Key::Q),
+    ("R", Key::R),
+    ("S", Key::S),
+    ("T", Key::T),
+    ("U", Key::U),
+    ("V", Key::V),
+    ("W", Key::W),
+    ("X", Key::X),
+    ("Y", Key::Y),
+    ("Z", Key::Z),
+    ("Down", Key::Down),
+    ("Left", Key::Left),
+    ("Right", Key::Right),
+    ("Up", Key::Up),
+    ("Apostrophe", Key::Apostrophe),
+    ("Backquote", Key::Backquote),
+    ("Backslash", Key::Backslash),
+    ("Comma", Key::Comma),
+    ("LeftBracket", Key::LeftBracket),
+    ("Period", Key::Period),
+    ("RightBracket", Key::RightBracket),
+    ("Semicolon", Key::Semicolon),
+    ("Slash", Key::Slash),
+    ("Space", Key::Space),
+    ("Enter", Key::Enter),
+    ("Home", Key::Home),
+    ("End", Key::End),
+    ("PageUp", Key::PageUp),
+    ("PageDown", Key::PageDown),
+    ("Insert", Key::Insert),
+    ("Delete", Key::Delete),
+    ("LeftCtrl", Key::LeftCtrl),
+    ("RightCtrl", Key::RightCtrl),
+    ("LeftAlt", Key::LeftAlt),
+    ("RightAlt", Key::RightAlt),
+    ("NumPad0", Key::NumPad0),
+    ("NumPad1", Key::NumPad1),
+    ("NumPad2", Key::NumPad2),
+    ("NumPad3", Key::NumPad3),
+    ("NumPad4", Key::NumPad4),
+    ("NumPad5", Key::NumPad5),
+    ("NumPad6", Key::NumPad6),
+    ("NumPad7", Key::NumPad7),
+    ("NumPad8", Key::NumPad8),
+    ("NumPad9", Key::NumPad9),
+    ("NumPadDot", Key::NumPadDot),
+    ("NumPadSlash", Key::NumPadSlash),
+    ("NumPadAsterisk", Key::NumPadAsterisk),
+    ("NumPadMinus", Key::NumPadMinus),
+    ("NumPadPlus", Key::NumPadPlus),
+    ("NumPadEnter", Key::NumPadEnter),
+];
+
+// The minifb keys for each keypad key, failing on names it doesn't know.
+// The hotkeys (F1-F4, Backspace, Tab, Shift, Minus and Equal) aren't in the
+// table so a keymap can't take them over
+pub fn host_keys(keymap: &Keymap) -> Result<[Vec<Key>; 16], String> {
+    let mut keys: [Vec<Key>; 16] = Default::default();
+    for (keypad_key, names) in keymap.keys.iter().enumerate() {
+        for name in names {
+            let key = KEY_NAMES
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .ok_or(format!(
+                    "Unknown key '{}' for keypad key {:X}",
+                    name, keypad_key
+                ))?;
+            keys[keypad_key].push(key.1);
+        }
+    }
+    Ok(keys)
+}
+
+// F1-F4 load a save state slot, Shift+F1-F4 save to it
+const SLOT_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+
+// Held to step back through recent frames
+const REWIND_KEY: Key = Key::Backspace;
+
+// - and = change the speed, Tab turns turbo mode on and off
+const SPEED_KEYS: [(Key, Hotkey); 3] = [
+    (Key::Minus, Hotkey::Slower),
+    (Key::Equal, Hotkey::Faster),
+    (Key::Tab, Hotkey::Turbo),
+];
+
+// minifb window that also reports emulator hotkeys
+pub struct WindowDisplay {
+    window: Window,
+    buffer: Vec<u32>,
+    keys: [Vec<Key>; 16],
+    palette: [u32; 4],
+    hotkey: Option<Hotkey>,
+}
+
+impl WindowDisplay {
+    pub fn new(title: &str, keys: [Vec<Key>; 16]) -> Result<WindowDisplay, minifb::Error> {
+        let window = Window::new(
+            title,
+            64 * SCALE,
+            32 * SCALE,
+            WindowOptions {
+                resize: false,
+                scale: minifb::Scale::X1,
+                ..WindowOptions::default()
+            },
+        )?;
+        Ok(WindowDisplay {
+            window,
+            buffer: Vec::new(),
+            keys,
+            palette: PALETTE,
+            hotkey: None,
+        })
+    }
+
+    // Colors for pixel values 0-3, as 0xRRGGBB
+    pub fn set_palette(&mut self, palette: [u32; 4]) {
+        self.palette = palette;
+    }
+
+    fn poll_hotkeys(&mut self) {
+        let shift =
+            self.window.is_key_down(Key::LeftShift) || self.window.is_key_down(Key::RightShift);
+        for (slot, &key) in SLOT_KEYS.iter().enumerate() {
+            if self.window.is_key_pressed(key, KeyRepeat::No) {
+                self.hotkey = Some(if shift {
+                    Hotkey::SaveState(slot + 1)
+                } else {
+                    Hotkey::LoadState(slot + 1)
+                });
+            }
+        }
+        for &(key, hotkey) in SPEED_KEYS.iter() {
+            if self.window.is_key_pressed(key, KeyRepeat::No) {
+                self.hotkey = Some(hotkey);
+            }
+        }
+    }
+}
+
+impl Frontend for WindowDisplay {
+    fn hotkey(&self) -> Option<Hotkey> {
+        self.hotkey
+    }
+
+    fn take_hotkey(&mut self) -> Option<Hotkey> {
+        self.hotkey.take()
+    }
+
+    fn is_rewinding(&self) -> bool {
+        self.window.is_key_down(REWIND_KEY)
+    }
+}
+
+impl Display for WindowDisplay {
+    fn update(&mut self, buffer: &[u8], width: usize, height: usize) {
+        self.buffer.clear();
+        self.buffer
+            .extend(buffer.iter().map(|&color| self.palette[color as usize & 3]));
+        if let Err(e) = self.window.update_with_buffer(&self.buffer, width, height) {
+            eprintln!("Failed to update window: {}", e);
+        }
+        // Key presses are only seen after the window has been updated
+        self.poll_hotkeys();
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    fn is_key_down(&self, key: usize) -> bool {
+        self.keys
+            .get(key)
+            .is_some_and(|keys| keys.iter().any(|&key| self.window.is_key_down(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::LAYOUTS;
+
+    #[test]
+    fn test_host_keys() {
+        for (name, _) in LAYOUTS {
+            let keys = host_keys(&Keymap::layout(name).unwrap()).unwrap();
+            assert!(keys.iter().all(|keys| keys.len() == 1), "{}", name);
+        }
+        let keys = host_keys(&Keymap::default()).unwrap();
+        assert_eq!(keys[0xC], [Key::Key4]);
+
+        let mut keymap = Keymap::default();
+        keymap.keys[5] = vec!["up".to_string(), "Tab".to_string()];
+        assert_eq!(
+            host_keys(&keymap),
+            Err("Unknown key 'Tab' for keypad key 5".to_string())
+        );
+        keymap.keys[5] = vec!["Minus".to_string()];
+        assert!(host_keys(&keymap).is_err());
+    }
+}
@@ -0,0 +1,270 @@
+// Runs test ROMs headlessly under each quirks preset and compares the final
+// screen with the golden ASCII dumps in tests/golden, named
+// <rom>.<preset>.txt. Set UPDATE_GOLDEN=1 to write the dumps instead of
+// checking them, then review the diff before committing.
+//
+// The ROMs written for this harness are Octo sources in tests/roms. The
+// ROMs of Timendus' chip8-test-suite aren't redistributed here; drop its
+// .ch8 files from bin/ into tests/roms and generate their golden dumps to
+// have them checked as well. They're skipped when missing, unless
+// CHIP8_TEST_SUITE is set.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const PRESETS: [&str; 5] = ["vip", "chip48", "schip", "xochip", "modern"];
+
+struct Case {
+    rom: &'static str,
+    frames: u32,
+    // Key presses as frame:key[:frames]
+    presses: &'static [&'static str],
+    // Whether a missing ROM is skipped rather than a failure
+    optional: bool,
+    // The runs to check, or an empty slice for one under every preset
+    runs: &'static [Run],
+}
+
+// A run under `preset` with `select` written to 0x1FF, which the suite's
+// later ROMs read to skip their menus. Its golden dump is <rom>.<name>.txt
+struct Run {
+    name: &'static str,
+    preset: &'static str,
+    select: u8,
+}
+
+const CASES: [Case; 11] = [
+    Case {
+        rom: "logo.8o",
+        frames: 60,
+        presses: &[],
+        optional: false,
+        runs: &[],
+    },
+    Case {
+        rom: "opcodes.8o",
+        frames: 60,
+        presses: &[],
+        optional: false,
+        runs: &[],
+    },
+    Case {
+        rom: "flags.8o",
+        frames: 60,
+        presses: &[],
+        optional: false,
+        runs: &[],
+    },
+    Case {
+        rom: "quirks.8o",
+        frames: 60,
+        presses: &[],
+        optional: false,
+        runs: &[],
+    },
+    Case {
+        rom: "keypad.8o",
+        frames: 120,
+        presses: &["10:1", "20:a:30", "55:2", "70:f", "80:0:1", "90:5"],
+        optional: false,
+        runs: &[],
+    },
+    Case {
+        rom: "1-chip8-logo.ch8",
+        frames: 60,
+        presses: &[],
+        optional: true,
+        runs: &[],
+    },
+    Case {
+        rom: "2-ibm-logo.ch8",
+        frames: 60,
+        presses: &[],
+        optional: true,
+        runs: &[],
+    },
+    Case {
+        rom: "3-corax+.ch8",
+        frames: 120,
+        presses: &[],
+        optional: true,
+        runs: &[],
+    },
+    Case {
+        rom: "4-flags.ch8",
+        frames: 120,
+        presses: &[],
+        optional: true,
+        runs: &[],
+    },
+    // Each platform the quirks ROM knows, under the matching preset
+    Case {
+        rom: "5-quirks.ch8",
+        frames: 600,
+        presses: &[],
+        optional: true,
+        runs: &[
+            Run {
+                name: "vip",
+                preset: "vip",
+                select: 1,
+            },
+            Run {
+                name: "schip",
+                preset: "schip",
+                select: 2,
+            },
+            Run {
+                name: "xochip",
+                preset: "xochip",
+                select: 3,
+            },
+        ],
+    },
+    // Each of the keypad ROM's tests, with keys pressed and released
+    Case {
+        rom: "6-keypad.ch8",
+        frames: 120,
+        presses: &["30:5", "40:a:20", "90:5"],
+        optional: true,
+        runs: &[
+            Run {
+                name: "ex9e",
+                preset: "modern",
+                select: 1,
+            },
+            Run {
+                name: "exa1",
+                preset: "modern",
+                select: 2,
+            },
+            Run {
+                name: "fx0a",
+                preset: "vip",
+                select: 3,
+            },
+        ],
+    },
+];
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn run_headless(rom: &Path, preset: &str, select: Option<u8>, case: &Case) -> String {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_chip8_emu"));
+    command
+        .arg("headless")
+        .args(["--quirks", preset])
+        .args(["--frames", &case.frames.to_string()]);
+    for press in case.presses {
+        command.args(["--press", press]);
+    }
+    if let Some(select) = select {
+        command.args(["--poke", &format!("1FF:{:02X}", select)]);
+    }
+    let output = command.arg(rom).output().expect("failed to run emulator");
+    assert!(
+        output.status.success(),
+        "{} ({}) failed: {}",
+        case.rom,
+        preset,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Expected and actual screens side by side, with the differing pixels
+// marked in a third column and the rows that differ flagged with '>'
+fn visual_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let width = expected.iter().chain(&actual).map(|l| l.len()).max();
+    let width = width.unwrap_or(0);
+    let mut out = format!(
+        "  {:width$}  {:width$}  differences\n",
+        "expected", "actual"
+    );
+    for row in 0..expected.len().max(actual.len()) {
+        let e = expected.get(row).copied().unwrap_or("");
+        let a = actual.get(row).copied().unwrap_or("");
+        let marks: String = (0..width)
+            .map(|x| match (e.as_bytes().get(x), a.as_bytes().get(x)) {
+                (Some(p), Some(q)) if p == q => '.',
+                _ => 'X',
+            })
+            .collect();
+        let flag = if e == a { ' ' } else { '>' };
+        out.push_str(&format!("{} {:width$}  {:width$}  {}\n", flag, e, a, marks));
+    }
+    out
+}
+
+#[test]
+fn test_conformance() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let suite_required = env::var_os("CHIP8_TEST_SUITE").is_some();
+    let mut failures = Vec::new();
+    for case in &CASES {
+        let rom = tests_dir().join("roms").join(case.rom);
+        if !rom.exists() && case.optional && !suite_required {
+            eprintln!("skipping {}, not in tests/roms", case.rom);
+            continue;
+        }
+        if !rom.exists() {
+            failures.push(format!("{} is missing from tests/roms", case.rom));
+            continue;
+        }
+        let stem = rom.file_stem().unwrap().to_string_lossy().into_owned();
+        let runs: Vec<(&str, &str, Option<u8>)> = if case.runs.is_empty() {
+            PRESETS
+                .iter()
+                .map(|&preset| (preset, preset, None))
+                .collect()
+        } else {
+            case.runs
+                .iter()
+                .map(|run| (run.name, run.preset, Some(run.select)))
+                .collect()
+        };
+        for (name, preset, select) in runs {
+            let actual = run_headless(&rom, preset, select, case);
+            let golden = tests_dir()
+                .join("golden")
+                .join(format!("{}.{}.txt", stem, name));
+            if update {
+                fs::create_dir_all(golden.parent().unwrap()).unwrap();
+                fs::write(&golden, &actual).unwrap();
+                continue;
+            }
+            match fs::read_to_string(&golden) {
+                Ok(expected) if expected == actual => {}
+                Ok(expected) => failures.push(format!(
+                    "{} ({}) differs from {}:\n{}",
+                    case.rom,
+                    preset,
+                    golden.display(),
+                    visual_diff(&expected, &actual)
+                )),
+                Err(e) => failures.push(format!(
+                    "{} ({}): {}: {}, run with UPDATE_GOLDEN=1 to create it",
+                    case.rom,
+                    preset,
+                    golden.display(),
+                    e
+                )),
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_visual_diff() {
+    let diff = visual_diff("#.\n..\n", "#.\n.#\n");
+    assert_eq!(
+        diff,
+        "  expected  actual  differences\n  #.  #.  ..\n> ..  .#  .X\n"
+    );
+}